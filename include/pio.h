@@ -0,0 +1,58 @@
+/*
+ * SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ *
+ * C interface of pio built with `--features capi`.
+ */
+
+#ifndef PIO_H
+#define PIO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PIO_FORMAT_JPEG 0
+#define PIO_FORMAT_PNG 1
+#define PIO_FORMAT_WEBP 2
+
+typedef struct {
+    /* Output format, one of PIO_FORMAT_* */
+    int format;
+    /* Target quality, converted to DSSIM with the calibrated table of the format */
+    uint8_t quality;
+    /* Target DSSIM of the output compared to the input overriding `quality` if positive */
+    double target;
+    uint8_t min_quality;
+    uint8_t max_quality;
+} PioOptions;
+
+typedef struct {
+    /* Compressed image on success, NULL otherwise */
+    uint8_t *data;
+    size_t len;
+    /* Quality of the lossy compressor, -1 if it wasn't used */
+    int quality;
+    double dssim;
+    /* Error message on failure, NULL otherwise */
+    char *error;
+} PioOutput;
+
+/*
+ * Optimize JPEG, PNG or WebP image of `len` bytes at `input`. Returns 0 on success and -1 on
+ * failure, in which case `output->error` has the reason. `output` must be released with
+ * `pio_free` in both cases. NULL `input` or `options` is a failure, but `output` must not be NULL.
+ */
+int pio_optimize(const uint8_t *input, size_t len, const PioOptions *options, PioOutput *output);
+
+/* Release memory of the output of `pio_optimize`. */
+void pio_free(PioOutput *output);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
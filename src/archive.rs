@@ -0,0 +1,154 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::io::{Cursor, Read, Write};
+
+/// Supported archive format.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Kind {
+    Zip,
+    Tar,
+}
+
+impl Kind {
+    pub fn from_magic(buffer: &[u8]) -> Option<Self> {
+        match buffer {
+            [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Some(Self::Zip),
+            _ if buffer.get(257..262) == Some(b"ustar") => Some(Self::Tar),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrite archive with contents of files replaced by `replace`, which gets the path and contents
+/// of each file and returns new contents or `None` to keep the file as is. Entries are written in
+/// the same order with the same metadata.
+pub fn rewrite(
+    buffer: &[u8],
+    replace: impl FnMut(&str, &[u8]) -> Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    match Kind::from_magic(buffer) {
+        Some(Kind::Zip) => rewrite_zip(buffer, replace),
+        Some(Kind::Tar) => rewrite_tar(buffer, replace),
+        None => Err("unknown archive format, expected zip or tar".to_string()),
+    }
+}
+
+/// Count files for which `filter` returns true given the path and contents of each file, for
+/// example to report progress of `rewrite`.
+pub fn count_files(
+    buffer: &[u8],
+    mut filter: impl FnMut(&str, &[u8]) -> bool,
+) -> Result<usize, String> {
+    let mut count = 0;
+    match Kind::from_magic(buffer) {
+        Some(Kind::Zip) => {
+            let failed =
+                |err: zip::result::ZipError| format!("failed to read zip archive: {}", err);
+            let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).map_err(failed)?;
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i).map_err(failed)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)
+                    .map_err(|err| format!("failed to read zip archive: {}", err))?;
+                if filter(file.name(), &data) {
+                    count += 1;
+                }
+            }
+        }
+        Some(Kind::Tar) => {
+            let failed = |err: std::io::Error| format!("failed to read tar archive: {}", err);
+            let mut archive = tar::Archive::new(buffer);
+            for entry in archive.entries().map_err(failed)? {
+                let mut entry = entry.map_err(failed)?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = entry.path().map_err(failed)?.into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).map_err(failed)?;
+                if filter(&path.to_string_lossy(), &data) {
+                    count += 1;
+                }
+            }
+        }
+        None => return Err("unknown archive format, expected zip or tar".to_string()),
+    }
+    Ok(count)
+}
+
+fn rewrite_zip(
+    buffer: &[u8],
+    mut replace: impl FnMut(&str, &[u8]) -> Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let failed = |err: zip::result::ZipError| format!("failed to rewrite zip archive: {}", err);
+    let io_failed = |err: std::io::Error| format!("failed to rewrite zip archive: {}", err);
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).map_err(failed)?;
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    writer.set_comment(String::from_utf8_lossy(archive.comment()));
+
+    for i in 0..archive.len() {
+        let replaced = {
+            let mut file = archive.by_index(i).map_err(failed)?;
+            if file.is_dir() {
+                None
+            } else {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).map_err(io_failed)?;
+                replace(file.name(), &data).map(|data| {
+                    let mut options = zip::write::FileOptions::default()
+                        .compression_method(file.compression())
+                        .last_modified_time(file.last_modified());
+                    if let Some(mode) = file.unix_mode() {
+                        options = options.unix_permissions(mode);
+                    }
+                    (file.name().to_string(), options, data)
+                })
+            }
+        };
+        match replaced {
+            Some((name, options, data)) => {
+                writer.start_file(name, options).map_err(failed)?;
+                writer.write_all(&data).map_err(io_failed)?;
+            }
+            // Copy without recompressing, which keeps the entry exactly as it was.
+            None => writer
+                .raw_copy_file(archive.by_index_raw(i).map_err(failed)?)
+                .map_err(failed)?,
+        }
+    }
+
+    Ok(writer.finish().map_err(failed)?.into_inner())
+}
+
+fn rewrite_tar(
+    buffer: &[u8],
+    mut replace: impl FnMut(&str, &[u8]) -> Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let failed = |err: std::io::Error| format!("failed to rewrite tar archive: {}", err);
+    let mut archive = tar::Archive::new(buffer);
+    let mut builder = tar::Builder::new(Vec::new());
+
+    for entry in archive.entries().map_err(failed)? {
+        let mut entry = entry.map_err(failed)?;
+        let mut header = entry.header().clone();
+        let path = entry.path().map_err(failed)?.into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(failed)?;
+        if header.entry_type().is_file() {
+            if let Some(replaced) = replace(&path.to_string_lossy(), &data) {
+                data = replaced;
+            }
+        }
+        // Path is set again so that long paths are written with extension headers.
+        header.set_size(data.len() as u64);
+        builder
+            .append_data(&mut header, &path, data.as_slice())
+            .map_err(failed)?;
+    }
+
+    builder.into_inner().map_err(failed)
+}
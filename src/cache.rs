@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Directory of previous results keyed by hash of input data and options.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Key for input data compressed with options serialized to a string.
+    pub fn key(input: &[u8], options: &str) -> String {
+        let mut hasher = Sha256::new();
+        // Length prefix keeps boundary between input and options unambiguous.
+        hasher.update((input.len() as u64).to_le_bytes());
+        hasher.update(input);
+        hasher.update(options.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.dir.join(key)).ok()
+    }
+
+    // Entry is written to a temporary file first, so that concurrent runs never read partial data.
+    pub fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp_path = self
+            .dir
+            .join(format!(".{}-{}.tmp", key, std::process::id()));
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, self.dir.join(key))
+    }
+}
@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Token for stopping a long-running optimization, for example from another thread. Clones share
+/// the same state. The quality search checks the token between iterations and returns the best
+/// result found so far.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token that is cancelled automatically after the timeout.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
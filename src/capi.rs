@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! C interface for optimizing an image in memory, declared in `include/pio.h`.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+
+use crate::cancel::CancellationToken;
+use crate::common::Format;
+use crate::search::{self, OptimizeOptions, OptimizeResult};
+
+pub const PIO_FORMAT_JPEG: c_int = 0;
+pub const PIO_FORMAT_PNG: c_int = 1;
+pub const PIO_FORMAT_WEBP: c_int = 2;
+
+/// Options of `pio_optimize`.
+#[repr(C)]
+pub struct PioOptions {
+    /// Output format, one of `PIO_FORMAT_*`
+    pub format: c_int,
+    /// Target quality, converted to DSSIM with the calibrated table of the format
+    pub quality: u8,
+    /// Target DSSIM of the output compared to the input overriding `quality` if positive
+    pub target: f64,
+    pub min_quality: u8,
+    pub max_quality: u8,
+}
+
+/// Result of `pio_optimize`, released with `pio_free`.
+#[repr(C)]
+pub struct PioOutput {
+    /// Compressed image on success, null otherwise
+    pub data: *mut u8,
+    pub len: usize,
+    /// Quality of the lossy compressor, -1 if it wasn't used
+    pub quality: c_int,
+    pub dssim: f64,
+    /// Error message on failure, null otherwise
+    pub error: *mut c_char,
+}
+
+fn optimize(input: &[u8], options: &PioOptions) -> Result<OptimizeResult, String> {
+    let format = match options.format {
+        PIO_FORMAT_JPEG => Format::JPEG,
+        PIO_FORMAT_PNG => Format::PNG,
+        PIO_FORMAT_WEBP => Format::WEBP,
+        _ => return Err("unknown output format".to_string()),
+    };
+    let options = OptimizeOptions {
+        format,
+        quality: options.quality,
+        target: Some(options.target).filter(|target| *target > 0.0),
+        min_quality: options.min_quality,
+        max_quality: options.max_quality,
+    };
+    search::optimize_bytes(input, &options, &CancellationToken::new(), &mut |_| {})
+}
+
+/// Optimize image of `len` bytes at `input` and store the result in `output`. Returns 0 on success
+/// and -1 on failure, in which case `output.error` has the reason. `output` must be released with
+/// `pio_free` in both cases. Null `input` or `options` is a failure.
+///
+/// # Safety
+///
+/// Non-null `input` must point to `len` readable bytes, non-null `options` must be a valid pointer
+/// and `output` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn pio_optimize(
+    input: *const u8,
+    len: usize,
+    options: *const PioOptions,
+    output: *mut PioOutput,
+) -> c_int {
+    let result = if input.is_null() || options.is_null() {
+        Err("input and options must not be null".to_string())
+    } else {
+        let input = std::slice::from_raw_parts(input, len);
+        // Panics must not unwind into C.
+        std::panic::catch_unwind(|| optimize(input, &*options))
+            .unwrap_or_else(|_| Err("unexpected error".to_string()))
+    };
+    // Output may be uninitialized, so it's written without reading the old value.
+    match result {
+        Ok(result) => {
+            let data = Box::into_raw(result.bytes.into_boxed_slice());
+            output.write(PioOutput {
+                data: data as *mut u8,
+                len: data.len(),
+                quality: result.quality.map_or(-1, c_int::from),
+                dssim: result.dssim,
+                error: std::ptr::null_mut(),
+            });
+            0
+        }
+        Err(err) => {
+            output.write(PioOutput {
+                data: std::ptr::null_mut(),
+                len: 0,
+                quality: -1,
+                dssim: f64::NAN,
+                error: CString::new(err.replace('\0', ""))
+                    .unwrap_or_default()
+                    .into_raw(),
+            });
+            -1
+        }
+    }
+}
+
+/// Release memory of the output of `pio_optimize`.
+///
+/// # Safety
+///
+/// `output` must have been filled by `pio_optimize` and not released before.
+#[no_mangle]
+pub unsafe extern "C" fn pio_free(output: *mut PioOutput) {
+    let output = &mut *output;
+    if !output.data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            output.data,
+            output.len,
+        )));
+        output.data = std::ptr::null_mut();
+    }
+    if !output.error.is_null() {
+        drop(CString::from_raw(output.error));
+        output.error = std::ptr::null_mut();
+    }
+}
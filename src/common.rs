@@ -7,9 +7,43 @@ use std::path::Path;
 use clap::ArgEnum;
 use dssim_core::{ToRGBAPLU, RGBAPLU};
 use imgref::{Img, ImgVec};
-use rgb::{alt::GRAY8, ComponentBytes, RGB8, RGBA8};
+use rgb::{alt::GRAY8, ComponentBytes, RGB8, RGBA16, RGBA8};
 
-#[derive(PartialEq)]
+use crate::hdr::ToneMapping;
+use crate::metadata::Metadata;
+use crate::profile::{RenderingIntent, TargetColorSpace};
+
+/// Rectangular area of an image.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// Part of the rectangle inside an image of the size, `None` if there is nothing left.
+    pub fn clip(&self, width: usize, height: usize) -> Option<Rect> {
+        let rect = Rect {
+            x: self.x,
+            y: self.y,
+            width: self.width.min(width.saturating_sub(self.x)),
+            height: self.height.min(height.saturating_sub(self.y)),
+        };
+        if rect.width > 0 && rect.height > 0 {
+            Some(rect)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
 pub enum ColorSpace {
     Gray,
     GrayAlpha,
@@ -17,23 +51,61 @@ pub enum ColorSpace {
     RGBA,
 }
 
+impl std::fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Gray => write!(f, "grayscale"),
+            Self::GrayAlpha => write!(f, "grayscale with alpha"),
+            Self::RGB => write!(f, "RGB"),
+            Self::RGBA => write!(f, "RGB with alpha"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Image {
     pub width: usize,
     pub height: usize,
     pub data: Vec<RGBA8>,
+    /// Pixels of 16-bit input before reducing them to `data`, so that lossless output can keep
+    /// them. Cleared when `data` is changed.
+    pub data16: Option<Vec<RGBA16>>,
     pub color_space: ColorSpace,
+    pub metadata: Metadata,
 }
 
-fn distance(a: u8, b: u8) -> u8 {
-    if a < b {
-        b - a
-    } else {
-        a - b
-    }
+fn is_gray(color: RGB8) -> bool {
+    color.r.abs_diff(color.g) <= 1 && color.g.abs_diff(color.b) <= 1
 }
 
-fn is_gray(color: RGB8) -> bool {
-    distance(color.r, color.g) <= 1 && distance(color.g, color.b) <= 1
+// Rows of pixel data in a raw buffer, checking that the buffer is large enough.
+fn raw_rows(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    channels: usize,
+) -> Result<impl Iterator<Item = &[u8]>, String> {
+    let row = width * channels;
+    if stride < row {
+        return Err(format!(
+            "stride {} is smaller than row of {} bytes",
+            stride, row
+        ));
+    }
+    let required = if height == 0 {
+        0
+    } else {
+        (height - 1) * stride + row
+    };
+    if data.len() < required {
+        return Err(format!(
+            "expected at least {} bytes of pixel data, got {}",
+            required,
+            data.len()
+        ));
+    }
+    Ok((0..height).map(move |y| &data[y * stride..y * stride + row]))
 }
 
 fn srgb_to_linear(u: u8) -> f32 {
@@ -67,6 +139,8 @@ impl Image {
                 (true, false) => ColorSpace::RGB,
                 (true, true) => ColorSpace::RGBA,
             },
+            data16: None,
+            metadata: Metadata::default(),
         }
     }
 
@@ -80,6 +154,66 @@ impl Image {
             height,
             data: data.iter().map(|c| RGB8::from(*c).alpha(255)).collect(),
             color_space: ColorSpace::Gray,
+            data16: None,
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Image from 8-bit RGBA pixels where each row starts `stride` bytes after the previous one.
+    pub fn from_raw_rgba(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Self, String> {
+        let pixels = raw_rows(data, width, height, stride, 4)?
+            .flat_map(|row| row.chunks_exact(4))
+            .map(|c| RGBA8::new(c[0], c[1], c[2], c[3]))
+            .collect();
+        Ok(Self::from_rgba(pixels, width, height))
+    }
+
+    /// Image from 8-bit RGB pixels where each row starts `stride` bytes after the previous one.
+    pub fn from_raw_rgb(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Self, String> {
+        let pixels = raw_rows(data, width, height, stride, 3)?
+            .flat_map(|row| row.chunks_exact(3))
+            .map(|c| RGBA8::new(c[0], c[1], c[2], 255))
+            .collect();
+        Ok(Self::from_rgba(pixels, width, height))
+    }
+
+    // Downscale image by an integer factor averaging each block of pixels. Partial blocks at the
+    // right and bottom edges are dropped.
+    pub fn downscale(&self, factor: usize) -> Image {
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u32; 4];
+                for yy in y * factor..(y + 1) * factor {
+                    for c in &self.data[yy * self.width + x * factor..][..factor] {
+                        for (s, v) in sum.iter_mut().zip([c.r, c.g, c.b, c.a]) {
+                            *s += v as u32;
+                        }
+                    }
+                }
+                let n = (factor * factor) as u32;
+                let [r, g, b, a] = sum.map(|s| ((s + n / 2) / n) as u8);
+                data.push(RGBA8::new(r, g, b, a));
+            }
+        }
+        Image {
+            data,
+            width,
+            height,
+            color_space: self.color_space,
+            data16: None,
+            metadata: Metadata::default(),
         }
     }
 
@@ -87,6 +221,15 @@ impl Image {
         Img::new(self.data.to_rgbaplu(), self.width, self.height)
     }
 
+    // Alpha channel scaled to range 0-1.
+    pub fn to_alpha(&self) -> ImgVec<f32> {
+        Img::new(
+            self.data.iter().map(|c| c.a as f32 / 255.0).collect(),
+            self.width,
+            self.height,
+        )
+    }
+
     pub fn to_gray(&self) -> ImgVec<GRAY8> {
         Img::new(
             self.data.iter().map(|c| c.g.into()).collect(),
@@ -95,10 +238,38 @@ impl Image {
         )
     }
 
+    // Average color of the pixels on the image border weighted by alpha, or `None` if the border
+    // is fully transparent or the image is empty.
+    pub fn edge_color(&self) -> Option<RGB8> {
+        let (width, height) = (self.width, self.height);
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let edge = (0..width)
+            .flat_map(|x| [(x, 0), (x, height - 1)])
+            .chain((1..height.saturating_sub(1)).flat_map(|y| [(0, y), (width - 1, y)]));
+        let mut sum = [0.0; 3];
+        let mut total = 0.0;
+        for (x, y) in edge {
+            let pixel = self.data[y * width + x];
+            let a = pixel.a as f32 / 255.0;
+            for (s, c) in sum.iter_mut().zip(pixel.rgb().iter()) {
+                *s += srgb_to_linear(c) * a;
+            }
+            total += a;
+        }
+        if total == 0.0 {
+            return None;
+        }
+        let [r, g, b] = sum.map(|s| linear_to_srgb(s / total));
+        Some(RGB8::new(r, g, b))
+    }
+
     pub fn alpha_blend(&mut self, bg: RGB8) {
         use rayon::prelude::*;
         use rgb::ComponentMap;
 
+        let bg_srgb = bg;
         let bg = bg.map(srgb_to_linear);
         self.data.par_iter_mut().for_each(|pixel| {
             let a = pixel.a as f32 / 255.0;
@@ -112,6 +283,32 @@ impl Image {
                 .collect::<RGB8>()
                 .alpha(255);
         });
+        // Blending gray image with colored background can introduce color.
+        self.data16 = None;
+        self.color_space = match self.color_space {
+            ColorSpace::Gray | ColorSpace::GrayAlpha if is_gray(bg_srgb) => ColorSpace::Gray,
+            _ => ColorSpace::RGB,
+        };
+    }
+
+    // Convert image to grayscale using luminance calculated in linear light.
+    pub fn grayscale(&mut self) {
+        use rayon::prelude::*;
+
+        self.data.par_iter_mut().for_each(|pixel| {
+            let y = linear_to_srgb(
+                0.2126 * srgb_to_linear(pixel.r)
+                    + 0.7152 * srgb_to_linear(pixel.g)
+                    + 0.0722 * srgb_to_linear(pixel.b),
+            );
+            *pixel = RGB8::new(y, y, y).alpha(pixel.a);
+        });
+        self.data16 = None;
+        self.color_space = if self.data.iter().any(|c| c.a < 255) {
+            ColorSpace::GrayAlpha
+        } else {
+            ColorSpace::Gray
+        };
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -148,12 +345,31 @@ impl Image {
     }
 }
 
-// Rotate and flip image according to Exif orientation.
-pub fn orient_image(image: Image, orientation: u32) -> Image {
-    if orientation == 1 {
-        return image;
+impl From<image::DynamicImage> for Image {
+    fn from(image: image::DynamicImage) -> Self {
+        Self::from_image_rs(image.into_rgba8())
     }
-    let mut output = image.into_image_rs();
+}
+
+// Metadata is not carried over.
+impl From<Image> for image::DynamicImage {
+    fn from(image: Image) -> Self {
+        let color_space = image.color_space;
+        let image = image::DynamicImage::ImageRgba8(image.into_image_rs());
+        match color_space {
+            ColorSpace::Gray => image::DynamicImage::ImageLuma8(image.into_luma8()),
+            ColorSpace::GrayAlpha => image::DynamicImage::ImageLumaA8(image.into_luma_alpha8()),
+            ColorSpace::RGB => image::DynamicImage::ImageRgb8(image.into_rgb8()),
+            ColorSpace::RGBA => image,
+        }
+    }
+}
+
+// Rotate and flip image buffer according to Exif orientation.
+fn orient_buffer<P: image::Pixel + 'static>(
+    mut output: image::ImageBuffer<P, Vec<P::Subpixel>>,
+    orientation: u32,
+) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
     match orientation {
         2 => image::imageops::flip_horizontal_in_place(&mut output),
         3 => image::imageops::rotate180_in_place(&mut output),
@@ -170,10 +386,59 @@ pub fn orient_image(image: Image, orientation: u32) -> Image {
         8 => output = image::imageops::rotate270(&output),
         _ => unreachable!(),
     }
-    Image::from_image_rs(output)
+    output
+}
+
+// Rotate and flip image according to Exif orientation.
+pub fn orient_image(mut image: Image, orientation: u32) -> Image {
+    if orientation == 1 {
+        return image;
+    }
+    let metadata = std::mem::take(&mut image.metadata);
+    let data16 = image.data16.take().map(|data16| {
+        let buffer = image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(
+            image.width as u32,
+            image.height as u32,
+            data16.iter().flat_map(|c| [c.r, c.g, c.b, c.a]).collect(),
+        )
+        .unwrap();
+        orient_buffer(buffer, orientation)
+            .into_raw()
+            .chunks_exact(4)
+            .map(|c| RGBA16::new(c[0], c[1], c[2], c[3]))
+            .collect()
+    });
+    let mut image = Image::from_image_rs(orient_buffer(image.into_image_rs(), orientation));
+    image.metadata = metadata;
+    image.data16 = data16;
+    image
 }
 
-pub fn exif_orientation(exif: exif::Exif) -> Option<u32> {
+#[derive(PartialEq, Copy, Clone, Debug, Default, ArgEnum)]
+pub enum OrientationPolicy {
+    /// Rotate and flip image data according to Exif orientation
+    #[default]
+    Bake,
+    /// Keep image data as is and copy Exif orientation to output
+    Keep,
+    /// Keep image data as is and drop Exif orientation
+    Ignore,
+}
+
+impl OrientationPolicy {
+    pub fn apply(&self, mut image: Image, orientation: u32) -> Image {
+        match self {
+            Self::Bake => orient_image(image, orientation),
+            Self::Keep => {
+                image.metadata.orientation = Some(orientation);
+                image
+            }
+            Self::Ignore => image,
+        }
+    }
+}
+
+pub fn exif_orientation(exif: &exif::Exif) -> Option<u32> {
     exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
         .and_then(|field| field.value.get_uint(0))
         .filter(|x| *x >= 1 && *x <= 8)
@@ -181,11 +446,25 @@ pub fn exif_orientation(exif: exif::Exif) -> Option<u32> {
 
 #[derive(Copy, Clone, Debug)]
 pub enum ChromaSubsampling {
+    _411,
     _420,
     _422,
+    _440,
     _444,
 }
 
+impl std::fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::_411 => write!(f, "4:1:1"),
+            Self::_420 => write!(f, "4:2:0"),
+            Self::_422 => write!(f, "4:2:2"),
+            Self::_440 => write!(f, "4:4:0"),
+            Self::_444 => write!(f, "4:4:4"),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum ChromaSubsamplingOption {
     None,
@@ -193,13 +472,23 @@ pub enum ChromaSubsamplingOption {
     Manual(ChromaSubsampling),
 }
 
-#[derive(PartialEq, Copy, Clone, ArgEnum)]
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
 pub enum Format {
     JPEG,
     PNG,
     WEBP,
 }
 
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::JPEG => write!(f, "JPEG"),
+            Self::PNG => write!(f, "PNG"),
+            Self::WEBP => write!(f, "WebP"),
+        }
+    }
+}
+
 impl Format {
     pub fn from_ext(input: &str) -> Option<Self> {
         match input {
@@ -217,6 +506,22 @@ impl Format {
             .and_then(|ext| Self::from_ext(&ext.to_ascii_lowercase()))
     }
 
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::JPEG => "jpeg",
+            Self::PNG => "png",
+            Self::WEBP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::JPEG => "image/jpeg",
+            Self::PNG => "image/png",
+            Self::WEBP => "image/webp",
+        }
+    }
+
     pub fn from_magic(buffer: &[u8]) -> Option<Self> {
         match buffer {
             [0xff, 0xd8, 0xff, ..] => Some(Self::JPEG),
@@ -234,6 +539,14 @@ impl Format {
         }
     }
 
+    pub fn supports_hdr(&self) -> bool {
+        match self {
+            Self::JPEG => false,
+            Self::PNG => true,
+            Self::WEBP => false,
+        }
+    }
+
     pub fn supports_chroma_subsampling(&self) -> bool {
         match self {
             Self::JPEG => true,
@@ -243,5 +556,46 @@ impl Format {
     }
 }
 
+#[derive(Copy, Clone, Default)]
+pub struct ReadOptions {
+    /// Keep RGB color profile instead of converting image data to sRGB
+    pub keep_color_profile: bool,
+    /// Tone map HDR image data to SDR using the operator, HDR data is kept as is if not set
+    pub tone_mapping: Option<ToneMapping>,
+    /// Color space to transform image data to
+    pub color_space: TargetColorSpace,
+    /// Rendering intent to use when transforming image data
+    pub rendering_intent: RenderingIntent,
+    /// Use black point compensation when transforming image data
+    pub black_point_compensation: bool,
+    /// Maximum number of pixels, larger images are rejected before decoding image data
+    pub max_pixels: Option<u64>,
+    /// Whether to rotate image data according to Exif orientation
+    pub orientation: OrientationPolicy,
+}
+
+impl ReadOptions {
+    /// Flags for transforming image data from the input color profile.
+    #[cfg(feature = "codecs")]
+    pub fn transform_flags(&self) -> lcms2::Flags {
+        if self.black_point_compensation {
+            lcms2::Flags::BLACKPOINT_COMPENSATION
+        } else {
+            lcms2::Flags::default()
+        }
+    }
+
+    /// Check image dimensions read from file header against the limits.
+    pub fn check_dimensions(&self, width: usize, height: usize) -> Result<(), String> {
+        match self.max_pixels {
+            Some(max_pixels) if width as u64 * height as u64 > max_pixels => Err(format!(
+                "image is too large: {}x{} exceeds limit of {} pixels",
+                width, height, max_pixels
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 pub type ReadResult = Result<Image, String>;
 pub type CompressResult = Result<(Image, Vec<u8>), String>;
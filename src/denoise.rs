@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use clap::ArgEnum;
+use rayon::prelude::*;
+use rgb::RGBA8;
+
+use crate::common::Image;
+
+// Radius of the neighborhood of the bilateral filter in pixels and standard deviation of its
+// spatial weight.
+const RADIUS: isize = 2;
+const SPATIAL_SIGMA: f32 = 1.5;
+
+// Size of blocks in pixels for estimating noise.
+const BLOCK_SIZE: usize = 16;
+
+// Maximum ratio of noise after and before downscaling by 2 for noise to be considered grain. The
+// ratio is about 0.5 for grain and 1 or more for texture.
+const GRAIN_SCALE_RATIO: f64 = 0.75;
+
+/// Fixed strength of denoising.
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum Level {
+    /// Smooth slight noise, keeping fine detail
+    Light,
+    Medium,
+    /// Smooth heavy noise of high-ISO photos, also removing some fine detail
+    Strong,
+}
+
+impl Level {
+    /// Strength for `denoise`.
+    pub fn strength(&self) -> f32 {
+        match self {
+            Self::Light => 4.0,
+            Self::Medium => 8.0,
+            Self::Strong => 16.0,
+        }
+    }
+}
+
+/// Estimate standard deviation of noise in the image in 8-bit units.
+///
+/// Uses the method of Immerkær ("Fast Noise Variance Estimation", 1996), where the luma is
+/// filtered with a mask that mostly cancels out smooth gradients and edges, leaving noise. Texture
+/// is left too, so the estimate is taken from the flattest blocks of the image, because grain
+/// covers the whole image but texture usually doesn't.
+pub fn estimate_noise(image: &Image) -> f64 {
+    let (width, height) = (image.width, image.height);
+    if width < BLOCK_SIZE + 2 || height < BLOCK_SIZE + 2 {
+        return 0.0;
+    }
+    let luma: Vec<f64> = image
+        .data
+        .iter()
+        .map(|c| 0.299 * c.r as f64 + 0.587 * c.g as f64 + 0.114 * c.b as f64)
+        .collect();
+    #[rustfmt::skip]
+    const MASK: [[f64; 3]; 3] = [
+        [ 1.0, -2.0,  1.0],
+        [-2.0,  4.0, -2.0],
+        [ 1.0, -2.0,  1.0],
+    ];
+    let blocks_x = (width - 2) / BLOCK_SIZE;
+    let mut blocks: Vec<f64> = (0..(height - 2) / BLOCK_SIZE * blocks_x)
+        .into_par_iter()
+        .map(|block| {
+            let (block_x, block_y) = (block % blocks_x, block / blocks_x);
+            let mut sum = 0.0;
+            for y in 1 + block_y * BLOCK_SIZE..1 + (block_y + 1) * BLOCK_SIZE {
+                for x in 1 + block_x * BLOCK_SIZE..1 + (block_x + 1) * BLOCK_SIZE {
+                    let mut value = 0.0;
+                    for (dy, row) in MASK.iter().enumerate() {
+                        for (dx, weight) in row.iter().enumerate() {
+                            value += weight * luma[(y + dy - 1) * width + x + dx - 1];
+                        }
+                    }
+                    sum += value.abs();
+                }
+            }
+            (std::f64::consts::PI / 2.0).sqrt() * sum / (6.0 * (BLOCK_SIZE * BLOCK_SIZE) as f64)
+        })
+        .collect();
+    blocks.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    blocks[blocks.len() / 10]
+}
+
+/// Estimate standard deviation of grain in the image in 8-bit units, or 0 if the noise doesn't
+/// look like grain.
+///
+/// Grain is random for each pixel, so averaging pixels in 2x2 blocks halves it. Fine texture like
+/// foliage isn't reduced as much, because neighboring pixels are correlated.
+pub fn estimate_grain(image: &Image) -> f64 {
+    let noise = estimate_noise(image);
+    if estimate_noise(&image.downscale(2)) < GRAIN_SCALE_RATIO * noise {
+        noise
+    } else {
+        0.0
+    }
+}
+
+/// Smooth noise with a bilateral filter which averages each pixel with its neighbors of similar
+/// color, so that edges are kept. `strength` is the standard deviation of color difference in
+/// 8-bit units that is still averaged. Alpha channel is kept as is.
+pub fn denoise(image: &Image, strength: f32) -> Image {
+    if strength <= 0.0 {
+        return image.clone();
+    }
+    let (width, height) = (image.width as isize, image.height as isize);
+    let spatial: Vec<(isize, isize, f32)> = (-RADIUS..=RADIUS)
+        .flat_map(|dy| (-RADIUS..=RADIUS).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| {
+            let distance = (dx * dx + dy * dy) as f32;
+            (
+                dx,
+                dy,
+                (-distance / (2.0 * SPATIAL_SIGMA * SPATIAL_SIGMA)).exp(),
+            )
+        })
+        .collect();
+    let range = -1.0 / (2.0 * strength * strength);
+
+    let mut data = vec![RGBA8::default(); image.data.len()];
+    data.par_chunks_mut(image.width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let y = y as isize;
+            for (x, output) in row.iter_mut().enumerate() {
+                let x = x as isize;
+                let center = image.data[(y * width + x) as usize];
+                let mut sum = [0.0f32; 3];
+                let mut total = 0.0;
+                for (dx, dy, spatial_weight) in &spatial {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                        continue;
+                    }
+                    let pixel = image.data[(ny * width + nx) as usize];
+                    let difference = [
+                        pixel.r as f32 - center.r as f32,
+                        pixel.g as f32 - center.g as f32,
+                        pixel.b as f32 - center.b as f32,
+                    ];
+                    // Color difference is averaged over channels to keep the scale of 8-bit
+                    // values.
+                    let distance = difference.iter().map(|d| d * d).sum::<f32>() / 3.0;
+                    let weight = spatial_weight * (distance * range).exp();
+                    for (s, c) in sum.iter_mut().zip([pixel.r, pixel.g, pixel.b]) {
+                        *s += weight * c as f32;
+                    }
+                    total += weight;
+                }
+                let [r, g, b] = sum.map(|s| (s / total).round() as u8);
+                *output = RGBA8::new(r, g, b, center.a);
+            }
+        });
+
+    Image {
+        data,
+        width: image.width,
+        height: image.height,
+        color_space: image.color_space,
+        data16: None,
+        metadata: image.metadata.clone(),
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use clap::ArgEnum;
+use rgb::RGBA16;
+
+/// Coding-independent code points from ITU-T H.273
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Cicp {
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+}
+
+const PRIMARIES_BT709: u8 = 1;
+const PRIMARIES_BT2020: u8 = 9;
+const PRIMARIES_DISPLAY_P3: u8 = 12;
+
+const TRANSFER_PQ: u8 = 16;
+const TRANSFER_HLG: u8 = 18;
+
+impl Cicp {
+    /// Parse data of PNG `cICP` chunk.
+    pub fn from_png_chunk(data: &[u8]) -> Option<Self> {
+        match data {
+            [colour_primaries, transfer_characteristics, matrix_coefficients, full_range] => {
+                Some(Self {
+                    colour_primaries: *colour_primaries,
+                    transfer_characteristics: *transfer_characteristics,
+                    matrix_coefficients: *matrix_coefficients,
+                    full_range: *full_range != 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_png_chunk(&self) -> [u8; 4] {
+        [
+            self.colour_primaries,
+            self.transfer_characteristics,
+            self.matrix_coefficients,
+            self.full_range as u8,
+        ]
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.transfer_characteristics, TRANSFER_PQ | TRANSFER_HLG)
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum ToneMapping {
+    /// Clip highlights brighter than SDR white
+    Clip,
+    /// Extended Reinhard operator
+    Reinhard,
+    /// Filmic operator by John Hable
+    Hable,
+}
+
+// Reference white luminance for mapping HDR to SDR (ITU-R BT.2408).
+const SDR_WHITE_NITS: f32 = 203.0;
+
+// Nominal peak luminance of HLG display.
+const HLG_PEAK_NITS: f32 = 1000.0;
+
+// SMPTE ST 2084 electro-optical transfer function. Returns luminance in nits.
+fn pq_eotf(v: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let e = v.max(0.0).powf(1.0 / M2);
+    10000.0 * ((e - C1).max(0.0) / (C2 - C3 * e)).powf(1.0 / M1)
+}
+
+// ITU-R BT.2100 HLG inverse opto-electrical transfer function. Returns relative scene light.
+fn hlg_inverse_oetf(v: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 1.0 - 4.0 * A;
+    const C: f32 = 0.559_910_7;
+    if v <= 0.5 {
+        v * v / 3.0
+    } else {
+        (((v - C) / A).exp() + B) / 12.0
+    }
+}
+
+fn srgb_encode(u: f32) -> f32 {
+    if u <= 0.0031308 {
+        12.92 * u
+    } else {
+        1.055 * u.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply(matrix: &[[f32; 3]; 3], rgb: [f32; 3]) -> [f32; 3] {
+    let mut output = [0.0; 3];
+    for (output, row) in output.iter_mut().zip(matrix) {
+        *output = row.iter().zip(&rgb).map(|(a, b)| a * b).sum();
+    }
+    output
+}
+
+#[rustfmt::skip]
+const BT2020_TO_BT709: [[f32; 3]; 3] = [
+    [ 1.6605, -0.5876, -0.0728],
+    [-0.1246,  1.1329, -0.0083],
+    [-0.0182, -0.1006,  1.1187],
+];
+
+#[rustfmt::skip]
+const DISPLAY_P3_TO_BT709: [[f32; 3]; 3] = [
+    [ 1.2249, -0.2247,  0.0000],
+    [-0.0420,  1.0419,  0.0000],
+    [-0.0197, -0.0786,  1.0979],
+];
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+    0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+impl ToneMapping {
+    // Map luminance relative to SDR white into range 0-1. `peak` is the maximum luminance of the
+    // source relative to SDR white.
+    fn apply(&self, l: f32, peak: f32) -> f32 {
+        match self {
+            Self::Clip => l.min(1.0),
+            Self::Reinhard => l * (1.0 + l / (peak * peak)) / (1.0 + l),
+            Self::Hable => {
+                const EXPOSURE: f32 = 2.0;
+                hable(l * EXPOSURE) / hable(peak * EXPOSURE)
+            }
+        }
+    }
+}
+
+/// Tone map HDR image data in place to SDR sRGB.
+pub fn tone_map(data: &mut [RGBA16], cicp: &Cicp, operator: ToneMapping) {
+    use rayon::prelude::*;
+
+    let peak = match cicp.transfer_characteristics {
+        TRANSFER_PQ => 10000.0 / SDR_WHITE_NITS,
+        _ => HLG_PEAK_NITS / SDR_WHITE_NITS,
+    };
+    let primaries = match cicp.colour_primaries {
+        PRIMARIES_BT2020 => Some(&BT2020_TO_BT709),
+        PRIMARIES_DISPLAY_P3 => Some(&DISPLAY_P3_TO_BT709),
+        PRIMARIES_BT709 => None,
+        _ => {
+            eprintln!("Unknown colour primaries, assuming BT.709");
+            None
+        }
+    };
+
+    data.par_iter_mut().for_each(|pixel| {
+        let mut encoded = [pixel.r, pixel.g, pixel.b].map(|c| c as f32 / 65535.0);
+        if !cicp.full_range {
+            encoded = encoded.map(|c| (c - 16.0 / 255.0) * 255.0 / 219.0);
+        }
+        let nits = match cicp.transfer_characteristics {
+            TRANSFER_PQ => encoded.map(pq_eotf),
+            _ => {
+                // HLG reference OOTF with system gamma of 1.2 for 1000 nits display.
+                let scene = encoded.map(hlg_inverse_oetf);
+                let y = 0.2627 * scene[0] + 0.6780 * scene[1] + 0.0593 * scene[2];
+                scene.map(|e| HLG_PEAK_NITS * y.powf(0.2) * e)
+            }
+        };
+        let mut rgb = nits.map(|c| c / SDR_WHITE_NITS);
+        if let Some(matrix) = primaries {
+            rgb = multiply(matrix, rgb).map(|c| c.max(0.0));
+        }
+
+        // Tone map luminance and scale color channels to preserve hue.
+        let l = luminance(rgb);
+        let scale = if l > 0.0 {
+            operator.apply(l, peak) / l
+        } else {
+            0.0
+        };
+        let rgb = rgb.map(|c| (srgb_encode((c * scale).min(1.0)) * 65535.0).round() as u16);
+
+        pixel.r = rgb[0];
+        pixel.g = rgb[1];
+        pixel.b = rgb[2];
+    });
+}
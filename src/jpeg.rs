@@ -3,30 +3,86 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use rgb::{alt::GRAY8, ComponentBytes, RGB8};
+use std::os::raw::{c_int, c_ulong};
+use std::sync::Arc;
+
+use mozjpeg::ColorSpaceExt;
+use mozjpeg_sys as ffi;
+use rgb::{alt::GRAY8, ComponentBytes, RGB8, RGBA8};
 
 use crate::common::{
-    exif_orientation, orient_image, ChromaSubsampling, ColorSpace, CompressResult, Image,
-    ReadResult,
+    exif_orientation, ChromaSubsampling, ColorSpace, CompressResult, Image, ReadOptions,
+    ReadResult, Rect,
 };
-use crate::profile::{is_srgb, GRAY_PROFILE, SRGB_PROFILE};
+use crate::metadata::{Density, Metadata, MetadataPolicy};
+use crate::profile::{read_transform, GRAY_PROFILE, SRGB_PROFILE};
+
+// Maximum length of marker data.
+const MAX_MARKER_SIZE: usize = 65533;
+
+// Identifier of APP1 marker containing XMP packet.
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+// Payload of unwinding from libjpeg error.
+struct JpegError(String);
+
+// Unwind instead of exiting the process like the standard error handler does. Libjpeg is compiled
+// with unwinding support, so this is caught by `catch_error` after the destructors have run.
+extern "C-unwind" fn error_exit(cinfo: &mut ffi::jpeg_common_struct) {
+    let mut buffer = [0; 80];
+    unsafe {
+        if let Some(format_message) = cinfo.err.as_ref().and_then(|err| err.format_message) {
+            format_message(cinfo, &buffer);
+        }
+    }
+    buffer[79] = 0;
+    let length = buffer.iter().position(|&c| c == 0).unwrap_or(0);
+    let message = String::from_utf8_lossy(&buffer[..length]).into_owned();
+    std::panic::resume_unwind(Box::new(JpegError(message)));
+}
+
+// Warnings such as corrupt data are not printed.
+extern "C" fn silence_message(_cinfo: &mut ffi::jpeg_common_struct, _level: c_int) {}
+
+fn error_mgr(err: &mut ffi::jpeg_error_mgr) -> &mut ffi::jpeg_error_mgr {
+    let err = unsafe { ffi::jpeg_std_error(err) };
+    // The field is declared without unwinding, but the library is compiled with it.
+    err.error_exit = Some(unsafe {
+        std::mem::transmute::<
+            extern "C-unwind" fn(&mut ffi::jpeg_common_struct),
+            unsafe extern "C" fn(&mut ffi::jpeg_common_struct),
+        >(error_exit)
+    });
+    err.emit_message = Some(silence_message);
+    err
+}
+
+// Run code using `Encoder` or `Decoder` and return libjpeg errors as `Err`.
+fn catch_error<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(
+        |payload| match payload.downcast::<JpegError>() {
+            Ok(err) => Err(err.0),
+            Err(payload) => std::panic::resume_unwind(payload),
+        },
+    )
+}
 
 // ICC profiles can be split into chunks and stored in multiple markers. Reconstruct the profile by
 // reading these markers and concatenating their data.
-fn jpeg_icc(dinfo: &mozjpeg::Decompress) -> Result<Option<Vec<u8>>, String> {
+fn jpeg_icc<'a>(markers: impl Iterator<Item = &'a [u8]>) -> Result<Option<Vec<u8>>, String> {
     let mut chunks = Vec::new();
     let mut total = 0;
-    for marker in dinfo.markers() {
-        if marker.data.starts_with(b"ICC_PROFILE\0") && marker.data.len() > 14 {
-            chunks.push((marker.data[12], &marker.data[14..]));
-            if total > 0 && total != marker.data[13] {
+    for data in markers {
+        if data.starts_with(b"ICC_PROFILE\0") && data.len() > 14 {
+            chunks.push((data[12], &data[14..]));
+            if total > 0 && total != data[13] {
                 return Err(format!(
                     "Failed to read ICC profile: different totals in two chunks (expected {} found {})",
                     total,
-                    marker.data[13]
+                    data[13]
                 ));
             }
-            total = marker.data[13];
+            total = data[13];
         }
     }
     if total as usize != chunks.len() {
@@ -47,49 +103,347 @@ fn jpeg_icc(dinfo: &mozjpeg::Decompress) -> Result<Option<Vec<u8>>, String> {
     }
 }
 
-pub fn read(buffer: &[u8]) -> ReadResult {
+// Minimal wrapper around the mozjpeg compressor. The mozjpeg crate doesn't expose mozjpeg specific
+// parameters such as trellis quantization and overshoot deringing.
+struct Encoder {
+    cinfo: ffi::jpeg_compress_struct,
+    err: ffi::jpeg_error_mgr,
+    outbuffer: *mut u8,
+    outsize: c_ulong,
+    scan_info: Vec<ffi::jpeg_scan_info>,
+}
+
+impl Encoder {
+    // Encoder is boxed because libjpeg keeps pointers to the error manager and output buffer.
+    fn alloc() -> Box<Self> {
+        unsafe {
+            let mut encoder = Box::new(Self {
+                cinfo: std::mem::zeroed(),
+                err: std::mem::zeroed(),
+                outbuffer: std::ptr::null_mut(),
+                outsize: 0,
+                scan_info: Vec::new(),
+            });
+            encoder.cinfo.common.err = error_mgr(&mut encoder.err);
+            ffi::jpeg_CreateCompress(
+                &mut encoder.cinfo,
+                ffi::JPEG_LIB_VERSION,
+                std::mem::size_of::<ffi::jpeg_compress_struct>(),
+            );
+            ffi::jpeg_mem_dest(
+                &mut encoder.cinfo,
+                &mut encoder.outbuffer,
+                &mut encoder.outsize,
+            );
+            encoder
+        }
+    }
+
+    fn new(color_space: mozjpeg::ColorSpace, width: usize, height: usize) -> Box<Self> {
+        let mut encoder = Self::alloc();
+        encoder.cinfo.in_color_space = color_space;
+        encoder.cinfo.input_components = color_space.num_components() as c_int;
+        encoder.cinfo.image_width = width as ffi::JDIMENSION;
+        encoder.cinfo.image_height = height as ffi::JDIMENSION;
+        unsafe {
+            ffi::jpeg_set_defaults(&mut encoder.cinfo);
+        }
+        encoder
+    }
+
+    // Encoder for writing DCT coefficients read by the decoder with the same parameters.
+    fn from_decoder(decoder: &Decoder) -> Box<Self> {
+        let mut encoder = Self::alloc();
+        unsafe {
+            ffi::jpeg_copy_critical_parameters(&decoder.dinfo, &mut encoder.cinfo);
+        }
+        encoder
+    }
+
+    fn set_bool_param(&mut self, param: ffi::J_BOOLEAN_PARAM, value: bool) {
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, param, value as ffi::boolean);
+        }
+    }
+
+    fn set_int_param(&mut self, param: ffi::J_INT_PARAM, value: c_int) {
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, param, value);
+        }
+    }
+
+    fn set_quality(&mut self, quality: u8) {
+        unsafe {
+            ffi::jpeg_set_quality(&mut self.cinfo, quality as c_int, false as ffi::boolean);
+        }
+    }
+
+    // Replace the chroma quantization table with the table of a different quality.
+    fn set_chroma_quality(&mut self, luma_quality: u8, chroma_quality: u8) {
+        self.set_quality(chroma_quality);
+        let chroma = unsafe { (*self.cinfo.quant_tbl_ptrs[1]).quantval };
+        self.set_quality(luma_quality);
+        unsafe {
+            (*self.cinfo.quant_tbl_ptrs[1]).quantval = chroma;
+        }
+    }
+
+    // Use a single baseline scan instead of the progressive scan script set by default.
+    fn set_baseline(&mut self) {
+        self.set_bool_param(ffi::J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, false);
+        self.cinfo.scan_info = std::ptr::null();
+        self.cinfo.num_scans = 0;
+    }
+
+    // Replace the default scan script. libjpeg keeps a pointer to the script, so it's stored in the
+    // encoder.
+    fn set_scans(&mut self, scans: &[Scan]) {
+        self.set_bool_param(ffi::J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, false);
+        self.scan_info = scans
+            .iter()
+            .map(|scan| {
+                let mut info = ffi::jpeg_scan_info {
+                    comps_in_scan: scan.components.len() as c_int,
+                    Ss: scan.spectral.0 as c_int,
+                    Se: scan.spectral.1 as c_int,
+                    Ah: scan.approximation.0 as c_int,
+                    Al: scan.approximation.1 as c_int,
+                    ..Default::default()
+                };
+                for (index, component) in info.component_index.iter_mut().zip(&scan.components) {
+                    *index = *component as c_int;
+                }
+                info
+            })
+            .collect();
+        self.cinfo.scan_info = self.scan_info.as_ptr();
+        self.cinfo.num_scans = self.scan_info.len() as c_int;
+    }
+
+    fn components_mut(&mut self) -> &mut [ffi::jpeg_component_info] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.cinfo.comp_info, self.cinfo.num_components as usize)
+        }
+    }
+
+    fn start(&mut self) {
+        unsafe {
+            ffi::jpeg_start_compress(&mut self.cinfo, true as ffi::boolean);
+        }
+    }
+
+    fn set_options(&mut self, options: &CompressOptions) {
+        if !options.progressive {
+            self.set_baseline();
+        } else if let Some(scans) = &options.scans {
+            self.set_scans(scans);
+        }
+        self.set_bool_param(
+            ffi::J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT,
+            options.trellis,
+        );
+        self.set_bool_param(
+            ffi::J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC,
+            options.trellis,
+        );
+        self.set_bool_param(
+            ffi::J_BOOLEAN_PARAM::JBOOLEAN_OVERSHOOT_DERINGING,
+            options.deringing,
+        );
+        self.cinfo.smoothing_factor = options.smoothing as c_int;
+        self.cinfo.optimize_coding = options.optimize_huffman as ffi::boolean;
+    }
+
+    fn set_density(&mut self, density: Density) {
+        let (unit, x, y) = density.to_jfif();
+        self.cinfo.density_unit = unit;
+        self.cinfo.X_density = x;
+        self.cinfo.Y_density = y;
+    }
+
+    fn write_marker(&mut self, marker: mozjpeg::Marker, data: &[u8]) {
+        unsafe {
+            ffi::jpeg_write_marker(
+                &mut self.cinfo,
+                marker.into(),
+                data.as_ptr(),
+                data.len() as std::os::raw::c_uint,
+            );
+        }
+    }
+
+    fn write_exif(&mut self, exif: &[u8]) {
+        let data = [b"Exif\0\0", exif].concat();
+        // Exif data doesn't fit in a single marker. Extended Exif spanning multiple markers is
+        // poorly supported, so skip the data instead.
+        if data.len() <= MAX_MARKER_SIZE {
+            self.write_marker(mozjpeg::Marker::APP(1), &data);
+        }
+    }
+
+    fn write_xmp(&mut self, xmp: &[u8]) {
+        let data = [XMP_NAMESPACE, xmp].concat();
+        // Extended XMP spanning multiple markers is not supported, so skip the data instead.
+        if data.len() <= MAX_MARKER_SIZE {
+            self.write_marker(mozjpeg::Marker::APP(1), &data);
+        }
+    }
+
+    // Split ICC profile into chunks that fit in APP2 markers.
+    fn write_icc(&mut self, profile: &[u8]) {
+        const MAX_CHUNK_SIZE: usize = MAX_MARKER_SIZE - 14;
+        let total = profile.len().div_ceil(MAX_CHUNK_SIZE);
+        for (index, chunk) in profile.chunks(MAX_CHUNK_SIZE).enumerate() {
+            self.write_marker(
+                mozjpeg::Marker::APP(2),
+                &[b"ICC_PROFILE\0", &[index as u8 + 1, total as u8][..], chunk].concat(),
+            );
+        }
+    }
+
+    // Write metadata of the image and its color profile, or the default profile of the color space.
+    fn write_metadata(&mut self, image: &Image, gray: bool) {
+        if let Some(exif) = &image.metadata.exif {
+            self.write_exif(exif);
+        }
+        if let Some(xmp) = &image.metadata.xmp {
+            self.write_xmp(xmp);
+        }
+        let profile = match &image.metadata.icc_profile {
+            Some(icc) => icc.as_slice(),
+            None if gray => GRAY_PROFILE,
+            None => SRGB_PROFILE,
+        };
+        self.write_icc(profile);
+    }
+
+    fn write_scanlines(&mut self, data: &[u8]) -> Result<(), String> {
+        let stride = self.cinfo.image_width as usize * self.cinfo.input_components as usize;
+        if data.len() != stride * self.cinfo.image_height as usize {
+            return Err("Image data has unexpected size".to_string());
+        }
+        let rows: Vec<*const u8> = data.chunks(stride).map(|row| row.as_ptr()).collect();
+        while self.cinfo.next_scanline < self.cinfo.image_height {
+            let rows = &rows[self.cinfo.next_scanline as usize..];
+            let written = unsafe {
+                ffi::jpeg_write_scanlines(
+                    &mut self.cinfo,
+                    rows.as_ptr() as ffi::JSAMPARRAY,
+                    rows.len() as ffi::JDIMENSION,
+                )
+            };
+            if written == 0 {
+                return Err("Failed to compress image data".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<u8>, String> {
+        unsafe {
+            ffi::jpeg_finish_compress(&mut self.cinfo);
+            if self.outbuffer.is_null() {
+                return Err("Failed to compress image".to_string());
+            }
+            Ok(std::slice::from_raw_parts(self.outbuffer, self.outsize as usize).to_vec())
+        }
+    }
+}
+
+// Minimal wrapper around the mozjpeg decompressor for reading DCT coefficients.
+struct Decoder<'a> {
+    dinfo: ffi::jpeg_decompress_struct,
+    err: ffi::jpeg_error_mgr,
+    buffer: std::marker::PhantomData<&'a [u8]>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buffer: &'a [u8]) -> Box<Self> {
+        unsafe {
+            let mut decoder: Box<Self> = Box::new(std::mem::zeroed());
+            decoder.dinfo.common.err = error_mgr(&mut decoder.err);
+            ffi::jpeg_CreateDecompress(
+                &mut decoder.dinfo,
+                ffi::JPEG_LIB_VERSION,
+                std::mem::size_of::<ffi::jpeg_decompress_struct>(),
+            );
+            ffi::jpeg_mem_src(&mut decoder.dinfo, buffer.as_ptr(), buffer.len() as c_ulong);
+            // Keep APP2 markers for reading ICC profile.
+            ffi::jpeg_save_markers(&mut decoder.dinfo, 0xe2, 0xffff);
+            ffi::jpeg_read_header(&mut decoder.dinfo, true as ffi::boolean);
+            decoder
+        }
+    }
+
+    fn markers(&self) -> impl Iterator<Item = &[u8]> {
+        let mut marker = self.dinfo.marker_list;
+        std::iter::from_fn(move || unsafe {
+            let current = marker.as_ref()?;
+            marker = current.next;
+            Some(std::slice::from_raw_parts(
+                current.data,
+                current.data_length as usize,
+            ))
+        })
+    }
+}
+
+impl Drop for Decoder<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::jpeg_destroy_decompress(&mut self.dinfo);
+        }
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::jpeg_destroy_compress(&mut self.cinfo);
+            if !self.outbuffer.is_null() {
+                libc::free(self.outbuffer as *mut libc::c_void);
+            }
+        }
+    }
+}
+
+pub fn read(buffer: &[u8], options: &ReadOptions) -> ReadResult {
     let dinfo = mozjpeg::Decompress::with_markers(&[mozjpeg::Marker::APP(2)])
         .from_mem(buffer)
         .map_err(|err| err.to_string())?;
 
-    let profile = match jpeg_icc(&dinfo) {
-        Ok(Some(icc)) => match lcms2::Profile::new_icc(&icc) {
-            Ok(x) => Some(x),
-            Err(err) => {
-                eprintln!("Failed to read ICC profile: {}", err);
-                None
-            }
-        },
-        Ok(None) => None,
-        Err(err) => {
-            eprintln!("Failed to read ICC profile: {}", err);
-            None
-        }
-    };
+    let icc = jpeg_icc(dinfo.markers().map(|marker| marker.data)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        None
+    });
+    let destination = options.color_space.profile();
+    let mut icc_profile = None;
 
     let (width, height) = dinfo.size();
+    options.check_dimensions(width, height)?;
 
-    let image = match dinfo.image() {
+    let mut image = match dinfo.image() {
         Ok(mozjpeg::decompress::Format::RGB(mut decompress)) => {
             let mut data: Vec<RGB8> = decompress
                 .read_scanlines()
                 .ok_or_else(|| "Failed decode image data".to_string())?;
             decompress.finish_decompress();
 
-            if let Some(profile) = profile {
-                if !is_srgb(&profile) {
-                    eprintln!("Transforming RGB to sRGB...");
-                    let transform = lcms2::Transform::new(
-                        &profile,
-                        lcms2::PixelFormat::RGB_8,
-                        &lcms2::Profile::new_srgb(),
-                        lcms2::PixelFormat::RGB_8,
-                        lcms2::Intent::Perceptual,
-                    )
-                    .map_err(|err| err.to_string())?;
-                    transform.transform_in_place(&mut data);
-                }
+            let color = read_transform(icc.as_deref(), options);
+            if let Some(profile) = color.source {
+                eprintln!("Transforming RGB to {}...", options.color_space);
+                let transform = lcms2::Transform::new_flags(
+                    &profile,
+                    lcms2::PixelFormat::RGB_8,
+                    &destination,
+                    lcms2::PixelFormat::RGB_8,
+                    options.rendering_intent.intent(),
+                    options.transform_flags(),
+                )
+                .map_err(|err| err.to_string())?;
+                transform.transform_in_place(&mut data);
             }
+            icc_profile = color.icc_profile;
 
             Ok(Image::from_rgb(data, width, height))
         }
@@ -99,31 +453,33 @@ pub fn read(buffer: &[u8]) -> ReadResult {
                 .ok_or_else(|| "Failed decode image data".to_string())?;
             decompress.finish_decompress();
 
-            if let Some(profile) = profile {
-                if !is_srgb(&profile) {
-                    eprintln!("Transforming Gray to sRGB...");
-                    let transform = lcms2::Transform::new(
-                        &profile,
-                        lcms2::PixelFormat::GRAY_8,
-                        &lcms2::Profile::new_srgb(),
-                        lcms2::PixelFormat::RGB_8,
-                        lcms2::Intent::Perceptual,
-                    )
-                    .map_err(|err| err.to_string())?;
+            // Grayscale image data without a profile is assumed to be in sRGB.
+            let color = read_transform(Some(icc.as_deref().unwrap_or(GRAY_PROFILE)), options);
+            if let Some(profile) = color.source {
+                eprintln!("Transforming Gray to {}...", options.color_space);
+                let transform = lcms2::Transform::new_flags(
+                    &profile,
+                    lcms2::PixelFormat::GRAY_8,
+                    &destination,
+                    lcms2::PixelFormat::RGB_8,
+                    options.rendering_intent.intent(),
+                    options.transform_flags(),
+                )
+                .map_err(|err| err.to_string())?;
 
-                    let mut output = vec![RGB8::new(0, 0, 0); data.len()];
-                    transform.transform_pixels(&data, &mut output);
+                let mut output = vec![RGB8::new(0, 0, 0); data.len()];
+                transform.transform_pixels(&data, &mut output);
 
-                    Ok(Image::from_rgb(output, width, height))
-                } else {
-                    Ok(Image::from_gray(data, width, height))
-                }
+                icc_profile = color.icc_profile;
+                Ok(Image::from_rgb(output, width, height))
             } else {
                 Ok(Image::from_gray(data, width, height))
             }
         }
         Ok(mozjpeg::decompress::Format::CMYK(mut decompress)) => {
-            let profile = profile
+            let profile = icc
+                .as_ref()
+                .and_then(|icc| lcms2::Profile::new_icc(icc).ok())
                 .ok_or_else(|| "Expected ICC profile for JPEG in CMYK color space".to_string())?;
 
             let data: Vec<[u8; 4]> = decompress
@@ -131,53 +487,226 @@ pub fn read(buffer: &[u8]) -> ReadResult {
                 .ok_or_else(|| "Failed decode image data".to_string())?;
             decompress.finish_decompress();
 
-            eprintln!("Transforming CMYK to sRGB...");
-            let transform = lcms2::Transform::new(
+            eprintln!("Transforming CMYK to {}...", options.color_space);
+            let transform = lcms2::Transform::new_flags(
                 &profile,
                 lcms2::PixelFormat::CMYK_8_REV,
-                &lcms2::Profile::new_srgb(),
+                &destination,
                 lcms2::PixelFormat::RGB_8,
-                lcms2::Intent::Perceptual,
+                options.rendering_intent.intent(),
+                options.transform_flags(),
             )
             .map_err(|err| err.to_string())?;
 
             let mut output = vec![RGB8::new(0, 0, 0); data.len()];
             transform.transform_pixels(&data, &mut output);
 
+            icc_profile = options.color_space.icc();
             Ok(Image::from_rgb(output, width, height))
         }
         Err(err) => Err(format!("Failed decode image data: {}", err)),
     }?;
 
-    let orientation = exif::Reader::new()
+    let exif = exif::Reader::new()
         .read_from_container(&mut std::io::Cursor::new(buffer))
-        .ok()
-        .and_then(exif_orientation)
-        .unwrap_or(1);
+        .ok();
+    let orientation = exif.as_ref().and_then(exif_orientation).unwrap_or(1);
+    image.metadata = Metadata::from_exif(exif.as_ref());
+    image.metadata.icc_profile = icc_profile;
+    image.metadata.xmp = jpeg_xmp(buffer);
+    if let Some(density) = jfif_density(buffer) {
+        image.metadata.density = Some(density);
+    }
+
+    Ok(options.orientation.apply(image, orientation))
+}
+
+/// Scan of a progressive JPEG. See `wizard.txt` of libjpeg for the meaning of the values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    /// Indices of the components in the scan, 0 for luma and 1 and 2 for chroma
+    pub components: Vec<u8>,
+    /// First and last DCT coefficient in zigzag order
+    pub spectral: (u8, u8),
+    /// High and low bit positions of successive approximation
+    pub approximation: (u8, u8),
+}
+
+/// Parse scan script in the format of `cjpeg -scans`. Each scan lists its components, optionally
+/// followed by spectral selection and successive approximation, e.g. `0,1,2: 0-0, 0, 1;`. Text
+/// after `#` is a comment.
+pub fn parse_scans(script: &str) -> Result<Vec<Scan>, String> {
+    let script: String = script
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let parse_value = |value: &str, max: u8| match value.trim().parse::<u8>() {
+        Ok(value) if value <= max => Ok(value),
+        _ => Err(format!("invalid value {:?} in scan script", value.trim())),
+    };
+    let mut scans = Vec::new();
+    for scan in script
+        .split(';')
+        .map(str::trim)
+        .filter(|scan| !scan.is_empty())
+    {
+        let (components, parameters) = match scan.split_once(':') {
+            Some((components, parameters)) => (components, Some(parameters)),
+            None => (scan, None),
+        };
+        let components = components
+            .split(',')
+            .map(|component| parse_value(component, 3))
+            .collect::<Result<Vec<_>, _>>()?;
+        if components.len() > 4 {
+            return Err("expected at most 4 components in a scan".to_string());
+        }
+        let (spectral, approximation) = match parameters {
+            Some(parameters) => match parameters.split(',').collect::<Vec<_>>()[..] {
+                [spectral, high, low] => {
+                    let (first, last) = spectral.split_once('-').ok_or_else(|| {
+                        format!("invalid spectral selection {:?}", spectral.trim())
+                    })?;
+                    (
+                        (parse_value(first, 63)?, parse_value(last, 63)?),
+                        (parse_value(high, 13)?, parse_value(low, 13)?),
+                    )
+                }
+                _ => return Err(format!("invalid scan {:?}", scan)),
+            },
+            None => ((0, 63), (0, 0)),
+        };
+        scans.push(Scan {
+            components,
+            spectral,
+            approximation,
+        });
+    }
+    if scans.is_empty() {
+        return Err("scan script is empty".to_string());
+    }
+    Ok(scans)
+}
+
+/// Region compressed with quality changed by `delta` from the quality of the rest of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityRegion {
+    pub rect: Rect,
+    pub delta: i8,
+}
 
-    Ok(orient_image(image, orientation))
+#[derive(Clone)]
+pub struct CompressOptions {
+    /// Encode image in multiple scans instead of a single baseline scan
+    pub progressive: bool,
+    /// Scan script of progressive images instead of the scans optimized by mozjpeg
+    pub scans: Option<Arc<[Scan]>>,
+    /// Use trellis quantization to choose quantized coefficients by rate-distortion
+    pub trellis: bool,
+    /// Reduce ringing artifacts around hard edges on white background (e.g. text)
+    pub deringing: bool,
+    /// Smooth image data before compression to reduce noise, 0 disables smoothing
+    pub smoothing: u8,
+    /// Compute optimal Huffman tables instead of using the standard tables, progressive images
+    /// always use optimal tables
+    pub optimize_huffman: bool,
+    /// Difference of chroma quality from luma quality
+    pub chroma_quality_offset: i8,
+    /// Regions quantized with a different quality than the rest of the image, later regions take
+    /// precedence where they overlap
+    pub regions: Arc<[QualityRegion]>,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            progressive: true,
+            scans: None,
+            trellis: true,
+            deringing: true,
+            smoothing: 0,
+            optimize_huffman: true,
+            chroma_quality_offset: 0,
+            regions: Arc::new([]),
+        }
+    }
 }
 
 pub fn compress(
     image: &Image,
     quality: u8,
     chroma_subsampling: ChromaSubsampling,
+    options: &CompressOptions,
 ) -> CompressResult {
-    let mut cinfo = mozjpeg::Compress::new(match image.color_space {
-        ColorSpace::Gray => mozjpeg::ColorSpace::JCS_GRAYSCALE,
-        _ => mozjpeg::ColorSpace::JCS_EXT_RGBX,
-    });
-    cinfo.set_size(image.width, image.height);
-    cinfo.set_quality(quality as f32);
-    cinfo.set_mem_dest();
+    catch_error(|| compress_image(image, quality, chroma_subsampling, options))
+}
+
+fn compress_image(
+    image: &Image,
+    quality: u8,
+    chroma_subsampling: ChromaSubsampling,
+    options: &CompressOptions,
+) -> CompressResult {
+    // Grayscale JPEG can't use an RGB color profile.
+    let gray = image.color_space == ColorSpace::Gray && image.metadata.icc_profile.is_none();
+    let cdata = if options.regions.iter().all(|region| region.delta == 0) {
+        encode(image, quality, chroma_subsampling, options, gray)?
+    } else {
+        quantize_regions(image, quality, chroma_subsampling, options, gray)?
+    };
 
-    if image.color_space != ColorSpace::Gray {
+    // Compressed data is already in the color space of the input image, so pixels can be decoded
+    // for comparison without reading metadata or transforming colors.
+    let mut decompress = mozjpeg::Decompress::new_mem(&cdata)
+        .and_then(mozjpeg::Decompress::rgba)
+        .map_err(|err| err.to_string())?;
+    let data: Vec<RGBA8> = decompress
+        .read_scanlines()
+        .ok_or_else(|| "Failed decode image data".to_string())?;
+    decompress.finish_decompress();
+
+    Ok((Image::from_rgba(data, image.width, image.height), cdata))
+}
+
+fn region_quality(quality: u8, delta: i8) -> u8 {
+    (quality as i16 + delta as i16).clamp(1, 100) as u8
+}
+
+fn encode(
+    image: &Image,
+    quality: u8,
+    chroma_subsampling: ChromaSubsampling,
+    options: &CompressOptions,
+    gray: bool,
+) -> Result<Vec<u8>, String> {
+    let mut encoder = Encoder::new(
+        if gray {
+            mozjpeg::ColorSpace::JCS_GRAYSCALE
+        } else {
+            mozjpeg::ColorSpace::JCS_EXT_RGBX
+        },
+        image.width,
+        image.height,
+    );
+    encoder.set_quality(quality);
+    encoder.set_options(options);
+    if !gray && options.chroma_quality_offset != 0 {
+        encoder.set_chroma_quality(
+            quality,
+            region_quality(quality, options.chroma_quality_offset),
+        );
+    }
+
+    if !gray {
         let chroma_subsampling = match chroma_subsampling {
             ChromaSubsampling::_444 => [[1, 1], [1, 1], [1, 1]],
             ChromaSubsampling::_422 => [[2, 1], [1, 1], [1, 1]],
             ChromaSubsampling::_420 => [[2, 2], [1, 1], [1, 1]],
+            ChromaSubsampling::_440 => [[1, 2], [1, 1], [1, 1]],
+            ChromaSubsampling::_411 => [[4, 1], [1, 1], [1, 1]],
         };
-        for (c, samp) in cinfo
+        for (c, samp) in encoder
             .components_mut()
             .iter_mut()
             .zip(chroma_subsampling.iter())
@@ -187,27 +716,343 @@ pub fn compress(
         }
     }
 
-    cinfo.start_compress();
-    let profile = match image.color_space {
-        ColorSpace::Gray => GRAY_PROFILE,
-        _ => SRGB_PROFILE,
+    if let Some(density) = image.metadata.density {
+        encoder.set_density(density);
+    }
+    encoder.start();
+    encoder.write_metadata(image, gray);
+    if gray {
+        encoder.write_scanlines(image.to_gray().buf().as_bytes())?;
+    } else {
+        encoder.write_scanlines(image.as_bytes())?;
+    }
+    encoder.finish()
+}
+
+// JPEG has a single quantization table per component, so quality of regions is changed by
+// combining DCT coefficients of images compressed with each quality. The output uses the tables of
+// the highest quality, and blocks of lower quality are copied from their own image scaled by the
+// ratio of the tables. This reproduces the lower quality closely, and the coefficients zeroed by
+// coarser quantization make the blocks smaller. Block has the quality at its center.
+fn quantize_regions(
+    image: &Image,
+    quality: u8,
+    chroma_subsampling: ChromaSubsampling,
+    options: &CompressOptions,
+    gray: bool,
+) -> Result<Vec<u8>, String> {
+    let block_quality = |x: usize, y: usize| {
+        options
+            .regions
+            .iter()
+            .rev()
+            .find(|region| region.rect.contains(x, y))
+            .map_or(quality, |region| region_quality(quality, region.delta))
     };
-    cinfo.write_marker(
-        mozjpeg::Marker::APP(2),
-        &[b"ICC_PROFILE\0\x01\x01", profile].concat(),
+    let mut qualities: Vec<u8> = options
+        .regions
+        .iter()
+        .map(|region| region_quality(quality, region.delta))
+        .chain([quality])
+        .collect();
+    qualities.sort_unstable();
+    qualities.dedup();
+    let buffers = qualities
+        .iter()
+        .map(|quality| encode(image, *quality, chroma_subsampling, options, gray))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut decoders = Vec::new();
+    for buffer in &buffers {
+        let mut decoder = Decoder::new(buffer);
+        let coefficients = unsafe { ffi::jpeg_read_coefficients(&mut decoder.dinfo) };
+        if coefficients.is_null() {
+            return Err("Failed to read DCT coefficients".to_string());
+        }
+        decoders.push((decoder, coefficients));
+    }
+    // Output is written from the coefficients of the highest quality.
+    let ((output, output_coefficients), sources) = decoders.split_last_mut().unwrap();
+
+    let (max_h_samp, max_v_samp) = (
+        output.dinfo.max_h_samp_factor as usize,
+        output.dinfo.max_v_samp_factor as usize,
     );
-    if !match image.color_space {
-        ColorSpace::Gray => cinfo.write_scanlines(image.to_gray().buf().as_bytes()),
-        _ => cinfo.write_scanlines(image.as_bytes()),
-    } {
-        return Err("Failed to compress image data".to_string());
+    let num_components = output.dinfo.num_components as usize;
+    for index in 0..num_components {
+        let component = unsafe { &*output.dinfo.comp_info.add(index) };
+        let table = unsafe { (*component.quant_table).quantval };
+        // Size of the block in pixels of the image.
+        let block_width = 8 * max_h_samp / component.h_samp_factor as usize;
+        let block_height = 8 * max_v_samp / component.v_samp_factor as usize;
+        for row in 0..component.height_in_blocks {
+            let blocks = unsafe { block_row(output, *output_coefficients, index, row, true)? };
+            for (column, block) in blocks.iter_mut().enumerate() {
+                let quality = block_quality(
+                    column * block_width + block_width / 2,
+                    row as usize * block_height + block_height / 2,
+                );
+                let source = match qualities.iter().position(|q| *q == quality) {
+                    Some(source) if source < sources.len() => source,
+                    _ => continue,
+                };
+                let (decoder, coefficients) = &mut sources[source];
+                let source_table =
+                    unsafe { (*(*decoder.dinfo.comp_info.add(index)).quant_table).quantval };
+                let source_block =
+                    unsafe { &block_row(decoder, *coefficients, index, row, false)?[column] };
+                for (i, coefficient) in block.iter_mut().enumerate() {
+                    let value = source_block[i] as f64 * source_table[i] as f64;
+                    *coefficient = (value / table[i] as f64).round() as ffi::JCOEF;
+                }
+            }
+        }
     }
-    cinfo.finish_compress();
 
-    let cdata = cinfo
-        .data_to_vec()
-        .map_err(|_err| "Failed to compress image".to_string())?;
-    let image = read(&cdata)?;
+    let mut encoder = Encoder::from_decoder(output);
+    // Coefficients are already quantized, so trellis quantization and smoothing don't apply.
+    encoder.set_options(&CompressOptions {
+        trellis: false,
+        smoothing: 0,
+        ..options.clone()
+    });
+    if let Some(density) = image.metadata.density {
+        encoder.set_density(density);
+    }
+    unsafe {
+        ffi::jpeg_write_coefficients(&mut encoder.cinfo, *output_coefficients);
+    }
+    encoder.write_metadata(image, gray);
+    let cdata = encoder.finish()?;
+    for (decoder, _) in &mut decoders {
+        unsafe {
+            ffi::jpeg_finish_decompress(&mut decoder.dinfo);
+        }
+    }
+    Ok(cdata)
+}
+
+// Row of DCT coefficient blocks of a component read by `jpeg_read_coefficients`.
+unsafe fn block_row<'a>(
+    decoder: &'a mut Decoder,
+    coefficients: *mut *mut ffi::jvirt_barray_control,
+    component: usize,
+    row: ffi::JDIMENSION,
+    writable: bool,
+) -> Result<&'a mut [ffi::JBLOCK], String> {
+    let width = (*decoder.dinfo.comp_info.add(component)).width_in_blocks as usize;
+    let access = (*decoder.dinfo.common.mem)
+        .access_virt_barray
+        .ok_or_else(|| "Failed to read DCT coefficients".to_string())?;
+    let rows = access(
+        &mut decoder.dinfo.common,
+        *coefficients.add(component),
+        row,
+        1,
+        writable as ffi::boolean,
+    );
+    Ok(std::slice::from_raw_parts_mut(*rows, width))
+}
+
+/// Estimate quality of JPEG image by finding the quality and base quantization table of libjpeg or
+/// mozjpeg that produce luminance quantization table closest to the one in the image.
+pub fn estimate_quality(buffer: &[u8]) -> Option<u8> {
+    catch_error(|| Ok(estimate(buffer))).ok().flatten()
+}
+
+fn estimate(buffer: &[u8]) -> Option<u8> {
+    let decoder = Decoder::new(buffer);
+    let table = unsafe { decoder.dinfo.quant_tbl_ptrs[0].as_ref()? }.quantval;
+    let mut encoder = Encoder::new(mozjpeg::ColorSpace::JCS_GRAYSCALE, 8, 8);
+    let mut best: Option<(u32, u8)> = None;
+    // Base tables 0-8 are provided by mozjpeg, libjpeg uses table 0 from JPEG specification.
+    for index in 0..=8 {
+        encoder.set_int_param(ffi::J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, index);
+        for quality in 1..=100 {
+            encoder.set_quality(quality);
+            let candidate = unsafe { encoder.cinfo.quant_tbl_ptrs[0].as_ref()? }.quantval;
+            let distance = table
+                .iter()
+                .zip(&candidate)
+                .map(|(a, b)| a.abs_diff(*b) as u32)
+                .sum();
+            if best.is_none_or(|(best_distance, _)| distance < best_distance) {
+                best = Some((distance, quality));
+            }
+        }
+    }
+    best.map(|(_, quality)| quality)
+}
+
+/// Optimize JPEG losslessly by writing its DCT coefficients again without decoding image data.
+/// Huffman tables and scans are optimized, and metadata other than the color profile is handled
+/// according to the policy.
+pub fn transcode(
+    buffer: &[u8],
+    policy: MetadataPolicy,
+    options: &CompressOptions,
+) -> Result<Vec<u8>, String> {
+    catch_error(|| transcode_coefficients(buffer, policy, options))
+}
+
+fn transcode_coefficients(
+    buffer: &[u8],
+    policy: MetadataPolicy,
+    options: &CompressOptions,
+) -> Result<Vec<u8>, String> {
+    let mut decoder = Decoder::new(buffer);
+    let icc = jpeg_icc(decoder.markers())?;
+
+    // Image data is not rotated, so orientation must be kept.
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(buffer))
+        .ok();
+    let orientation = exif.as_ref().and_then(exif_orientation).unwrap_or(1);
+    let mut metadata = Metadata::from_exif(exif.as_ref());
+    metadata.xmp = jpeg_xmp(buffer);
+    if let Some(density) = jfif_density(buffer) {
+        metadata.density = Some(density);
+    }
+    let metadata = metadata.apply_policy_with_orientation(policy, orientation);
+
+    let coefficients = unsafe { ffi::jpeg_read_coefficients(&mut decoder.dinfo) };
+    if coefficients.is_null() {
+        return Err("Failed to read DCT coefficients".to_string());
+    }
+
+    let mut encoder = Encoder::from_decoder(&decoder);
+    // Coefficients are already quantized, so trellis quantization and smoothing don't apply.
+    encoder.set_options(&CompressOptions {
+        trellis: false,
+        smoothing: 0,
+        ..options.clone()
+    });
+    if let Some(density) = metadata.density {
+        encoder.set_density(density);
+    }
+    unsafe {
+        ffi::jpeg_write_coefficients(&mut encoder.cinfo, coefficients);
+    }
+    if let Some(exif) = &metadata.exif {
+        encoder.write_exif(exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        encoder.write_xmp(xmp);
+    }
+    if let Some(icc) = &icc {
+        encoder.write_icc(icc);
+    }
+    let cdata = encoder.finish()?;
+    unsafe {
+        ffi::jpeg_finish_decompress(&mut decoder.dinfo);
+    }
+    Ok(cdata)
+}
+
+// Find segments before the image data. Returns offset, marker and data of each segment.
+fn read_segments(buffer: &[u8]) -> Vec<(usize, u8, &[u8])> {
+    let mut segments = Vec::new();
+    let mut offset = 2;
+    while let Some([0xff, marker, a, b]) = buffer.get(offset..offset + 4) {
+        // Start of scan is followed by the image data.
+        if *marker == 0xda {
+            break;
+        }
+        let length = u16::from_be_bytes([*a, *b]) as usize;
+        let data = match buffer.get(offset + 4..offset + 2 + length) {
+            Some(data) => data,
+            None => break,
+        };
+        segments.push((offset, *marker, data));
+        offset += 2 + length;
+    }
+    segments
+}
+
+// Read density from the JFIF segment.
+fn jfif_density(buffer: &[u8]) -> Option<Density> {
+    read_segments(buffer)
+        .into_iter()
+        .find_map(|(_, marker, data)| match data {
+            [b'J', b'F', b'I', b'F', 0, _, _, unit, x1, x2, y1, y2, ..] if marker == 0xe0 => {
+                Density::from_jfif(
+                    *unit,
+                    u16::from_be_bytes([*x1, *x2]),
+                    u16::from_be_bytes([*y1, *y2]),
+                )
+            }
+            _ => None,
+        })
+}
+
+// Read XMP packet from APP1 segment.
+fn jpeg_xmp(buffer: &[u8]) -> Option<Vec<u8>> {
+    read_segments(buffer)
+        .into_iter()
+        .filter(|(_, marker, _)| *marker == 0xe1)
+        .find_map(|(_, _, data)| data.strip_prefix(XMP_NAMESPACE))
+        .map(<[u8]>::to_vec)
+}
+
+/// Add comment segment after the application segments, which must come first for JFIF and Exif.
+pub fn add_comment(buffer: &[u8], text: &str) -> Vec<u8> {
+    let position = read_segments(buffer)
+        .into_iter()
+        .find(|(_, marker, _)| !(0xe0..=0xef).contains(marker))
+        .map_or(2, |(offset, _, _)| offset);
+    let length = (text.len().min(MAX_MARKER_SIZE) + 2) as u16;
+    [
+        &buffer[..position],
+        &[0xff, 0xfe],
+        &length.to_be_bytes(),
+        &text.as_bytes()[..length as usize - 2],
+        &buffer[position..],
+    ]
+    .concat()
+}
+
+/// Read texts of comment segments.
+pub fn read_comments(buffer: &[u8]) -> Vec<String> {
+    read_segments(buffer)
+        .into_iter()
+        .filter(|(_, marker, _)| *marker == 0xfe)
+        .map(|(_, _, data)| String::from_utf8_lossy(data).into_owned())
+        .collect()
+}
+
+/// Read width and height from the frame header.
+pub fn dimensions(buffer: &[u8]) -> Option<(usize, usize)> {
+    match start_of_frame(buffer)? {
+        [_, h1, h2, w1, w2, ..] => Some((
+            u16::from_be_bytes([*w1, *w2]) as usize,
+            u16::from_be_bytes([*h1, *h2]) as usize,
+        )),
+        _ => None,
+    }
+}
+
+/// Read chroma subsampling from the sampling factors of YCbCr components. `None` is returned for
+/// grayscale images and uncommon sampling factors.
+pub fn chroma_subsampling(buffer: &[u8]) -> Option<ChromaSubsampling> {
+    // Each component has id, sampling factors and quantization table.
+    match start_of_frame(buffer)? {
+        [_, _, _, _, _, 3, _, y, _, _, 0x11, _, _, 0x11, _] => match y {
+            0x22 => Some(ChromaSubsampling::_420),
+            0x21 => Some(ChromaSubsampling::_422),
+            0x11 => Some(ChromaSubsampling::_444),
+            0x12 => Some(ChromaSubsampling::_440),
+            0x41 => Some(ChromaSubsampling::_411),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
-    Ok((image, cdata))
+fn start_of_frame(buffer: &[u8]) -> Option<&[u8]> {
+    read_segments(buffer)
+        .into_iter()
+        // Start of frame markers, excluding DHT, JPG and DAC which share the range.
+        .find(|(_, marker, _)| {
+            (0xc0..=0xcf).contains(marker) && ![0xc4, 0xc8, 0xcc].contains(marker)
+        })
+        .map(|(_, _, data)| data)
 }
@@ -1,10 +1,29 @@
 // SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+pub mod archive;
+pub mod cache;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod color;
 pub mod common;
+pub mod denoise;
+pub mod hdr;
+#[cfg(feature = "codecs")]
 pub mod jpeg;
+pub mod metadata;
 pub mod output;
 pub mod png;
 pub mod profile;
+#[cfg(feature = "saliency")]
+pub mod saliency;
+pub mod search;
 pub mod ssim;
+#[cfg(feature = "async")]
+pub mod task;
+#[cfg(feature = "codecs")]
 pub mod webp;
+
+#[cfg(feature = "async")]
+pub use task::optimize_async;
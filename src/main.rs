@@ -3,39 +3,80 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use clap::{ArgEnum, Parser};
+use clap::{ArgEnum, FromArgMatches, IntoApp, Parser, Subcommand};
 use rgb::RGB8;
+use sha2::{Digest, Sha256};
 
-use pio::common::{ChromaSubsampling, ChromaSubsamplingOption, CompressResult, Format, Image};
+use pio::cache::Cache;
+use pio::cancel::CancellationToken;
+use pio::color::parse_color;
+use pio::common::{
+    orient_image, ChromaSubsampling, ChromaSubsamplingOption, Format, Image, OrientationPolicy,
+    ReadOptions, ReadResult, Rect,
+};
+use pio::hdr::ToneMapping;
+use pio::jpeg::QualityRegion;
+use pio::metadata::MetadataPolicy;
 use pio::output::Output;
-use pio::{jpeg, png, ssim, webp};
-
-type LossyCompressor = Box<dyn Fn(&Image, u8, ChromaSubsampling) -> CompressResult>;
-type LosslessCompressor = Box<dyn Fn(&Image) -> CompressResult>;
-
-#[rustfmt::skip]
-const QUALITY_SSIM: [f64; 101] = [
-    0.64405, 0.64405, 0.493921, 0.3717685, 0.2875005, 0.226447, 0.18505, 0.155942,
-    0.13402550000000002, 0.1161245, 0.10214999999999999, 0.09164900000000001, 0.0830645,
-    0.0747825, 0.0686465, 0.0636275, 0.058777499999999996, 0.054973999999999995, 0.0509935,
-    0.048128000000000004, 0.0452685, 0.0428175, 0.0404645, 0.0387125, 0.036169999999999994,
-    0.034700999999999996, 0.03334, 0.0319895, 0.029954, 0.029339499999999998, 0.028261,
-    0.0271415, 0.025916, 0.0248545, 0.0244545, 0.023451, 0.022603, 0.022269, 0.021344, 0.020581,
-    0.0202495, 0.019450000000000002, 0.019161499999999998, 0.0189065, 0.018063, 0.017832,
-    0.0169555, 0.016857999999999998, 0.016676, 0.0159105, 0.0157275, 0.015555,
-    0.014891499999999998, 0.014727, 0.0145845, 0.013921, 0.0137565, 0.0135065, 0.012928,
-    0.012669, 0.0125305, 0.011922499999999999, 0.011724, 0.011544, 0.0112675, 0.0107825,
-    0.010481, 0.010245, 0.009772, 0.0095075, 0.009262, 0.008721, 0.0084715, 0.008324999999999999,
-    0.007556500000000001, 0.0074540000000000006, 0.007243, 0.0067735, 0.0066254999999999994,
-    0.006356499999999999, 0.005924499999999999, 0.005674500000000001, 0.005422, 0.0050215,
-    0.0047565, 0.0044755, 0.0041294999999999995, 0.0038510000000000003, 0.00361, 0.003372,
-    0.0029255, 0.0027010000000000003, 0.0024415, 0.002091, 0.0017955, 0.001591, 0.001218,
-    0.0009805, 0.000749, 0.000548, 0.0004,
-];
+use pio::profile::{RenderingIntent, TargetColorSpace};
+use pio::search::{
+    Compressed, FixedCompressor, LossyCompressor, QualitySearch, SearchStrategy, Setting,
+};
+use pio::{denoise, jpeg, png, ssim, webp};
+
+// Rough estimate of peak memory usage per pixel of input image including image data, compressed
+// candidates and SSIM calculation.
+const MEMORY_PER_PIXEL: u64 = 256;
+
+// Downscale factor for `--fast-ssim`, which keeps compared images at roughly 10 megapixels or
+// less.
+fn fast_ssim_scale(width: usize, height: usize) -> usize {
+    match width * height {
+        pixels if pixels > 40_000_000 => 4,
+        pixels if pixels > 10_000_000 => 2,
+        _ => 1,
+    }
+}
+
+fn quality_curve(args: &Args, format: Format) -> Result<[f64; 101], String> {
+    match &args.quality_curve {
+        Some(path) => read_quality_curve(path),
+        None => Ok(*pio::search::quality_curve(format)),
+    }
+}
+
+// Read table of target DSSIM for each quality written by `pio calibrate`.
+fn read_quality_curve(path: &Path) -> Result<[f64; 101], String> {
+    let invalid = || {
+        format!(
+            "invalid quality curve {}, expected DSSIM for each quality between 0 and 100",
+            path.display()
+        )
+    };
+    let curve = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let mut table = [f64::NAN; 101];
+    // The first line is a header.
+    for line in curve.lines().skip(1).filter(|line| !line.is_empty()) {
+        let (quality, dssim) = line.split_once(',').ok_or_else(invalid)?;
+        let quality = parse_quality(quality).map_err(|_| invalid())?;
+        table[quality as usize] = dssim.parse().map_err(|_| invalid())?;
+    }
+    if table.iter().any(|dssim| dssim.is_nan()) {
+        return Err(invalid());
+    }
+    Ok(table)
+}
 
 fn parse_quality(x: &str) -> Result<u8, &'static str> {
     match x.parse::<u8>() {
@@ -50,28 +91,509 @@ fn parse_quality(x: &str) -> Result<u8, &'static str> {
     }
 }
 
-fn parse_color(input: &str) -> Result<RGB8, String> {
-    if input.len() != 7 || !input.starts_with('#') {
-        return Err("expected format #rrggbb".to_string());
+fn parse_png_effort(x: &str) -> Result<u8, &'static str> {
+    match x.parse::<u8>() {
+        Ok(x) if (1..=10).contains(&x) => Ok(x),
+        _ => Err("expected value between 1 and 10"),
+    }
+}
+
+fn parse_posterize(x: &str) -> Result<u8, &'static str> {
+    match x.parse::<u8>() {
+        Ok(x) if x <= 4 => Ok(x),
+        _ => Err("expected value between 0 and 4"),
+    }
+}
+
+fn parse_webp_method(x: &str) -> Result<u8, &'static str> {
+    match x.parse::<u8>() {
+        Ok(x) if x <= 6 => Ok(x),
+        _ => Err("expected value between 0 and 6"),
+    }
+}
+
+// Scan script is read while parsing arguments, so that it's included in the cached options.
+fn parse_scans(path: &str) -> Result<Arc<[jpeg::Scan]>, String> {
+    let script = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    Ok(jpeg::parse_scans(&script)?.into())
+}
+
+// Target quality for all output formats with optional overrides for specific formats.
+#[derive(Debug, Clone, PartialEq)]
+struct Quality {
+    default: u8,
+    formats: Vec<(Format, u8)>,
+}
+
+impl Quality {
+    fn for_format(&self, format: Format) -> Option<u8> {
+        self.formats
+            .iter()
+            .find(|(f, _)| *f == format)
+            .map(|(_, quality)| *quality)
+    }
+}
+
+fn parse_quality_option(x: &str) -> Result<Quality, String> {
+    let mut quality = Quality {
+        default: 85,
+        formats: Vec::new(),
+    };
+    for part in x.split(',') {
+        match part.split_once('=') {
+            Some((format, value)) => {
+                let format = Format::from_ext(format).ok_or_else(|| {
+                    format!("unknown format `{}`, expected jpeg, png or webp", format)
+                })?;
+                quality.formats.push((format, parse_quality(value)?));
+            }
+            None => quality.default = parse_quality(part)?,
+        }
+    }
+    Ok(quality)
+}
+
+fn parse_png_colors(x: &str) -> Result<u32, &'static str> {
+    match x.parse::<u32>() {
+        Ok(x) if (2..=256).contains(&x) => Ok(x),
+        _ => Err("expected value between 2 and 256"),
+    }
+}
+
+fn parse_size(x: &str) -> Result<u64, &'static str> {
+    let (number, multiplier) = match x.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&x[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&x[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&x[..i], 1 << 30),
+        _ => (x, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or("expected size in bytes with optional K, M or G suffix")
+}
+
+// Minimum savings as percentage of the input size or as bytes.
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum Savings {
+    Percent(f64),
+    Bytes(u64),
+}
+
+impl Savings {
+    fn is_reached(&self, original_size: usize, output_size: usize) -> bool {
+        let saved = original_size.saturating_sub(output_size);
+        match self {
+            Self::Percent(percent) => 100.0 * saved as f64 >= percent * original_size as f64,
+            Self::Bytes(bytes) => saved as u64 >= *bytes,
+        }
+    }
+}
+
+fn parse_savings(x: &str) -> Result<Savings, &'static str> {
+    match x.strip_suffix('%') {
+        Some(percent) => percent
+            .parse::<f64>()
+            .ok()
+            .filter(|percent| (0.0..=100.0).contains(percent))
+            .map(Savings::Percent)
+            .ok_or("expected percentage between 0 and 100"),
+        None => parse_size(x).map(Savings::Bytes).map_err(|_| {
+            "expected percentage (e.g. 5%) or size in bytes with optional K, M or G suffix"
+        }),
+    }
+}
+
+fn parse_roi(x: &str) -> Result<QualityRegion, &'static str> {
+    let (rect, delta) = x.split_once(':').unwrap_or((x, "+10"));
+    let rect = match rect
+        .split(',')
+        .map(|x| x.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .as_deref()
+    {
+        Ok([x, y, width, height]) if *width > 0 && *height > 0 => Rect {
+            x: *x,
+            y: *y,
+            width: *width,
+            height: *height,
+        },
+        _ => return Err("expected region as X,Y,WIDTH,HEIGHT with optional :DELTA"),
+    };
+    let delta = delta
+        .parse::<i8>()
+        .ok()
+        .filter(|delta| (-100..=100).contains(delta))
+        .ok_or("expected quality delta between -100 and +100")?;
+    Ok(QualityRegion { rect, delta })
+}
+
+fn parse_duration(x: &str) -> Result<Duration, &'static str> {
+    let (number, multiplier) = if let Some(number) = x.strip_suffix("ms") {
+        (number, 1)
+    } else if let Some(number) = x.strip_suffix('s') {
+        (number, 1000)
+    } else if let Some(number) = x.strip_suffix('m') {
+        (number, 60 * 1000)
+    } else {
+        (x, 1000)
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .map(Duration::from_millis)
+        .ok_or("expected duration with optional ms, s or m suffix")
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BackgroundColor {
+    Auto,
+    Color(RGB8),
+}
+
+fn parse_background_color(input: &str) -> Result<BackgroundColor, String> {
+    if input == "auto" {
+        Ok(BackgroundColor::Auto)
+    } else {
+        parse_color(input).map(BackgroundColor::Color)
     }
-    Ok(RGB8::new(
-        u8::from_str_radix(&input[1..=2], 16).map_err(|err| err.to_string())?,
-        u8::from_str_radix(&input[3..=4], 16).map_err(|err| err.to_string())?,
-        u8::from_str_radix(&input[5..=6], 16).map_err(|err| err.to_string())?,
-    ))
 }
 
-#[derive(PartialEq, Copy, Clone, ArgEnum)]
+// Peak resident set size of the process in bytes.
+#[cfg(unix)]
+fn peak_memory_usage() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    // Linux reports `ru_maxrss` in kilobytes but macOS in bytes.
+    let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(usage.ru_maxrss as u64 * scale)
+}
+
+#[cfg(not(unix))]
+fn peak_memory_usage() -> Option<u64> {
+    None
+}
+
+// Peak memory usage so far if `--report-memory` is used.
+fn reported_memory_usage(report_memory: bool) -> Option<u64> {
+    if report_memory {
+        peak_memory_usage()
+    } else {
+        None
+    }
+}
+
+fn format_memory_usage(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Auto,
+}
+
+impl From<Format> for OutputFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::JPEG => Self::Jpeg,
+            Format::PNG => Self::Png,
+            Format::WEBP => Self::Webp,
+        }
+    }
+}
+
+impl OutputFormat {
+    fn candidates(&self) -> Vec<Format> {
+        match self {
+            Self::Jpeg => vec![Format::JPEG],
+            Self::Png => vec![Format::PNG],
+            Self::Webp => vec![Format::WEBP],
+            Self::Auto => vec![Format::JPEG, Format::PNG, Format::WEBP],
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum Preset {
+    /// Photographs, compared with `--fast-ssim`, prefers JPEG or WebP with `--output-format auto`
+    Photo,
+    /// Screenshots with text, without chroma subsampling and dithering and with up to 128 colors
+    /// in PNG palette, prefers PNG or WebP
+    Screenshot,
+    /// Small images with few colors, without chroma subsampling and dithering and with up to 64
+    /// colors in PNG palette, prefers PNG or WebP
+    Icon,
+    /// Illustrations, without chroma subsampling and with ordered dithering
+    Artwork,
+    /// Detect from the number of colors and flat areas of the image
+    Auto,
+}
+
+impl Preset {
+    // Candidates of `--output-format auto`.
+    fn formats(&self) -> &'static [Format] {
+        match self {
+            Self::Photo => &[Format::JPEG, Format::WEBP],
+            Self::Screenshot | Self::Icon => &[Format::PNG, Format::WEBP],
+            Self::Artwork | Self::Auto => &[Format::JPEG, Format::PNG, Format::WEBP],
+        }
+    }
+
+    // Guess content of the image from the number of colors and the fraction of pixels with the
+    // same color as the pixel on the left. Photos have many colors compared to the number of
+    // pixels even if they have flat areas like dark sky, while screenshots are mostly flat.
+    fn detect(image: &Image) -> Self {
+        let colors: std::collections::HashSet<_> = image.data.iter().collect();
+        let color_ratio = colors.len() as f64 / image.data.len() as f64;
+        let flat = image
+            .data
+            .chunks(image.width)
+            .flat_map(|row| row.windows(2))
+            .filter(|pair| pair[0] == pair[1])
+            .count() as f64
+            / image.data.len() as f64;
+        if colors.len() <= 256 && image.width.max(image.height) <= 256 {
+            Self::Icon
+        } else if color_ratio > 0.05 {
+            Self::Photo
+        } else if flat > 0.5 {
+            Self::Screenshot
+        } else {
+            Self::Artwork
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum Flip {
+    /// Mirror left and right
+    H,
+    /// Mirror top and bottom
+    V,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
 enum FailStrategy {
     None, // TODO: split to Ignore and Warn?
     Exit,
     Copy,
 }
 
-#[derive(Parser)]
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Optimize an image, which is the default when no subcommand is given
+    ///
+    /// `pio optimize [OPTIONS] [INPUT]` is the same as `pio [OPTIONS] [INPUT]`.
+    Optimize,
+
+    /// Compare quality of two images
+    ///
+    /// Prints DSSIM of the compressed image compared to the original and the difference in file
+    /// size. Butteraugli score isn't printed, because pio doesn't include an implementation of it.
+    Compare {
+        /// Original image
+        #[clap(parse(from_os_str))]
+        original: PathBuf,
+
+        /// Compressed image
+        #[clap(parse(from_os_str))]
+        compressed: PathBuf,
+    },
+
+    /// Print image properties and recommended settings without compressing
+    Analyze {
+        /// Image to analyze
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+    },
+
+    /// Optimize images uploaded with HTTP POST requests
+    ///
+    /// Options given before the subcommand are used as defaults. Query parameters `quality`,
+    /// `min`, `max`, `spread` and `format` override them per request. `GET /healthz` reports
+    /// that the server is running and `GET /readyz` whether it can take more requests. On SIGTERM
+    /// the server stops accepting connections and exits after finishing requests in progress.
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080", value_name = "ADDRESS")]
+        listen: String,
+
+        /// Reject request bodies larger than this with 413 Payload Too Large
+        #[clap(parse(try_from_str = parse_size), long, default_value = "64M", value_name = "SIZE")]
+        max_body_size: u64,
+
+        /// Close connections that don't send or receive data within this time
+        #[clap(parse(try_from_str = parse_duration), long, default_value = "30s", value_name = "DURATION")]
+        connection_timeout: Duration,
+
+        /// Maximum number of requests handled at the same time. Up to the same number of requests
+        /// wait for their turn, and further requests are rejected with 503 Service Unavailable
+        #[clap(long, default_value = "4", value_name = "NUMBER")]
+        max_connections: NonZeroUsize,
+    },
+
+    /// Create quality curve for `--quality-curve` from average DSSIM of each quality over images
+    Calibrate {
+        /// Output format to calibrate
+        #[clap(arg_enum, long)]
+        format: Format,
+
+        /// Images or directories of images to compress with each quality
+        #[clap(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Average DSSIM of each quality over images using median or mean
+        #[clap(arg_enum, long, default_value_t = Average::Median)]
+        average: Average,
+
+        /// Print table as CSV for `--quality-curve` or as Rust constant for the built-in tables
+        #[clap(arg_enum, long, default_value_t = CalibrateOutput::Csv, value_name = "FORMAT")]
+        output: CalibrateOutput,
+    },
+
+    /// Print DSSIM and size of an image compressed with each quality
+    Curve {
+        /// Image to compress with each quality
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Output format to compress to
+        #[clap(arg_enum, long)]
+        format: Format,
+
+        /// Print results as CSV or JSON
+        #[clap(arg_enum, long, default_value_t = CurveOutput::Csv, value_name = "FORMAT")]
+        output: CurveOutput,
+    },
+
+    /// Measure encoding time, size and DSSIM of each output format and quality over images
+    Bench {
+        /// Images or directories of images to compress
+        #[clap(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Output formats to compare
+        #[clap(
+            arg_enum,
+            long,
+            use_delimiter = true,
+            default_value = "jpeg,png,webp",
+            value_name = "FORMATS"
+        )]
+        formats: Vec<Format>,
+
+        /// Qualities to compress with
+        #[clap(parse(try_from_str = parse_quality), long, use_delimiter = true, default_value = "70,80,90", value_name = "QUALITIES")]
+        qualities: Vec<u8>,
+    },
+
+    /// Print shell completion script
+    Completions {
+        /// Shell to print completion script for
+        #[clap(arg_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum Average {
+    Median,
+    Mean,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum CalibrateOutput {
+    Csv,
+    Rust,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+enum CurveOutput {
+    Csv,
+    Json,
+}
+
+// Search options that can be set separately for each output format in configuration file.
+#[derive(Clone, Debug, Default)]
+struct FormatDefaults {
+    quality: Option<u8>,
+    min: Option<u8>,
+    max: Option<u8>,
+    spread: Option<u8>,
+    chroma_subsampling: Option<String>,
+    quality_curve: Option<PathBuf>,
+}
+
+impl FormatDefaults {
+    // Set option from configuration file. Keys are the same as long option names.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = |err: &str| format!("invalid value for `{}`: {}", key, err);
+        match key {
+            "quality" => self.quality = Some(parse_quality(value).map_err(invalid)?),
+            "min" => self.min = Some(parse_quality(value).map_err(invalid)?),
+            "max" => self.max = Some(parse_quality(value).map_err(invalid)?),
+            "spread" => self.spread = Some(parse_quality(value).map_err(invalid)?),
+            "chroma-subsampling" => match value {
+                "444" | "422" | "420" | "440" | "411" | "auto" | "source" => {
+                    self.chroma_subsampling = Some(value.to_string())
+                }
+                _ => return Err(invalid("expected 444, 422, 420, 440, 411, auto or source")),
+            },
+            "quality-curve" => self.quality_curve = Some(PathBuf::from(value)),
+            _ => return Err(format!("option `{}` can't be set per format", key)),
+        }
+        Ok(())
+    }
+
+    fn unset(&mut self, key: &str) {
+        match key {
+            "quality" => self.quality = None,
+            "min" => self.min = None,
+            "max" => self.max = None,
+            "spread" => self.spread = None,
+            "chroma-subsampling" => self.chroma_subsampling = None,
+            "quality-curve" => self.quality_curve = None,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
 #[clap(version, about = "Perceptual Image Optimizer")]
 struct Args {
-    /// Input file to use, standard input is used when value is - or not set
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Search options overridden for specific output formats in configuration file
+    #[clap(skip)]
+    format_defaults: Vec<(Format, FormatDefaults)>,
+
+    /// Long names of options given on the command line or in configuration file, which take
+    /// precedence over `--preset`
+    #[clap(skip)]
+    explicit_options: Vec<String>,
+
+    /// Set when optimizing several images, which prints overall progress instead of the progress
+    /// of each quality search unless `--verbose` is used
+    #[clap(skip)]
+    batch: bool,
+
+    /// Print progress of the quality search of each image also in `--archive`
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Input file to use, standard input is used when value is - or not set. With `http` feature,
+    /// http and https URLs are fetched.
     #[clap(parse(from_os_str))]
     input: Option<PathBuf>,
 
@@ -79,17 +601,120 @@ struct Args {
     #[clap(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
 
-    /// Set output file format
+    /// Optimize images inside a zip or tar archive, keeping their names and formats
+    #[clap(long, conflicts_with_all = &["input-format", "output-format", "output-formats", "output-dir", "ssim-map", "cache-dir"])]
+    archive: bool,
+
+    /// Set input file format instead of detecting it from the data
+    #[clap(arg_enum, long, value_name = "FORMAT")]
+    input_format: Option<Format>,
+
+    /// Use defaults suited for the type of the image
+    #[clap(arg_enum, long)]
+    preset: Option<Preset>,
+
+    /// Set output file format, auto uses the format with the smallest output
     #[clap(arg_enum, long, value_name = "FORMAT")]
-    output_format: Option<Format>,
+    output_format: Option<OutputFormat>,
+
+    /// Write output in each of these formats to `--output-dir` (e.g. jpeg,webp)
+    #[clap(arg_enum, long, use_delimiter = true, value_name = "FORMATS", requires = "output-dir", conflicts_with_all = &["output-format", "ssim-map", "lossless-transcode"])]
+    output_formats: Vec<OutputFormat>,
 
     /// Overwrite input file in-place
     #[clap(long, requires = "input", conflicts_with = "output")]
     in_place: bool,
 
-    /// Set target quality for output
-    #[clap(parse(try_from_str = parse_quality), default_value_t = 85, long)]
-    quality: u8,
+    /// Write output to this directory with file name from `--template`
+    #[clap(long, parse(from_os_str), value_name = "DIR", requires = "input", conflicts_with_all = &["output", "in-place"])]
+    output_dir: Option<PathBuf>,
+
+    /// File name of output in `--output-dir`, placeholders are {stem}, {ext}, {format}, {width},
+    /// {height}, {quality} and {hash}
+    #[clap(long, default_value = "{stem}.{ext}", value_name = "TEMPLATE")]
+    template: String,
+
+    /// Skip input if its output in `--output-dir` already exists
+    #[clap(long, conflicts_with = "force")]
+    skip_existing: bool,
+
+    /// Overwrite existing output in `--output-dir`
+    #[clap(long)]
+    force: bool,
+
+    /// Write output next to the input with this suffix added to the file name (e.g. .min)
+    #[clap(long, requires = "input", conflicts_with_all = &["output", "in-place", "output-dir", "template", "archive"])]
+    suffix: Option<String>,
+
+    /// Don't copy permissions, ownership and modification time of the input file when
+    /// overwriting it in place
+    #[clap(long)]
+    no_preserve_attributes: bool,
+
+    /// Keep the original file with this suffix added to its name when overwriting it in place,
+    /// unless the backup exists already
+    #[clap(
+        long,
+        requires = "in-place",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = ".orig",
+        value_name = "SUFFIX"
+    )]
+    backup: Option<String>,
+
+    /// Set modification time of the output file to that of the input file
+    #[clap(long)]
+    preserve_times: bool,
+
+    /// Search for the output without writing it and report the result
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Append a CSV row with sizes, quality and status of each output to this file
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Print the result with each quality tried by the search as a line of JSON to standard
+    /// output
+    #[clap(long, conflicts_with = "archive")]
+    json: bool,
+
+    /// Reject input images with more pixels than this before decoding
+    #[clap(long, value_name = "PIXELS")]
+    max_pixels: Option<u64>,
+
+    /// Reject input images estimated to need more memory than this (e.g. 512M or 2G)
+    #[clap(parse(try_from_str = parse_size), long, value_name = "SIZE")]
+    max_memory: Option<u64>,
+
+    /// Stop searching after this time (e.g. 30s) and use the best result found so far
+    #[clap(parse(try_from_str = parse_duration), long, value_name = "DURATION")]
+    timeout: Option<Duration>,
+
+    /// Record in the output that it was optimized by pio and with which setting
+    #[clap(long)]
+    mark_optimized: bool,
+
+    /// Copy input as is if it was marked by `--mark-optimized`
+    #[clap(long)]
+    skip_optimized: bool,
+
+    /// Reuse results of previous runs with the same input and options from this directory
+    #[clap(long, parse(from_os_str), value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Use table of target DSSIM for each quality created by `pio calibrate`
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    quality_curve: Option<PathBuf>,
+
+    /// Write false-color map of differences between input and output to PNG file
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    ssim_map: Option<PathBuf>,
+
+    /// Set target quality for output, optionally separately for each format (e.g. jpeg=85,webp=78)
+    #[clap(parse(try_from_str = parse_quality_option), default_value = "85", long)]
+    quality: Quality,
 
     /// Set minimum quality for output
     #[clap(parse(try_from_str = parse_quality), long)]
@@ -103,9 +728,14 @@ struct Args {
     #[clap(parse(try_from_str = parse_quality), default_value_t=10, long)]
     spread: u8,
 
-    /// Set background color to use when output format doesn't support transparency
-    #[clap(parse(try_from_str = parse_color), default_value = "#ffffff", long, value_name = "COLOR")]
-    background_color: RGB8,
+    /// Set quality search algorithm, fixed compresses with `--quality` without comparing images
+    #[clap(arg_enum, long, default_value_t = SearchStrategy::Binary, value_name = "STRATEGY")]
+    search: SearchStrategy,
+
+    /// Set background color to use when output format doesn't support transparency (color or auto
+    /// to use the average color of the image edges)
+    #[clap(parse(try_from_str = parse_background_color), default_value = "#ffffff", long, value_name = "COLOR")]
+    background_color: BackgroundColor,
 
     /// Add background color even if output format supports transparency
     #[clap(long)]
@@ -115,452 +745,5737 @@ struct Args {
     #[clap(arg_enum, long = "optimization_failed", default_value_t=FailStrategy::None, value_name = "STRATEGY")]
     fail_strategy: FailStrategy,
 
-    /// Specify chroma subsampling
-    #[clap(long, possible_values=["444", "422", "420", "auto"], default_value="auto")]
+    /// Handle output like it was larger than the input if it saves less than this (e.g. 5% or 10K)
+    #[clap(parse(try_from_str = parse_savings), long, value_name = "SAVINGS")]
+    min_savings: Option<Savings>,
+
+    /// Quantize region X,Y,WIDTH,HEIGHT of JPEG output with quality changed by DELTA (default +10)
+    /// from the rest of the image, for example 100,100,400,300:+10. The quality search keeps the
+    /// target of the whole image, so raising quality in the region lowers it elsewhere. Can be
+    /// repeated.
+    #[clap(parse(try_from_str = parse_roi), long, value_name = "REGION", multiple_occurrences = true)]
+    roi: Vec<QualityRegion>,
+
+    /// Quantize the visually most important region of the image with higher quality like with
+    /// `--roi`
+    #[cfg(feature = "saliency")]
+    #[clap(long)]
+    saliency: bool,
+
+    /// Specify chroma subsampling, source uses the subsampling of JPEG input and auto otherwise
+    #[clap(long, possible_values=["444", "422", "420", "440", "411", "auto", "source"], default_value="source")]
     chroma_subsampling: String,
-}
 
-fn find_image(
-    image: &Image,
-    attr: &ssim::Calculator,
-    lossy_compress: &LossyCompressor,
-    target: f64,
-    min_quality: u8,
-    max_quality: u8,
-    original_size: u64,
-    chroma_subsampling: ChromaSubsampling,
-) -> Result<(f64, Vec<u8>), String> {
-    let mut min = min_quality;
-    let mut max = max_quality;
-    let mut best_buffer = Vec::new();
-    let mut best_dssim = f64::INFINITY;
-
-    // Compress image with different qualities and find which is closest to the SSIM target. Binary
-    // search is used to speed up the search. Since there are 101 possible quality values, only
-    // ceil(log2(101)) = 7 comparisons are needed at maximum.
-    loop {
-        // Overflow is not possible because `min` and `max` are in range 0-100.
-        let quality = (min + max) / 2;
-
-        let (compressed, buffer) = lossy_compress(image, quality, chroma_subsampling)?;
-
-        for x in 0..=100 / 4 {
-            if x == quality / 4 {
-                eprint!("O")
-            } else if x == 0 || x == 100 / 4 {
-                eprint!("|");
-            } else if x == min / 4 {
-                eprint!("[");
-            } else if x == max / 4 {
-                eprint!("]");
-            } else if x > min / 4 && x < max / 4 {
-                eprint!("-");
-            } else {
-                eprint!(" ");
-            }
-        }
+    /// Set which metadata to copy from input to output
+    #[clap(arg_enum, long, default_value_t = MetadataPolicy::None, value_name = "POLICY")]
+    metadata: MetadataPolicy,
 
-        let dssim = attr
-            .compare(&compressed)
-            .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+    /// Set whether to rotate image data according to Exif orientation or keep the orientation tag
+    #[clap(arg_enum, long, default_value_t = OrientationPolicy::Bake, value_name = "POLICY")]
+    orientation: OrientationPolicy,
 
-        eprintln!(
-            " {:>3} quality  {:.6} SSIM  {:>3} % of original",
-            quality,
-            dssim,
-            100 * buffer.len() as u64 / original_size,
-        );
+    /// Don't rotate image according to Exif orientation, same as `--orientation ignore`
+    #[clap(long, conflicts_with = "orientation")]
+    no_auto_orient: bool,
 
-        // Last steps of the binary search are pretty close to each other, so the final step may
-        // not actually have SSIM closest to the target. Instead of using the last step, keep track
-        // of the best attempt so far.
-        if (dssim - target).abs() < (best_dssim - target).abs() {
-            best_buffer = buffer;
-            best_dssim = dssim;
-        }
+    /// Rotate image clockwise by degrees
+    #[clap(long, possible_values = &["90", "180", "270"], value_name = "DEGREES")]
+    rotate: Option<u16>,
 
-        // Binary search step.
-        if dssim > target {
-            min = quality + 1;
-        } else {
-            // Prevent underflow because comparison is unreliable at low qualities.
-            if quality == 0 {
-                break;
-            }
-            max = quality - 1;
-        }
+    /// Flip image horizontally or vertically after rotating
+    #[clap(arg_enum, long, value_name = "DIRECTION")]
+    flip: Option<Flip>,
 
-        if min > max {
-            break;
+    /// Convert image to grayscale
+    #[clap(long)]
+    grayscale: bool,
+
+    /// Copy text chunks (e.g. title, author and software) from PNG input to PNG output
+    #[clap(long)]
+    keep_text: bool,
+
+    /// Keep color profile of the input instead of converting to sRGB
+    #[clap(long, conflicts_with = "target-colorspace")]
+    keep_color_profile: bool,
+
+    /// Set color space of output
+    #[clap(arg_enum, long, default_value_t = TargetColorSpace::Srgb, value_name = "COLORSPACE")]
+    target_colorspace: TargetColorSpace,
+
+    /// Set rendering intent to use when converting from color profile of the input
+    #[clap(arg_enum, long, default_value_t = RenderingIntent::Perceptual, value_name = "INTENT")]
+    rendering_intent: RenderingIntent,
+
+    /// Use black point compensation when converting from color profile of the input
+    #[clap(long)]
+    black_point_compensation: bool,
+
+    /// Set tone mapping operator for HDR input [default: hable if output format doesn't support HDR]
+    #[clap(arg_enum, long, value_name = "OPERATOR")]
+    tone_mapping: Option<ToneMapping>,
+
+    /// Compare downscaled images during quality search for large images, which is faster but less
+    /// accurate
+    #[clap(long)]
+    fast_ssim: bool,
+
+    /// Use progressive encoding for JPEG output (default)
+    #[clap(long, conflicts_with = "baseline")]
+    progressive: bool,
+
+    /// Use baseline encoding for JPEG output
+    #[clap(long)]
+    baseline: bool,
+
+    /// Use progressive JPEG scan script from file in the format of `cjpeg -scans`
+    #[clap(long, parse(try_from_str = parse_scans), conflicts_with = "baseline")]
+    scans: Option<Arc<[jpeg::Scan]>>,
+
+    /// Disable trellis quantization for JPEG output
+    #[clap(long)]
+    no_trellis: bool,
+
+    /// Disable overshoot deringing for JPEG output
+    #[clap(long)]
+    no_deringing: bool,
+
+    /// Set smoothing factor for JPEG output to reduce noise, 0 disables smoothing
+    #[clap(parse(try_from_str = parse_quality), default_value_t = 0, long)]
+    smoothing: u8,
+
+    /// Use standard Huffman tables for baseline JPEG output instead of computing optimal tables
+    #[clap(long)]
+    no_optimize_huffman: bool,
+
+    /// Set chroma quality of JPEG output relative to the luma quality found by the search (e.g. -10)
+    #[clap(
+        long,
+        default_value_t = 0,
+        allow_hyphen_values = true,
+        value_name = "OFFSET"
+    )]
+    chroma_quality_offset: i8,
+
+    /// Set WebP compression method between 0 (fastest) and 6 (smallest output)
+    #[clap(parse(try_from_str = parse_webp_method), default_value_t = 6, long, value_name = "METHOD")]
+    webp_method: u8,
+
+    /// Set quality of alpha channel for lossy WebP output
+    #[clap(parse(try_from_str = parse_quality), default_value_t = 100, long)]
+    webp_alpha_quality: u8,
+
+    /// Use faster but less accurate RGB to YUV conversion for lossy WebP output
+    #[clap(long)]
+    no_sharp_yuv: bool,
+
+    /// Set near-lossless WebP level between 0 (smallest output) and 100 (disabled)
+    #[clap(parse(try_from_str = parse_quality), default_value_t = 60, long, value_name = "LEVEL")]
+    webp_near_lossless: u8,
+
+    /// Set maximum number of colors in PNG palette
+    #[clap(parse(try_from_str = parse_png_colors), default_value_t = 256, long, value_name = "COLORS")]
+    png_colors: u32,
+
+    /// Set dithering algorithm for PNG palette
+    #[clap(arg_enum, long, default_value_t = png::Dithering::FloydSteinberg, value_name = "ALGORITHM")]
+    dither: png::Dithering,
+
+    /// Set strength of PNG dithering between 0 and 100
+    #[clap(parse(try_from_str = parse_quality), default_value_t = 100, long, value_name = "STRENGTH")]
+    dither_strength: u8,
+
+    /// Set effort of PNG palette quantization between 1 (fastest) and 10 (best quality)
+    #[clap(parse(try_from_str = parse_png_effort), default_value_t = 7, long, value_name = "EFFORT")]
+    png_effort: u8,
+
+    /// Reduce each channel by this many bits between 0 and 4 before PNG palette quantization, which
+    /// makes flat graphics compress better
+    #[clap(parse(try_from_str = parse_posterize), default_value_t = 0, long, value_name = "BITS")]
+    posterize: u8,
+
+    /// Remove film grain and other noise before compression if there is a lot of it, so that the
+    /// quality target isn't spent on preserving noise
+    #[clap(long)]
+    remove_grain: bool,
+
+    /// Smooth noise with the strength before compression, comparing output to the smoothed image
+    #[clap(arg_enum, long, value_name = "LEVEL", conflicts_with = "remove-grain")]
+    denoise: Option<denoise::Level>,
+
+    /// Interlace PNG output for progressive rendering, which usually increases file size
+    #[clap(long)]
+    interlace: bool,
+
+    /// Set DEFLATE compression level for PNG output
+    #[clap(arg_enum, long, default_value_t = png::CompressionLevel::Default, value_name = "LEVEL")]
+    png_compression: png::CompressionLevel,
+
+    /// Compress PNG or WebP output losslessly instead of searching for quality target
+    #[clap(long)]
+    lossless: bool,
+
+    /// Optimize JPEG input losslessly without decoding image data, requires JPEG output
+    #[clap(long)]
+    lossless_transcode: bool,
+
+    /// Report peak memory usage after processing, also in `--report` and `--json`
+    #[clap(long)]
+    report_memory: bool,
+}
+
+#[derive(Clone)]
+struct CompressOptions {
+    jpeg: jpeg::CompressOptions,
+    png: png::CompressOptions,
+    webp: webp::CompressOptions,
+}
+
+impl CompressOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            jpeg: jpeg::CompressOptions {
+                progressive: !args.baseline,
+                scans: args.scans.clone(),
+                trellis: !args.no_trellis,
+                deringing: !args.no_deringing,
+                smoothing: args.smoothing,
+                optimize_huffman: !args.no_optimize_huffman,
+                chroma_quality_offset: args.chroma_quality_offset,
+                regions: args.roi.as_slice().into(),
+            },
+            png: png::CompressOptions {
+                max_colors: args.png_colors,
+                dithering: args.dither,
+                dithering_level: args.dither_strength as f32 / 100.0,
+                interlace: args.interlace,
+                effort: args.png_effort,
+                posterize: args.posterize,
+            },
+            webp: webp::CompressOptions {
+                method: args.webp_method,
+                alpha_quality: args.webp_alpha_quality,
+                sharp_yuv: !args.no_sharp_yuv,
+            },
         }
     }
-
-    Ok((best_dssim, best_buffer))
 }
 
-fn compress_image(
-    image: Image,
-    lossy_compress: LossyCompressor,
-    lossless_compress: Option<LosslessCompressor>,
-    target: f64,
+// Grain above which it's removed with `--remove-grain`, and strength of denoising relative to the
+// grain. Grain is estimated from luma, which has less noise than separate color channels.
+const GRAIN_THRESHOLD: f64 = 1.5;
+const GRAIN_STRENGTH: f64 = 3.0;
+
+// Compress image to a single output format.
+#[allow(clippy::too_many_arguments)]
+fn compress_format(
+    image: &Image,
+    attr: &ssim::Calculator,
+    format: Format,
+    input_format: Format,
+    args: &Args,
+    options: &CompressOptions,
+    source_sampling: Option<ChromaSubsampling>,
+    curve: &[f64; 101],
     min_quality: u8,
     max_quality: u8,
     original_size: u64,
-    chroma_subsampling: ChromaSubsamplingOption,
-) -> Result<Vec<u8>, String> {
-    let attr = ssim::Calculator::new(&image)
-        .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
-
-    let mut best_buffer = Vec::new();
-    let mut best_dssim = f64::INFINITY;
-
-    let samplings = match chroma_subsampling {
-        ChromaSubsamplingOption::Auto => vec![
-            ChromaSubsampling::_444,
-            ChromaSubsampling::_422,
-            ChromaSubsampling::_420,
-        ],
-        ChromaSubsamplingOption::Manual(sampling) => vec![sampling],
-        ChromaSubsamplingOption::None => vec![ChromaSubsampling::_444],
-    };
-
-    for sampling in samplings {
-        eprintln!("chroma subsampling: {:?}", sampling);
-        let (dssim, buffer) = find_image(
-            &image,
-            &attr,
-            &lossy_compress,
-            target,
+    cancel: &CancellationToken,
+) -> Result<Compressed, String> {
+    // HDR data is kept in 16-bit truecolor. Palette and 8-bit output would cause banding, and SSIM
+    // of PQ or HLG encoded values doesn't match the perceived difference.
+    let hdr = format == Format::PNG && image.metadata.cicp.is_some();
+    if hdr && !args.lossless {
+        eprintln!("keeping HDR data, compressing losslessly");
+    }
+    let mut compressed = if args.lossless || hdr {
+        let (_, buffer) = match format {
+            Format::PNG => png::compress_lossless(image, &options.png),
+            Format::WEBP => webp::compress(image, 100, webp::Mode::Lossless, &options.webp),
+            Format::JPEG => unreachable!(),
+        }?;
+        let mut compressed = Compressed {
+            setting: Setting::Fixed("lossless".to_string()),
+            dssim: 0.0,
+            buffer,
+            chroma_subsampling: None,
+            iterations: 1,
+            trials: Vec::new(),
+        };
+        compressed.trials.push(compressed.trial());
+        compressed
+    } else {
+        let chroma_subsampling = if format.supports_chroma_subsampling() {
+            match args.chroma_subsampling.as_str() {
+                "420" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_420),
+                "422" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_422),
+                "444" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_444),
+                "440" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_440),
+                "411" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_411),
+                "auto" => ChromaSubsamplingOption::Auto,
+                "source" => source_sampling.map_or(
+                    ChromaSubsamplingOption::Auto,
+                    ChromaSubsamplingOption::Manual,
+                ),
+                _ => unreachable!(),
+            }
+        } else {
+            ChromaSubsamplingOption::None
+        };
+
+        let (lossy_compress, fixed_compress): (LossyCompressor, Vec<(&str, FixedCompressor)>) =
+            match format {
+                Format::JPEG => {
+                    let options = options.jpeg.clone();
+                    (
+                        Box::new(move |img, q, cs| jpeg::compress(img, q, cs, &options)),
+                        Vec::new(),
+                    )
+                }
+                Format::PNG => {
+                    let options = options.png;
+                    // Palette can't represent gradients and photos well, so try also truecolor
+                    // without quantization.
+                    (
+                        Box::new(move |img, q, _cs| png::compress(img, q, &options)),
+                        vec![(
+                            "truecolor",
+                            Box::new(move |img| png::compress_lossless(img, &options)),
+                        )],
+                    )
+                }
+                Format::WEBP => {
+                    let options = options.webp;
+                    let mut fixed_compress: Vec<(&str, FixedCompressor)> = Vec::new();
+                    // Level 100 disables near-lossless preprocessing.
+                    if args.webp_near_lossless < 100 {
+                        let level = args.webp_near_lossless;
+                        fixed_compress.push((
+                            "near-lossless",
+                            Box::new(move |img| {
+                                webp::compress(img, 100, webp::Mode::NearLossless(level), &options)
+                            }),
+                        ));
+                    }
+                    fixed_compress.push((
+                        "lossless",
+                        Box::new(move |img| {
+                            webp::compress(img, 100, webp::Mode::Lossless, &options)
+                        }),
+                    ));
+                    (
+                        Box::new(move |img, q, _cs| {
+                            webp::compress(img, q, webp::Mode::Lossy, &options)
+                        }),
+                        fixed_compress,
+                    )
+                }
+            };
+
+        let search = QualitySearch {
+            target: curve[args.quality.default as usize],
             min_quality,
-            max_quality,
+            // Fixed search compresses with the maximum quality.
+            max_quality: if args.search == SearchStrategy::Fixed {
+                args.quality.default
+            } else {
+                max_quality
+            },
+            chroma_subsampling,
+            metric: attr,
+            lossy: lossy_compress,
+            fixed: fixed_compress,
+            pool: None,
             original_size,
-            sampling,
-        )?;
-        if (dssim - target).abs() < (best_dssim - target).abs() {
-            best_buffer = buffer;
-            best_dssim = dssim;
+            // Input in the same format may have been optimized already.
+            check_max_quality: format == input_format,
+            curve: Some(curve),
+            strategy: args.search,
+        };
+        let show_search = !args.batch || args.verbose;
+        search.run(image, cancel, &mut |line| {
+            if show_search {
+                eprintln!("{}", line)
+            }
+        })?
+    };
+
+    if format == Format::PNG
+        && args.png_compression == png::CompressionLevel::Max
+        && !cancel.is_cancelled()
+    {
+        eprintln!("compressing with zopfli...");
+        compressed.buffer = png::recompress_zopfli(&compressed.buffer)?;
+    }
+
+    Ok(compressed)
+}
+
+// Outcome of processing the input, reported as exit code.
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum Outcome {
+    /// Output was written successfully
+    Success,
+    /// Input was copied to output because output would have been larger or saved too little
+    Copied,
+    /// Input was copied to output because it was already optimized
+    Skipped,
+}
+
+impl Outcome {
+    // Status in `--report`.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Success => "optimized",
+            Self::Copied => "copied",
+            Self::Skipped => "skipped",
+        }
+    }
+
+    // Exit code 1 is used for errors and 2 by clap for invalid arguments.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::Copied => 3,
+            Self::Skipped => 4,
+        }
+    }
+}
+
+fn write_output(
+    output_writer: Output,
+    output_buffer: &[u8],
+    input_buffer: &[u8],
+    fail_strategy: FailStrategy,
+    min_savings: Option<Savings>,
+) -> Result<Outcome, String> {
+    let (is, would_be) = if output_buffer.len() > input_buffer.len() {
+        ("is larger than input", "would be larger than input")
+    } else if min_savings
+        .is_some_and(|savings| !savings.is_reached(input_buffer.len(), output_buffer.len()))
+    {
+        (
+            "saves less than `--min-savings`",
+            "would save less than `--min-savings`",
+        )
+    } else {
+        output_writer
+            .write(output_buffer)
+            .map_err(|err| format!("failed to write output: {}", err))?;
+        return Ok(Outcome::Success);
+    };
+    match fail_strategy {
+        FailStrategy::None => {
+            eprintln!("warning: Output {} but still writing output normally. This behavior can be changed with `--optimization-failed` option.", is);
+            output_writer
+                .write(output_buffer)
+                .map_err(|err| format!("failed to write output: {}", err))?;
+            Ok(Outcome::Success)
+        }
+        FailStrategy::Exit => Err(format!("error: Output {}, exiting now...", would_be)),
+        FailStrategy::Copy => {
+            eprintln!("warning: Output {}, copying input to output...", would_be);
+            output_writer
+                .write(input_buffer)
+                .map_err(|err| format!("failed to write output: {}", err))?;
+            Ok(Outcome::Copied)
+        }
+    }
+}
+
+// Marker is a comment in JPEG, `tEXt` chunk with keyword `Software` in PNG and custom chunk in
+// WebP. For example "pio 0.4.0, quality 85, options 0123456789abcdef", where options is a digest of
+// the options that affect the output.
+fn marker(compressed: &Compressed, args: &Args) -> String {
+    format!(
+        "pio {}, {}, options {}",
+        env!("CARGO_PKG_VERSION"),
+        compressed.setting,
+        options_digest(args)
+    )
+}
+
+// Setting and options digest recorded in the marker. Markers of older versions don't have the
+// digest.
+fn parse_marker(marker: &str) -> (Option<Setting>, Option<&str>) {
+    let mut parts = marker.split(", ").skip(1);
+    let setting = parts.next().and_then(|setting| setting.parse().ok());
+    let options = parts
+        .next()
+        .and_then(|options| options.strip_prefix("options "));
+    (setting, options)
+}
+
+// Version and options serialized to a string. Options which don't affect the output are cleared so
+// that they don't change the string.
+fn options_string(args: &Args) -> String {
+    // Quality curves are included by their values instead of paths, so that editing the file
+    // changes the options.
+    let curves: Vec<_> = [Format::JPEG, Format::PNG, Format::WEBP]
+        .iter()
+        .filter_map(|format| {
+            let args = args.for_format(*format);
+            args.quality_curve.as_ref()?;
+            Some(quality_curve(&args, *format))
+        })
+        .collect();
+    format!(
+        "{} {:?} {:?}",
+        env!("CARGO_PKG_VERSION"),
+        Args {
+            quality_curve: None,
+            format_defaults: args
+                .format_defaults
+                .iter()
+                .map(|(format, defaults)| {
+                    (
+                        *format,
+                        FormatDefaults {
+                            quality_curve: None,
+                            ..defaults.clone()
+                        },
+                    )
+                })
+                .collect(),
+            input: None,
+            output: None,
+            output_dir: None,
+            template: String::new(),
+            suffix: None,
+            skip_existing: false,
+            force: false,
+            in_place: false,
+            dry_run: false,
+            report: None,
+            json: false,
+            ssim_map: None,
+            skip_optimized: false,
+            cache_dir: None,
+            report_memory: false,
+            batch: false,
+            verbose: false,
+            preserve_times: false,
+            backup: None,
+            timeout: None,
+            explicit_options: args
+                .explicit_options
+                .iter()
+                .filter(|option| *option != "timeout")
+                .cloned()
+                .collect(),
+            ..args.clone()
+        },
+        curves
+    )
+}
+
+// Short digest of the options for the marker.
+fn options_digest(args: &Args) -> String {
+    Cache::key(&[], &options_string(args))[..16].to_string()
+}
+
+fn add_marker(format: Format, buffer: &[u8], marker: &str) -> Result<Vec<u8>, String> {
+    match format {
+        Format::JPEG => Ok(jpeg::add_comment(buffer, marker)),
+        Format::PNG => png::add_text(buffer, "Software", marker),
+        Format::WEBP => webp::add_chunk(buffer, b"PIO ", marker.as_bytes())
+            .ok_or_else(|| "Failed to parse encoded image data".to_string()),
+    }
+}
+
+fn read_marker(format: Format, buffer: &[u8]) -> Option<String> {
+    let texts = match format {
+        Format::JPEG => jpeg::read_comments(buffer),
+        Format::PNG => png::read_texts(buffer, "Software"),
+        Format::WEBP => webp::read_chunks(buffer, b"PIO ")
+            .into_iter()
+            .map(|data| String::from_utf8_lossy(data).into_owned())
+            .collect(),
+    };
+    texts.into_iter().find(|text| text.starts_with("pio "))
+}
+
+fn decode(format: Format, buffer: &[u8], options: &ReadOptions) -> ReadResult {
+    match format {
+        Format::JPEG => jpeg::read(buffer, options),
+        Format::PNG => png::read(buffer, options),
+        Format::WEBP => webp::read(buffer, options),
+    }
+}
+
+fn dimensions(format: Format, buffer: &[u8]) -> Option<(usize, usize)> {
+    match format {
+        Format::JPEG => jpeg::dimensions(buffer),
+        Format::PNG => png::dimensions(buffer),
+        Format::WEBP => webp::dimensions(buffer),
+    }
+}
+
+// Replace placeholders in `--template` with values of the output.
+fn render_template(
+    template: &str,
+    input: &Path,
+    format: Format,
+    setting: &Setting,
+    buffer: &[u8],
+) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unterminated placeholder in template".to_string())?
+            + start;
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder {
+            "stem" => input
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            "ext" => format.extension().to_string(),
+            "format" => format.to_string().to_lowercase(),
+            "width" | "height" => {
+                let (width, height) = dimensions(format, buffer)
+                    .ok_or_else(|| "failed to read output dimensions".to_string())?;
+                if placeholder == "width" {
+                    width.to_string()
+                } else {
+                    height.to_string()
+                }
+            }
+            "quality" => match setting {
+                Setting::Quality(quality) => quality.to_string(),
+                Setting::Fixed(name) => name.replace(' ', "-"),
+            },
+            // Short prefix of SHA-256 is enough for cache busting.
+            "hash" => Sha256::digest(buffer)[..4]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            _ => {
+                return Err(format!(
+                    "unknown placeholder `{{{}}}` in template",
+                    placeholder
+                ))
+            }
+        };
+        output.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+// Path of output in `--output-dir` if it can be known before compression, which is when the output
+// format is fixed and the template doesn't depend on the result.
+fn known_output_path(args: &Args, format: OutputFormat) -> Option<PathBuf> {
+    let dir = args.output_dir.as_ref()?;
+    let format = match format.candidates()[..] {
+        [format] => format,
+        _ => return None,
+    };
+    if ["{width}", "{height}", "{quality}", "{hash}"]
+        .iter()
+        .any(|placeholder| args.template.contains(placeholder))
+    {
+        return None;
+    }
+    let setting = Setting::Fixed(String::new());
+    render_template(&args.template, args.input.as_ref()?, format, &setting, &[])
+        .ok()
+        .map(|name| dir.join(name))
+}
+
+// Size of existing output to skip with `--skip-existing`.
+fn existing_output(args: &Args, path: &Path) -> Option<usize> {
+    if !args.skip_existing {
+        return None;
+    }
+    let size = std::fs::metadata(path).ok()?.len() as usize;
+    eprintln!("output {} already exists, skipping", path.display());
+    Some(size)
+}
+
+// Returns `None` if the output already exists and is skipped.
+fn open_output_in_dir(
+    args: &Args,
+    dir: &Path,
+    format: Format,
+    setting: &Setting,
+    buffer: &[u8],
+) -> Result<Option<Output>, String> {
+    let input = args.input.as_ref().unwrap(); // validated by clap
+    let path = dir.join(render_template(
+        &args.template,
+        input,
+        format,
+        setting,
+        buffer,
+    )?);
+    // Template may contain subdirectories.
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("failed to create output directory: {}", err))?;
+    }
+    if !args.force && path.exists() {
+        if existing_output(args, &path).is_some() {
+            return Ok(None);
+        }
+        return Err(format!(
+            "output {} already exists, use `--force` to overwrite it or `--skip-existing` to skip it",
+            path.display()
+        ));
+    }
+    eprintln!("writing {}", path.display());
+    open_output_file(args, &path).map(Some)
+}
+
+fn open_output_file(args: &Args, path: &Path) -> Result<Output, String> {
+    let mut output =
+        Output::write_file(path).map_err(|err| format!("failed to open output file: {}", err))?;
+    // Standard input and URLs have no modification time.
+    if let Some(input) = args.input.as_ref().filter(|input| !is_url(input)) {
+        if args.preserve_times {
+            let modified = std::fs::metadata(input)
+                .and_then(|metadata| metadata.modified())
+                .map_err(|err| format!("failed to read modification time of input: {}", err))?;
+            output.set_modified(modified);
+        }
+    }
+    Ok(output)
+}
+
+fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+#[cfg(feature = "http")]
+fn open_url(url: &Path) -> Result<Box<dyn std::io::Read>, String> {
+    let url = url.to_str().unwrap(); // checked by `is_url`
+    let response = ureq::get(url)
+        .call()
+        .map_err(|err| format!("failed to fetch {}: {}", url, err))?;
+    Ok(Box::new(response.into_reader()))
+}
+
+#[cfg(not(feature = "http"))]
+fn open_url(_url: &Path) -> Result<Box<dyn std::io::Read>, String> {
+    Err("reading URLs requires pio to be built with `http` feature".to_string())
+}
+
+fn open_output(args: &Args) -> Result<Output, String> {
+    if args.in_place {
+        let path = args.input.as_ref().unwrap(); // validated by clap
+        Output::overwrite_file(path, !args.no_preserve_attributes, args.backup.as_deref())
+            .map_err(|err| format!("unable to overwrite file: {}", err))
+    } else {
+        match &args.output {
+            Some(path) => open_output_file(args, path),
+            None => Ok(Output::stdout()),
+        }
+    }
+}
+
+// Write output or only report the result in dry run.
+fn write_result(
+    output_writer: Option<Output>,
+    format: Format,
+    compressed: &Compressed,
+    input_buffer: &[u8],
+    fail_strategy: FailStrategy,
+    min_savings: Option<Savings>,
+) -> Result<Outcome, String> {
+    let original_size = input_buffer.len();
+    match output_writer {
+        Some(output_writer) => write_output(
+            output_writer,
+            &compressed.buffer,
+            input_buffer,
+            fail_strategy,
+            min_savings,
+        ),
+        None => {
+            // SSIM is unknown if the search didn't compare images.
+            let ssim = if compressed.dssim.is_nan() {
+                "SSIM not compared".to_string()
+            } else {
+                format!("{:.6} SSIM", compressed.dssim)
+            };
+            println!(
+                "{} {}, {}, {} bytes, {} % of original, saves {} bytes",
+                format,
+                compressed.setting,
+                ssim,
+                compressed.buffer.len(),
+                100 * compressed.buffer.len() / original_size,
+                original_size as i64 - compressed.buffer.len() as i64
+            );
+            Ok(Outcome::Success)
+        }
+    }
+}
+
+// Cache entry contains the setting and SSIM on separate lines followed by the output.
+fn encode_cache_entry(compressed: &Compressed) -> Vec<u8> {
+    let mut entry = format!("{}\n{}\n", compressed.setting, compressed.dssim).into_bytes();
+    entry.extend_from_slice(&compressed.buffer);
+    entry
+}
+
+fn decode_cache_entry(entry: &[u8]) -> Option<(Format, Compressed)> {
+    let mut parts = entry.splitn(3, |b| *b == b'\n');
+    let setting = std::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+    let dssim = std::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+    let buffer = parts.next()?.to_vec();
+    let format = Format::from_magic(&buffer)?;
+    // Chroma subsampling isn't stored in the entry but can be read from the JPEG frame header.
+    let chroma_subsampling = match format {
+        Format::JPEG => jpeg::chroma_subsampling(&buffer),
+        _ => None,
+    };
+    Some((
+        format,
+        Compressed {
+            setting,
+            dssim,
+            buffer,
+            chroma_subsampling,
+            iterations: 0,
+            trials: Vec::new(),
+        },
+    ))
+}
+
+// Compress input to the best format and setting for each requested output format. Each format is
+// compressed only once even if it's a candidate of multiple requests. Requests may be left without
+// result on timeout, except for the first one.
+fn optimize(
+    args: &Args,
+    cancel: &CancellationToken,
+    input_format: Format,
+    input_buffer: &[u8],
+    requests: &[OutputFormat],
+) -> Result<Vec<(Format, Compressed)>, String> {
+    let mut candidates: Vec<Vec<Format>> = requests.iter().map(OutputFormat::candidates).collect();
+    let original_size = input_buffer.len();
+
+    if args.lossless {
+        if requests.contains(&OutputFormat::Jpeg) {
+            return Err("JPEG output doesn't support lossless compression, use `--lossless-transcode` to optimize JPEG input without quality loss".to_string());
+        }
+        for formats in &mut candidates {
+            formats.retain(|format| *format != Format::JPEG);
+        }
+    }
+
+    let mut output_formats: Vec<Format> = Vec::new();
+    for format in candidates.iter().flatten() {
+        if !output_formats.contains(format) {
+            output_formats.push(*format);
+        }
+    }
+
+    if args.interlace {
+        if output_formats.contains(&Format::PNG) {
+            eprintln!("warning: Interlacing usually increases PNG file size.");
+        } else {
+            eprintln!("warning: `--interlace` only applies to PNG output.");
+        }
+    }
+
+    if args.lossless_transcode {
+        if input_format != Format::JPEG || !output_formats.contains(&Format::JPEG) {
+            return Err("`--lossless-transcode` requires JPEG input and output".to_string());
+        }
+        let options = CompressOptions::from_args(args);
+        let buffer = jpeg::transcode(input_buffer, args.metadata, &options.jpeg)
+            .map_err(|err| format!("failed to transcode image: {}", err))?;
+        if args.ssim_map.is_some() {
+            eprintln!("warning: `--ssim-map` is not supported with `--lossless-transcode`.");
+        }
+        if args.rotate.is_some() || args.flip.is_some() {
+            eprintln!(
+                "warning: `--rotate` and `--flip` are not supported with `--lossless-transcode`."
+            );
+        }
+        let mut compressed = Compressed {
+            setting: Setting::Fixed("lossless transcode".to_string()),
+            dssim: 0.0,
+            buffer,
+            chroma_subsampling: None,
+            iterations: 1,
+            trials: Vec::new(),
+        };
+        compressed.trials.push(compressed.trial());
+        return Ok(vec![(Format::JPEG, compressed)]);
+    }
+
+    let read_options = ReadOptions {
+        keep_color_profile: args.keep_color_profile,
+        tone_mapping: args
+            .tone_mapping
+            .or(if output_formats.iter().all(Format::supports_hdr) {
+                None
+            } else {
+                Some(ToneMapping::Hable)
+            }),
+        color_space: args.target_colorspace,
+        rendering_intent: args.rendering_intent,
+        black_point_compensation: args.black_point_compensation,
+        orientation: if args.no_auto_orient {
+            OrientationPolicy::Ignore
+        } else {
+            args.orientation
+        },
+        max_pixels: [
+            args.max_pixels,
+            args.max_memory.map(|bytes| bytes / MEMORY_PER_PIXEL),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied(),
+    };
+    let mut input_image = decode(input_format, input_buffer, &read_options)
+        .map_err(|err| format!("failed to read input: {}", err))?;
+    input_image.metadata = input_image.metadata.apply_policy(args.metadata);
+    if args.keep_text {
+        // Marker of an earlier optimization would describe the input, not the output.
+        input_image
+            .metadata
+            .texts
+            .retain(|text| !(text.keyword == "Software" && text.text.starts_with("pio ")));
+    } else {
+        input_image.metadata.texts.clear();
+    }
+
+    // Manual transforms use the same code as Exif orientation.
+    if let Some(degrees) = args.rotate {
+        let orientation = match degrees {
+            90 => 6,
+            180 => 3,
+            270 => 8,
+            _ => unreachable!(), // validated by clap
+        };
+        input_image = orient_image(input_image, orientation);
+    }
+    if let Some(flip) = args.flip {
+        let orientation = match flip {
+            Flip::H => 2,
+            Flip::V => 4,
+        };
+        input_image = orient_image(input_image, orientation);
+    }
+
+    // Preset is applied after decoding, so that it can be detected from the image.
+    let preset_args;
+    let args = match args.preset {
+        Some(preset) => {
+            let preset = if preset == Preset::Auto {
+                let preset = Preset::detect(&input_image);
+                eprintln!("detected preset: {:?}", preset);
+                preset
+            } else {
+                preset
+            };
+            preset_args = args.with_preset(preset);
+            for (request, formats) in requests.iter().zip(&mut candidates) {
+                if *request == OutputFormat::Auto {
+                    formats.retain(|format| preset.formats().contains(format));
+                }
+            }
+            output_formats.retain(|format| candidates.iter().flatten().any(|f| f == format));
+            &preset_args
+        }
+        None => args,
+    };
+    let mut options = CompressOptions::from_args(args);
+
+    // Noise is removed before anything else, so that images are also compared without noise.
+    if args.remove_grain {
+        let grain = denoise::estimate_grain(&input_image);
+        if grain > GRAIN_THRESHOLD {
+            eprintln!("removing film grain (strength {:.1})", grain);
+            input_image = denoise::denoise(&input_image, (GRAIN_STRENGTH * grain) as f32);
+        } else {
+            eprintln!("no film grain found");
+        }
+    }
+    if let Some(level) = args.denoise {
+        input_image = denoise::denoise(&input_image, level.strength());
+    }
+
+    // Keep transparency instead of trying formats which don't support it.
+    if !args.no_transparency && input_image.data.iter().any(|c| c.a < 255) {
+        for (request, formats) in requests.iter().zip(&mut candidates) {
+            if *request == OutputFormat::Auto {
+                formats.retain(Format::supports_transparency);
+            }
+        }
+        output_formats.retain(|format| candidates.iter().flatten().any(|f| f == format));
+    }
+
+    // Formats without transparency are compressed from image blended with the background color.
+    // The image is copied only if formats with and without transparency are both needed.
+    let blend = |format: &Format| args.no_transparency || !format.supports_transparency();
+    let background_color = match args.background_color {
+        BackgroundColor::Auto => input_image
+            .edge_color()
+            .unwrap_or_else(|| RGB8::new(255, 255, 255)),
+        BackgroundColor::Color(color) => color,
+    };
+    let (width, height) = (input_image.width, input_image.height);
+    if args
+        .roi
+        .iter()
+        .any(|roi| roi.rect.clip(width, height).is_none())
+    {
+        eprintln!("warning: ignoring regions outside the image");
+    }
+    #[allow(unused_mut)]
+    let mut rois = args.roi.clone();
+    #[cfg(feature = "saliency")]
+    if args.saliency {
+        match pio::saliency::salient_region(&input_image) {
+            Some(rect) => {
+                eprintln!(
+                    "salient region: {},{},{},{}",
+                    rect.x, rect.y, rect.width, rect.height
+                );
+                rois.push(QualityRegion { rect, delta: 10 });
+            }
+            None => eprintln!("no salient region found"),
+        }
+    }
+    if !rois.is_empty() && output_formats.iter().any(|format| *format != Format::JPEG) {
+        eprintln!("warning: regions are used only for JPEG output");
+    }
+    options.jpeg.regions = rois.into();
+    let ssim_scale = if args.fast_ssim {
+        fast_ssim_scale(width, height)
+    } else {
+        1
+    };
+    if ssim_scale > 1 {
+        eprintln!("comparing images downscaled by {}", ssim_scale);
+    }
+    let mut source = Some(input_image);
+    let mut images = Vec::new();
+    for blended in [true, false] {
+        if !output_formats.iter().any(|format| blend(format) == blended) {
+            continue;
+        }
+        let mut image = if blended && !output_formats.iter().all(blend) {
+            source.clone().unwrap()
+        } else {
+            source.take().unwrap()
+        };
+        if blended {
+            image.alpha_blend(background_color);
+        }
+        if args.grayscale {
+            image.grayscale();
+        }
+        let attr = ssim::Calculator::downscaled(&image, ssim_scale)
+            .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+        images.push((blended, image, attr));
+    }
+
+    // Keep chroma subsampling of JPEG input instead of searching it.
+    let source_sampling = match input_format {
+        Format::JPEG => jpeg::chroma_subsampling(input_buffer),
+        _ => None,
+    };
+
+    let mut results: Vec<(Format, Compressed)> = Vec::new();
+    for format in &output_formats {
+        if !results.is_empty() && cancel.is_cancelled() {
+            eprintln!("timed out, skipping remaining formats");
+            break;
+        }
+        if output_formats.len() > 1 {
+            eprintln!("format: {}", format);
+        }
+        let (_, input_image, attr) = images
+            .iter()
+            .find(|(blended, _, _)| *blended == blend(format))
+            .unwrap();
+        let args = args.for_format(*format);
+        let (min, max) = quality_range(&args)?;
+        let curve = quality_curve(&args, *format)?;
+        let compressed = compress_format(
+            input_image,
+            attr,
+            *format,
+            input_format,
+            &args,
+            &options,
+            source_sampling,
+            &curve,
+            min,
+            max,
+            original_size as u64,
+            cancel,
+        )
+        .map_err(|err| format!("failed to compress image: {}", err))?;
+        results.push((*format, compressed));
+    }
+
+    // Select the smallest result among candidates of each request.
+    let mut selected: Vec<(Format, Compressed)> = Vec::new();
+    for formats in &candidates {
+        let best = results
+            .iter()
+            .filter(|(format, _)| formats.contains(format))
+            .min_by_key(|(_, compressed)| compressed.buffer.len());
+        if let Some((format, compressed)) = best {
+            if formats.len() > 1 {
+                eprintln!("selected format: {}", format);
+            }
+            selected.push((*format, compressed.clone()));
+        }
+    }
+
+    if let (Some(path), [(format, compressed)]) = (&args.ssim_map, selected.as_slice()) {
+        let (_, _, attr) = images
+            .iter()
+            .find(|(blended, _, _)| *blended == blend(format))
+            .unwrap();
+        let output_image = decode(*format, &compressed.buffer, &read_options)
+            .map_err(|err| format!("failed to read output: {}", err))?;
+        let map = attr
+            .ssim_map(&output_image)
+            .ok_or_else(|| "Failed to calculate SSIM map".to_string())?;
+        lodepng::encode32_file(path, &map.data, map.width, map.height)
+            .map_err(|err| format!("failed to write SSIM map: {}", err))?;
+    }
+
+    Ok(selected)
+}
+
+impl Args {
+    // Arguments with the defaults of the preset applied to options which weren't given explicitly.
+    fn with_preset(&self, preset: Preset) -> Args {
+        let mut args = self.clone();
+        // Downscaled comparison is accurate enough for large photos, but it may miss artifacts
+        // around thin lines and text.
+        let (chroma_subsampling, dither, png_effort, png_colors, fast_ssim) = match preset {
+            Preset::Photo => ("source", png::Dithering::FloydSteinberg, 7, 256, true),
+            Preset::Screenshot => ("444", png::Dithering::None, 7, 128, false),
+            Preset::Icon => ("444", png::Dithering::None, 10, 64, false),
+            Preset::Artwork => ("444", png::Dithering::Ordered, 7, 256, false),
+            Preset::Auto => unreachable!(),
+        };
+        let explicit = |key: &str| self.explicit_options.iter().any(|option| option == key);
+        if !explicit("chroma-subsampling") {
+            args.chroma_subsampling = chroma_subsampling.to_string();
+        }
+        if !explicit("dither") {
+            args.dither = dither;
+        }
+        if !explicit("png-effort") {
+            args.png_effort = png_effort;
+        }
+        if !explicit("png-colors") {
+            args.png_colors = png_colors;
+        }
+        if !explicit("fast-ssim") {
+            args.fast_ssim = fast_ssim;
+        }
+        args
+    }
+
+    // Token cancelled after `--timeout` has passed.
+    fn cancellation_token(&self) -> CancellationToken {
+        self.timeout
+            .map(CancellationToken::with_timeout)
+            .unwrap_or_default()
+    }
+
+    // Arguments with the defaults of output format applied.
+    fn for_format(&self, format: Format) -> Args {
+        let mut args = self.clone();
+        for (_, defaults) in self.format_defaults.iter().filter(|(f, _)| *f == format) {
+            args.quality.default = defaults.quality.unwrap_or(args.quality.default);
+            args.min = defaults.min.or(args.min);
+            args.max = defaults.max.or(args.max);
+            args.spread = defaults.spread.unwrap_or(args.spread);
+            if let Some(chroma_subsampling) = &defaults.chroma_subsampling {
+                args.chroma_subsampling = chroma_subsampling.clone();
+            }
+            if let Some(quality_curve) = &defaults.quality_curve {
+                args.quality_curve = Some(quality_curve.clone());
+            }
+        }
+        // Quality given for the format on the command line takes precedence over the defaults.
+        if let Some(quality) = self.quality.for_format(format) {
+            args.quality.default = quality;
+        }
+        args.quality.formats.clear();
+        args
+    }
+}
+
+fn quality_range(args: &Args) -> Result<(u8, u8), String> {
+    let min = args
+        .min
+        .unwrap_or_else(|| args.quality.default.saturating_sub(args.spread));
+    let max = args
+        .max
+        .unwrap_or_else(|| std::cmp::min(args.quality.default + args.spread, 100));
+    if min > max {
+        return Err("value of `--min` must be less or equal to value of `--max`".to_string());
+    }
+    Ok((min, max))
+}
+
+fn pio(mut args: Args) -> Result<Summary, String> {
+    quality_range(&args)?;
+
+    // Suffix is a shorthand for a template in the directory of the input.
+    if let Some(suffix) = args.suffix.take() {
+        let input = args.input.as_ref().unwrap(); // validated by clap
+        if is_url(input) {
+            return Err("`--suffix` can't be used with URL input".to_string());
+        }
+        // Empty suffix would write the output over the input without the safety of `--in-place`.
+        if suffix.is_empty() {
+            return Err(
+                "`--suffix` can't be empty, use `--in-place` to overwrite the input".to_string(),
+            );
+        }
+        if suffix.contains(['{', '}']) {
+            return Err("`--suffix` can't contain `{` or `}`".to_string());
+        }
+        args.output_dir = Some(input.parent().map_or_else(PathBuf::new, Path::to_path_buf));
+        args.template = format!("{{stem}}{}.{{ext}}", suffix);
+    }
+
+    let (input_format, input_buffer) = {
+        let mut reader: Box<dyn std::io::Read> = match &args.input {
+            None => {
+                if args.output.is_none() && args.output_format.is_none() {
+                    return Err("reading from standard input, use `--output` to write to a file or `--output-format` to write to standard output".to_string());
+                }
+                Box::new(std::io::stdin())
+            }
+            Some(path) if is_url(path) => {
+                if args.in_place {
+                    return Err("can't overwrite URL in place".to_string());
+                }
+                open_url(path)?
+            }
+            Some(path) => Box::new(
+                File::open(path).map_err(|err| format!("failed to open input file: {}", err))?,
+            ),
+        };
+
+        if args.archive {
+            let mut buf = Vec::new();
+            reader
+                .read_to_end(&mut buf)
+                .map_err(|err| format!("failed to read input: {}", err))?;
+            return pio_archive(&args, &buf);
+        }
+
+        // Read enough data to determine input file format by magic number.
+        let mut buf = vec![0; 16];
+        reader
+            .read_exact(&mut buf)
+            .map_err(|err| format!("failed to read magic number: {}", err))?;
+        let fmt = args
+            .input_format
+            .or_else(|| Format::from_magic(&buf))
+            .ok_or_else(|| "unknown input format, expected jpeg, png or webp".to_string())?;
+        // Read rest of the input.
+        reader
+            .read_to_end(&mut buf)
+            .map_err(|err| format!("failed to read input: {}", err))?;
+
+        (fmt, buf)
+    };
+
+    if !args.output_formats.is_empty() {
+        return pio_formats(&args, input_format, &input_buffer);
+    }
+
+    let output_format = if args.in_place || args.output_dir.is_some() {
+        args.output_format.unwrap_or_else(|| input_format.into())
+    } else {
+        match &args.output {
+            Some(path) => args.output_format.or_else(|| Format::from_path(path).map(OutputFormat::from)).ok_or_else(|| {
+                "failed to determine output format: either use a known file extension (jpeg, png or webp) or specify the format using `--output-format`".to_string()
+            })?,
+            None => args.output_format.ok_or_else(|| "use `--output` to write to a file or `--output-format` to write to standard output".to_string())?,
+        }
+    };
+    // Output written to a path with a known extension must be in the format of the extension.
+    let path_format = match (&args.output, &args.input) {
+        (Some(path), _) => Format::from_path(path),
+        (None, Some(path)) if args.in_place && args.output_format.is_some() => {
+            Format::from_path(path)
         }
+        _ => None,
+    };
+    let output_format = match path_format {
+        Some(format) if output_format == OutputFormat::Auto => {
+            eprintln!("using {} to match the extension of the output", format);
+            format.into()
+        }
+        Some(format) if !output_format.candidates().contains(&format) => {
+            return Err(format!(
+                "output format {} doesn't match the extension of the output, which is for {}",
+                output_format.candidates()[0],
+                format
+            ));
+        }
+        _ => output_format,
+    };
+    if args.json
+        && !args.dry_run
+        && !args.in_place
+        && args.output_dir.is_none()
+        && args.output.is_none()
+    {
+        return Err("`--json` can't be used when writing the image to standard output, use `--output` or `--dry-run`".to_string());
+    }
+    if let Some(path) = known_output_path(&args, output_format).filter(|_| !args.dry_run) {
+        if let Some(size) = existing_output(&args, &path) {
+            return Ok(Summary {
+                outcome: Outcome::Skipped,
+                input_size: input_buffer.len(),
+                outputs: vec![(output_format.candidates()[0].to_string(), size)],
+            });
+        }
+    }
+    // Marked input is copied only if its format is a possible output format. Input marked with the
+    // same options is always copied, because optimizing it again would only lose more quality.
+    let marker = read_marker(input_format, &input_buffer)
+        .filter(|_| output_format.candidates().contains(&input_format));
+    if let Some(marker) = marker {
+        let (setting, options) = parse_marker(&marker);
+        let same_options = options == Some(options_digest(&args).as_str());
+        if same_options || args.skip_optimized {
+            if same_options {
+                eprintln!(
+                    "input is already optimal with the same options ({}), skipping",
+                    marker
+                );
+            } else {
+                eprintln!("input is already optimized ({}), skipping", marker);
+            }
+            let setting = setting.unwrap_or_else(|| Setting::Fixed("unknown".to_string()));
+            // Input is left untouched in place, and copied only if the output is another file.
+            let same_file = match (&args.input, &args.output) {
+                (Some(input), Some(output)) => {
+                    std::fs::canonicalize(input).ok() == std::fs::canonicalize(output).ok()
+                }
+                _ => false,
+            };
+            let output_writer = match &args.output_dir {
+                _ if args.dry_run || args.in_place || same_file => None,
+                Some(dir) => open_output_in_dir(&args, dir, input_format, &setting, &input_buffer)?,
+                None => Some(open_output(&args)?),
+            };
+            if let Some(output_writer) = output_writer {
+                output_writer
+                    .write(&input_buffer)
+                    .map_err(|err| format!("failed to write output: {}", err))?;
+            }
+            if let Some(report) = &args.report {
+                append_report(
+                    report,
+                    args.input.as_deref(),
+                    &ReportRow {
+                        format: Some(input_format),
+                        input_size: Some(input_buffer.len()),
+                        output_size: Some(input_buffer.len()),
+                        setting: Some(&setting),
+                        chroma_subsampling: None,
+                        dssim: None,
+                        status: Outcome::Skipped.name(),
+                        peak_memory: reported_memory_usage(args.report_memory),
+                    },
+                )?;
+            }
+            return Ok(Summary {
+                outcome: Outcome::Skipped,
+                input_size: input_buffer.len(),
+                outputs: vec![(input_format.to_string(), input_buffer.len())],
+            });
+        }
+    }
+
+    // Output is not opened in dry run, so existing files are left untouched. File name in output
+    // directory depends on the result, so it's opened after compression.
+    let output_writer = if args.dry_run || args.output_dir.is_some() {
+        None
+    } else {
+        Some(open_output(&args)?)
+    };
+
+    let cache = args.cache_dir.as_ref().map(Cache::new);
+    let cache_key = Cache::key(
+        &input_buffer,
+        &format!("{:?} {}", output_format, options_string(&args)),
+    );
+    // SSIM map can't be created from a cached result.
+    let cached = match &cache {
+        Some(cache) if args.ssim_map.is_none() => cache
+            .get(&cache_key)
+            .and_then(|entry| decode_cache_entry(&entry)),
+        _ => None,
+    };
+
+    let (format, compressed) = match cached {
+        Some(cached) => {
+            eprintln!("using cached output");
+            cached
+        }
+        None => {
+            let cancel = args.cancellation_token();
+            let (format, compressed) = optimize(
+                &args,
+                &cancel,
+                input_format,
+                &input_buffer,
+                &[output_format],
+            )?
+            .remove(0);
+            // Result of a search cut short by timeout depends on timing, so don't reuse it.
+            if let Some(cache) = cache.as_ref().filter(|_| !cancel.is_cancelled()) {
+                if let Err(err) = cache.put(&cache_key, &encode_cache_entry(&compressed)) {
+                    eprintln!("warning: Failed to write cache: {}", err);
+                }
+            }
+            (format, compressed)
+        }
+    };
+
+    let (outcome, output_size) =
+        write_compressed(&args, output_writer, format, compressed, &input_buffer)?;
+    Ok(Summary {
+        outcome,
+        input_size: input_buffer.len(),
+        outputs: vec![(format.to_string(), output_size)],
+    })
+}
+
+// Compress input to each of `--output-formats` and write them to `--output-dir`.
+fn pio_formats(args: &Args, input_format: Format, input_buffer: &[u8]) -> Result<Summary, String> {
+    if args.cache_dir.is_some() {
+        eprintln!("warning: `--cache-dir` is not supported with `--output-formats`.");
+    }
+    let mut summary = Summary {
+        outcome: Outcome::Success,
+        input_size: input_buffer.len(),
+        outputs: Vec::new(),
+    };
+    let mut formats = Vec::new();
+    for &format in &args.output_formats {
+        match known_output_path(args, format)
+            .filter(|_| !args.dry_run)
+            .and_then(|path| existing_output(args, &path))
+        {
+            Some(size) => summary
+                .outputs
+                .push((format.candidates()[0].to_string(), size)),
+            None => formats.push(format),
+        }
+    }
+    if formats.is_empty() {
+        summary.outcome = Outcome::Skipped;
+        return Ok(summary);
+    }
+    let cancel = args.cancellation_token();
+    for (format, compressed) in optimize(args, &cancel, input_format, input_buffer, &formats)? {
+        let (outcome, output_size) =
+            write_compressed(args, None, format, compressed, input_buffer)?;
+        if outcome == Outcome::Copied {
+            summary.outcome = Outcome::Copied;
+        }
+        summary.outputs.push((format.to_string(), output_size));
+    }
+    Ok(summary)
+}
+
+// Optimize images inside zip or tar archive. Images keep their name and format and are replaced
+// only if the result is smaller. Other entries are copied as is.
+fn pio_archive(args: &Args, input_buffer: &[u8]) -> Result<Summary, String> {
+    let output_writer = if args.dry_run {
+        None
+    } else if args.in_place || args.output.is_some() {
+        Some(open_output(args)?)
+    } else {
+        return Err("use `--output` or `--in-place` to write the archive".to_string());
+    };
+
+    let args = &Args {
+        batch: true,
+        ..args.clone()
+    };
+    let total =
+        pio::archive::count_files(input_buffer, |_, data| Format::from_magic(data).is_some())?;
+    let mut progress = Progress::new(total);
+    let (mut images, mut optimized) = (0, 0);
+    let output_buffer = pio::archive::rewrite(input_buffer, |name, data| {
+        let format = Format::from_magic(data)?;
+        images += 1;
+        progress.start(name);
+        let output = match optimize(
+            args,
+            &args.cancellation_token(),
+            format,
+            data,
+            &[format.into()],
+        ) {
+            Ok(mut results) => {
+                let (_, compressed) = results.remove(0);
+                if compressed.buffer.len() < data.len() {
+                    optimized += 1;
+                    Some(compressed.buffer)
+                } else {
+                    eprintln!("output is not smaller than input, keeping {} as is", name);
+                    None
+                }
+            }
+            Err(err) => {
+                eprintln!("warning: Failed to optimize {}: {}", name, err);
+                None
+            }
+        };
+        progress.finish(Some(
+            data.len() as i64 - output.as_ref().map_or(data.len(), Vec::len) as i64,
+        ));
+        output
+    })?;
+    eprintln!(
+        "optimized {} of {} images in the archive",
+        optimized, images
+    );
+
+    if let Some(output_writer) = output_writer {
+        output_writer
+            .write(&output_buffer)
+            .map_err(|err| format!("failed to write output: {}", err))?;
+    }
+    Ok(Summary {
+        outcome: Outcome::Success,
+        input_size: input_buffer.len(),
+        outputs: vec![("archive".to_string(), output_buffer.len())],
+    })
+}
+
+// Overall progress of optimizing or measuring several images, printed as a line before each image
+// with the number of images done, bytes saved so far and estimated time left.
+struct Progress {
+    total: usize,
+    done: usize,
+    saved: Option<i64>,
+    started: Instant,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: 0,
+            saved: None,
+            started: Instant::now(),
+        }
+    }
+
+    fn start(&self, name: &str) {
+        let mut line = format!("[{}/{}] {}", self.done + 1, self.total, name);
+        if let Some(saved) = self.saved {
+            line += &format!(", {} bytes saved so far", saved);
+        }
+        // Estimate assumes that the rest of the images take as long as the images so far.
+        if self.done > 0 {
+            let left = self.started.elapsed().as_secs_f64() / self.done as f64
+                * self.total.saturating_sub(self.done) as f64;
+            line += &format!(", {:.0} s left", left);
+        }
+        eprintln!("{}", line);
+    }
+
+    // Mark image done, with bytes saved if it was optimized.
+    fn finish(&mut self, saved: Option<i64>) {
+        self.done += 1;
+        if let Some(saved) = saved {
+            self.saved = Some(self.saved.unwrap_or(0) + saved);
+        }
+    }
+}
+
+// Sizes of the input and outputs, printed after processing.
+struct Summary {
+    outcome: Outcome,
+    input_size: usize,
+    /// Format and size of each output as written or, in dry run, as it would be written
+    outputs: Vec<(String, usize)>,
+}
+
+impl Summary {
+    fn print(&self, elapsed: Duration, peak_memory: Option<u64>) {
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|(kind, size)| {
+                let change = *size as f64 - self.input_size as f64;
+                format!(
+                    "{} {} bytes ({:+.1} %)",
+                    kind,
+                    size,
+                    100.0 * change / self.input_size as f64
+                )
+            })
+            .collect();
+        eprintln!(
+            "{}: {} bytes -> {} in {:.2} s{}",
+            self.outcome.name(),
+            self.input_size,
+            outputs.join(", "),
+            elapsed.as_secs_f64(),
+            peak_memory.map_or_else(String::new, |bytes| format!(
+                ", peak memory usage {}",
+                format_memory_usage(bytes)
+            ))
+        );
+    }
+}
+
+// Add marker and write output to the given writer or to `--output-dir`.
+fn write_compressed(
+    args: &Args,
+    output_writer: Option<Output>,
+    format: Format,
+    mut compressed: Compressed,
+    input_buffer: &[u8],
+) -> Result<(Outcome, usize), String> {
+    if args.mark_optimized {
+        compressed.buffer = add_marker(format, &compressed.buffer, &marker(&compressed, args))
+            .map_err(|err| format!("failed to add marker: {}", err))?;
+    }
+
+    let output_writer = match &args.output_dir {
+        Some(dir) if !args.dry_run => {
+            match open_output_in_dir(args, dir, format, &compressed.setting, &compressed.buffer)? {
+                Some(output_writer) => Some(output_writer),
+                None => return Ok((Outcome::Skipped, compressed.buffer.len())),
+            }
+        }
+        _ => output_writer,
+    };
+
+    let outcome = match output_writer {
+        // JSON is printed instead of the result of dry run.
+        None if args.json => Outcome::Success,
+        output_writer => write_result(
+            output_writer,
+            format,
+            &compressed,
+            input_buffer,
+            args.fail_strategy,
+            args.min_savings,
+        )?,
+    };
+    let output_size = if outcome == Outcome::Copied {
+        input_buffer.len()
+    } else {
+        compressed.buffer.len()
+    };
+
+    if let Some(report) = &args.report {
+        append_report(
+            report,
+            args.input.as_deref(),
+            &ReportRow {
+                format: Some(format),
+                input_size: Some(input_buffer.len()),
+                output_size: Some(output_size),
+                setting: Some(&compressed.setting),
+                chroma_subsampling: match format {
+                    Format::JPEG => jpeg::chroma_subsampling(&compressed.buffer),
+                    _ => None,
+                },
+                dssim: Some(compressed.dssim).filter(|dssim| !dssim.is_nan()),
+                status: outcome.name(),
+                peak_memory: reported_memory_usage(args.report_memory),
+            },
+        )?;
+    }
+
+    if args.json {
+        println!(
+            "{}",
+            result_json(
+                format,
+                &compressed,
+                input_buffer.len(),
+                output_size,
+                outcome,
+                reported_memory_usage(args.report_memory)
+            )
+        );
+    }
+
+    Ok((outcome, output_size))
+}
+
+// JSON number or `null` if the value is NaN.
+fn json_number(value: f64) -> String {
+    if value.is_nan() {
+        "null".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+// JSON fields describing the setting. Setting names and chroma subsamplings don't need escaping.
+fn json_setting(setting: &Setting, chroma_subsampling: Option<ChromaSubsampling>) -> String {
+    format!(
+        "\"setting\": \"{}\", \"quality\": {}, \"chroma_subsampling\": {}",
+        setting,
+        match setting {
+            Setting::Quality(quality) => quality.to_string(),
+            Setting::Fixed(_) => "null".to_string(),
+        },
+        chroma_subsampling.map_or_else(|| "null".to_string(), |s| format!("\"{}\"", s))
+    )
+}
+
+// Result of `--json` on a single line, including the trials of the search.
+fn result_json(
+    format: Format,
+    compressed: &Compressed,
+    input_size: usize,
+    output_size: usize,
+    outcome: Outcome,
+    peak_memory: Option<u64>,
+) -> String {
+    let trials: Vec<String> = compressed
+        .trials
+        .iter()
+        .map(|trial| {
+            format!(
+                "{{{}, \"dssim\": {}, \"size\": {}}}",
+                json_setting(&trial.setting, trial.chroma_subsampling),
+                json_number(trial.dssim),
+                trial.size
+            )
+        })
+        .collect();
+    format!(
+        "{{\"status\": \"{}\", \"format\": \"{}\", {}, \"dssim\": {}, \"input_size\": {}, \"output_size\": {}, \"iterations\": {}, \"trials\": [{}]{}}}",
+        outcome.name(),
+        format,
+        json_setting(&compressed.setting, compressed.chroma_subsampling),
+        json_number(compressed.dssim),
+        input_size,
+        output_size,
+        compressed.iterations,
+        trials.join(", "),
+        peak_memory.map_or_else(String::new, |bytes| format!(", \"peak_memory\": {}", bytes))
+    )
+}
+
+// Row of `--report`. Fields which are unknown or don't apply are left empty.
+struct ReportRow<'a> {
+    format: Option<Format>,
+    input_size: Option<usize>,
+    output_size: Option<usize>,
+    setting: Option<&'a Setting>,
+    chroma_subsampling: Option<ChromaSubsampling>,
+    dssim: Option<f64>,
+    status: &'static str,
+    peak_memory: Option<u64>,
+}
+
+// Quote CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Append row to the report, writing the header first if the file is empty. The row is written
+// with a single call so that rows of runs in parallel aren't interleaved.
+fn append_report(report: &Path, input: Option<&Path>, row: &ReportRow) -> Result<(), String> {
+    let failed = |err: std::io::Error| format!("failed to write report: {}", err);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(report)
+        .map_err(failed)?;
+    let mut line = String::new();
+    if file.metadata().map_err(failed)?.len() == 0 {
+        line.push_str(
+            "path,format,input_size,output_size,quality,chroma_subsampling,dssim,status,peak_memory\n",
+        );
+    }
+    let fields = [
+        input.map_or_else(|| "-".to_string(), |path| path.display().to_string()),
+        row.format
+            .map(|format| format.to_string())
+            .unwrap_or_default(),
+        row.input_size
+            .map(|size| size.to_string())
+            .unwrap_or_default(),
+        row.output_size
+            .map(|size| size.to_string())
+            .unwrap_or_default(),
+        match row.setting {
+            Some(Setting::Quality(quality)) => quality.to_string(),
+            Some(Setting::Fixed(name)) => name.clone(),
+            None => String::new(),
+        },
+        row.chroma_subsampling
+            .map(|sampling| sampling.to_string())
+            .unwrap_or_default(),
+        row.dssim.map(|dssim| dssim.to_string()).unwrap_or_default(),
+        row.status.to_string(),
+        row.peak_memory
+            .map(|bytes| bytes.to_string())
+            .unwrap_or_default(),
+    ];
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    line.push_str(&fields.join(","));
+    line.push('\n');
+    file.write_all(line.as_bytes()).map_err(failed)
+}
+
+// Optimize uploaded image using the default options overridden by query parameters.
+fn serve_request(args: &Args, query: &str, body: &[u8]) -> Result<(Format, Vec<u8>), String> {
+    let mut args = args.clone();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let invalid = |err: &str| format!("invalid value for `{}`: {}", key, err);
+        match key {
+            "quality" => args.quality = parse_quality_option(value).map_err(|err| invalid(&err))?,
+            "min" => args.min = Some(parse_quality(value).map_err(invalid)?),
+            "max" => args.max = Some(parse_quality(value).map_err(invalid)?),
+            "spread" => args.spread = parse_quality(value).map_err(invalid)?,
+            "format" => {
+                args.output_format =
+                    Some(OutputFormat::from_str(value, true).map_err(|err| invalid(err.as_str()))?)
+            }
+            _ => return Err(format!("unknown parameter `{}`", key)),
+        }
+        // Query parameters take precedence over defaults of output formats.
+        for (_, defaults) in &mut args.format_defaults {
+            defaults.unset(key);
+        }
+    }
+    let input_format = Format::from_magic(body)
+        .ok_or_else(|| "unknown input format, expected jpeg, png or webp".to_string())?;
+    let output_format = args.output_format.unwrap_or_else(|| input_format.into());
+    let cancel = args.cancellation_token();
+    let (format, compressed) =
+        optimize(&args, &cancel, input_format, body, &[output_format])?.remove(0);
+    Ok((format, compressed.buffer))
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+struct ServeOptions {
+    max_body_size: u64,
+    connection_timeout: Duration,
+    max_connections: NonZeroUsize,
+}
+
+// Set by SIGTERM to stop accepting connections.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+// Limit of requests optimized at the same time. Connections are read by more threads than there
+// are slots, so that requests can wait for a free slot while another thread is left to answer
+// health checks.
+struct Slots {
+    // Number of requests being optimized and waiting for a slot.
+    state: Mutex<(usize, usize)>,
+    freed: Condvar,
+    size: usize,
+    threads: usize,
+}
+
+impl Slots {
+    fn new(size: usize) -> Self {
+        Self {
+            state: Mutex::new((0, 0)),
+            freed: Condvar::new(),
+            size,
+            threads: 2 * size + 1,
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.state.lock().unwrap().0 < self.size
+    }
+
+    // Wait for a free slot, or return `false` if waiting would leave no thread free.
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.0 >= self.size && state.0 + state.1 + 1 >= self.threads {
+            return false;
+        }
+        state.1 += 1;
+        while state.0 >= self.size {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.1 -= 1;
+        state.0 += 1;
+        true
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().0 -= 1;
+        self.freed.notify_one();
+    }
+}
+
+// Handle a single HTTP/1.1 request. Only `POST /` with `Content-Length` header, and `GET /healthz`
+// and `GET /readyz` for health checks are supported.
+fn serve_connection(
+    args: &Args,
+    options: &ServeOptions,
+    slots: &Slots,
+    mut stream: TcpStream,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(options.connection_timeout))?;
+    stream.set_write_timeout(Some(options.connection_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let mut content_length = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<u64>().ok();
+            }
+        }
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    if method == "GET" && (path == "/healthz" || path == "/readyz") {
+        let (status, message) = if path == "/healthz" {
+            ("200 OK", "ok")
+        } else if SHUTDOWN.load(Ordering::Relaxed) {
+            ("503 Service Unavailable", "shutting down")
+        } else if !slots.is_free() {
+            ("503 Service Unavailable", "busy")
+        } else {
+            ("200 OK", "ready")
+        };
+        return write_response(&mut stream, status, "text/plain", message.as_bytes());
+    }
+    let result = if path != "/" {
+        Err(("404 Not Found", "not found".to_string()))
+    } else if method != "POST" {
+        Err((
+            "405 Method Not Allowed",
+            "expected POST request".to_string(),
+        ))
+    } else if content_length.is_some_and(|length| length > options.max_body_size) {
+        Err((
+            "413 Payload Too Large",
+            format!(
+                "request body is larger than {} bytes",
+                options.max_body_size
+            ),
+        ))
+    } else if let Some(length) = content_length {
+        let mut body = Vec::new();
+        reader.take(length).read_to_end(&mut body)?;
+        if slots.acquire() {
+            let result = serve_request(args, query, &body).map_err(|err| ("400 Bad Request", err));
+            slots.release();
+            result
+        } else {
+            Err((
+                "503 Service Unavailable",
+                "too many requests in progress".to_string(),
+            ))
+        }
+    } else {
+        Err((
+            "411 Length Required",
+            "expected Content-Length header".to_string(),
+        ))
+    };
+
+    match result {
+        Ok((format, buffer)) => {
+            eprintln!(
+                "{} {} -> {} bytes of {}",
+                method,
+                target,
+                buffer.len(),
+                format
+            );
+            write_response(&mut stream, "200 OK", format.mime_type(), &buffer)
+        }
+        Err((status, message)) => {
+            eprintln!("{} {} -> {}: {}", method, target, status, message);
+            write_response(&mut stream, status, "text/plain", message.as_bytes())
+        }
+    }
+}
+
+fn serve(listen: &str, options: ServeOptions, args: Args) -> Result<(), String> {
+    let listener = TcpListener::bind(listen)
+        .map_err(|err| format!("failed to listen on {}: {}", listen, err))?;
+    let address = listener
+        .local_addr()
+        .map_err(|err| format!("failed to listen on {}: {}", listen, err))?;
+    // Accepting is polled so that shutdown is noticed without a connection.
+    listener
+        .set_nonblocking(true)
+        .map_err(|err| format!("failed to listen on {}: {}", listen, err))?;
+    #[cfg(unix)]
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = request_shutdown;
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+    eprintln!("listening on {}", address);
+    // Connections are handed to a fixed number of threads. Accepting blocks while all of them
+    // are busy so that further connections wait in the listen backlog.
+    let slots = Slots::new(options.max_connections.get());
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Mutex::new(receiver);
+    std::thread::scope(|scope| {
+        for _ in 0..slots.threads {
+            scope.spawn(|| loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                if let Err(err) = serve_connection(&args, &options, &slots, stream) {
+                    eprintln!("failed to handle connection: {}", err);
+                }
+            });
+        }
+        while !SHUTDOWN.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = stream.set_nonblocking(false) {
+                        eprintln!("failed to accept connection: {}", err);
+                    } else if sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => eprintln!("failed to accept connection: {}", err),
+            }
+        }
+        eprintln!("shutting down, finishing requests in progress");
+        drop(sender);
+    });
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<(Format, Vec<u8>, Image), String> {
+    let buffer =
+        std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let format = Format::from_magic(&buffer).ok_or_else(|| {
+        format!(
+            "unknown format of {}, expected jpeg, png or webp",
+            path.display()
+        )
+    })?;
+    let image = decode(format, &buffer, &ReadOptions::default())
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    Ok((format, buffer, image))
+}
+
+fn compare(original: &Path, compressed: &Path) -> Result<(), String> {
+    let (_, original_buffer, original) = read_file(original)?;
+    let (_, compressed_buffer, compressed) = read_file(compressed)?;
+    let original_size = original_buffer.len();
+    let compressed_size = compressed_buffer.len();
+    if original.width != compressed.width || original.height != compressed.height {
+        return Err(format!(
+            "image dimensions differ: {}x{} and {}x{}",
+            original.width, original.height, compressed.width, compressed.height
+        ));
+    }
+    let attr = ssim::Calculator::new(&original)
+        .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+    let dssim = attr
+        .compare(&compressed)
+        .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+    println!("DSSIM: {:.6}", dssim);
+    println!(
+        "size:  {} -> {} bytes ({:+.1} %)",
+        original_size,
+        compressed_size,
+        100.0 * (compressed_size as f64 - original_size as f64) / original_size as f64
+    );
+    Ok(())
+}
+
+fn analyze(args: &Args, path: &Path) -> Result<(), String> {
+    let (format, buffer, image) = read_file(path)?;
+    println!("format:      {}", format);
+    println!("dimensions:  {}x{}", image.width, image.height);
+    println!("color space: {}", image.color_space);
+
+    // Estimate is on the libjpeg scale of the quantization tables, which isn't comparable to
+    // `--quality` of pio, but the quality table of JPEG gives roughly the DSSIM of the source.
+    let mut source_dssim = None;
+    if format == Format::JPEG {
+        if let Some(estimate) = jpeg::estimate_quality(&buffer) {
+            println!("quantizer:   libjpeg quality {} (estimated)", estimate);
+            source_dssim = Some(pio::search::QUALITY_SSIM_JPEG[estimate as usize]);
+        }
+    }
+
+    // Images with few colors compress well to palette PNG, others to lossy WebP.
+    let mut colors = std::collections::HashSet::new();
+    for pixel in &image.data {
+        colors.insert(*pixel);
+        if colors.len() > 256 {
+            break;
+        }
+    }
+    let recommended_format = if colors.len() <= 256 {
+        Format::PNG
+    } else {
+        Format::WEBP
+    };
+    let args = args.for_format(recommended_format);
+    let curve = quality_curve(&args, recommended_format)?;
+    // Higher quality than needed to reach the DSSIM of the source would only preserve its
+    // compression artifacts.
+    let quality = source_dssim
+        .and_then(|dssim| (0..=100).find(|quality| curve[*quality as usize] <= dssim))
+        .map_or(args.quality.default, |quality| {
+            quality.min(args.quality.default)
+        });
+    println!(
+        "recommended: --output-format {} --quality {}",
+        recommended_format.to_string().to_lowercase(),
+        quality
+    );
+    Ok(())
+}
+
+// Compress image with lossy encoder of the format using default options.
+fn compress_default(format: Format, image: &Image, quality: u8) -> pio::common::CompressResult {
+    match format {
+        Format::JPEG => {
+            jpeg::compress(image, quality, ChromaSubsampling::_420, &Default::default())
+        }
+        Format::PNG => png::compress(image, quality, &Default::default()),
+        Format::WEBP => webp::compress(image, quality, webp::Mode::Lossy, &Default::default()),
+    }
+}
+
+// Compress image with each quality using default encoder options and return DSSIM and size of
+// each quality.
+fn sweep_qualities(format: Format, image: &Image) -> Result<Vec<(f64, usize)>, String> {
+    use rayon::prelude::*;
+
+    let attr =
+        ssim::Calculator::new(image).ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+    (0..=100)
+        .into_par_iter()
+        .map(|quality| {
+            let (compressed, buffer) = compress_default(format, image, quality)?;
+            let dssim = attr
+                .compare(&compressed)
+                .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+            Ok((dssim, buffer.len()))
+        })
+        .collect()
+}
+
+// Compress images with each quality and print average DSSIM of each quality. Targets are
+// adjusted so that higher quality never has a larger target, like in the built-in tables.
+fn calibrate(
+    format: Format,
+    inputs: &[PathBuf],
+    average: Average,
+    output: CalibrateOutput,
+) -> Result<(), String> {
+    let paths = image_paths(inputs)?;
+    if paths.is_empty() {
+        return Err("no images found".to_string());
+    }
+    let mut dssims = vec![Vec::new(); 101];
+    let mut progress = Progress::new(paths.len());
+    for path in &paths {
+        progress.start(&path.display().to_string());
+        let (_, _, image) = read_file(path)?;
+        for (dssims, (dssim, _)) in dssims.iter_mut().zip(sweep_qualities(format, &image)?) {
+            dssims.push(dssim);
+        }
+        progress.finish(None);
+    }
+
+    let mut target = f64::INFINITY;
+    let mut targets = Vec::new();
+    for mut dssims in dssims {
+        let n = dssims.len();
+        let value = match average {
+            Average::Median => {
+                dssims.sort_by(f64::total_cmp);
+                if n % 2 == 0 {
+                    (dssims[n / 2 - 1] + dssims[n / 2]) / 2.0
+                } else {
+                    dssims[n / 2]
+                }
+            }
+            Average::Mean => dssims.iter().sum::<f64>() / n as f64,
+        };
+        target = target.min(value);
+        targets.push(target);
+    }
+
+    match output {
+        CalibrateOutput::Csv => {
+            println!("quality,dssim");
+            for (quality, target) in targets.iter().enumerate() {
+                println!("{},{}", quality, target);
+            }
+        }
+        CalibrateOutput::Rust => {
+            // Wrap values like the tables in `pio::search`.
+            println!("#[rustfmt::skip]");
+            println!(
+                "pub const QUALITY_SSIM_{}: [f64; 101] = [",
+                format.to_string().to_uppercase()
+            );
+            let mut line = String::new();
+            for target in targets {
+                let value = format!("{:?},", target);
+                if !line.is_empty() && 4 + line.len() + 1 + value.len() > 100 {
+                    println!("    {}", line);
+                    line.clear();
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(&value);
+            }
+            println!("    {}", line);
+            println!("];");
+        }
+    }
+    Ok(())
+}
+
+// Compress image with each quality and print DSSIM and size of each quality. Unlike
+// `calibrate`, values are printed as measured even if DSSIM doesn't decrease with quality.
+fn curve(input: &Path, format: Format, output: CurveOutput) -> Result<(), String> {
+    let (_, _, image) = read_file(input)?;
+    let results = sweep_qualities(format, &image)?;
+    match output {
+        CurveOutput::Csv => {
+            println!("quality,dssim,size");
+            for (quality, (dssim, size)) in results.iter().enumerate() {
+                println!("{},{},{}", quality, dssim, size);
+            }
+        }
+        CurveOutput::Json => {
+            let entries: Vec<String> = results
+                .iter()
+                .enumerate()
+                .map(|(quality, (dssim, size))| {
+                    format!(
+                        "  {{\"quality\": {}, \"dssim\": {}, \"size\": {}}}",
+                        quality, dssim, size
+                    )
+                })
+                .collect();
+            println!("[\n{}\n]", entries.join(",\n"));
+        }
+    }
+    Ok(())
+}
+
+// Expand directories to images in them by file extension. Other paths are used as is.
+fn image_paths(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        if !input.is_dir() {
+            paths.push(input.clone());
+            continue;
+        }
+        let entries = std::fs::read_dir(input)
+            .map_err(|err| format!("failed to read {}: {}", input.display(), err))?;
+        let mut images = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| format!("failed to read {}: {}", input.display(), err))?
+                .path();
+            if path.is_file() && Format::from_path(&path).is_some() {
+                images.push(path);
+            }
+        }
+        images.sort();
+        paths.extend(images);
+    }
+    Ok(paths)
+}
+
+// Compress images with each format and quality and print total encoding time, total size and mean
+// DSSIM of each as CSV. Images are compressed one at a time so that encoding times are comparable.
+fn bench(inputs: &[PathBuf], formats: &[Format], qualities: &[u8]) -> Result<(), String> {
+    let paths = image_paths(inputs)?;
+    if paths.is_empty() {
+        return Err("no images found".to_string());
+    }
+    // Total encoding time, total size and sum of DSSIM of each format and quality.
+    let mut totals = vec![(Duration::ZERO, 0, 0.0); formats.len() * qualities.len()];
+    let mut input_size = 0;
+    let mut progress = Progress::new(paths.len());
+    for path in &paths {
+        progress.start(&path.display().to_string());
+        let (_, buffer, image) = read_file(path)?;
+        input_size += buffer.len();
+        let attr = ssim::Calculator::new(&image)
+            .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+        let settings = formats
+            .iter()
+            .flat_map(|format| qualities.iter().map(move |quality| (*format, *quality)));
+        for ((format, quality), total) in settings.zip(&mut totals) {
+            let started = Instant::now();
+            let (compressed, buffer) = compress_default(format, &image, quality)?;
+            total.0 += started.elapsed();
+            total.1 += buffer.len();
+            total.2 += attr
+                .compare(&compressed)
+                .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+        }
+        progress.finish(None);
+    }
+
+    println!("format,quality,encode_ms,size,percent_of_input,dssim");
+    let settings = formats
+        .iter()
+        .flat_map(|format| qualities.iter().map(move |quality| (*format, *quality)));
+    for ((format, quality), (time, size, dssim)) in settings.zip(totals) {
+        println!(
+            "{},{},{},{},{:.1},{}",
+            format,
+            quality,
+            time.as_millis(),
+            size,
+            100.0 * size as f64 / input_size as f64,
+            dssim / paths.len() as f64
+        );
+    }
+    Ok(())
+}
+
+// Configuration file in the current directory takes precedence over user configuration.
+fn config_path() -> Option<PathBuf> {
+    let local = PathBuf::from("pio.toml");
+    if local.is_file() {
+        return Some(local);
+    }
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    let user = config_dir.join("pio").join("config.toml");
+    if user.is_file() {
+        Some(user)
+    } else {
+        None
+    }
+}
+
+// Parse command line arguments with defaults from configuration file. Keys of the file are long
+// option names and options given on the command line override them.
+fn parse_args() -> Result<Args, String> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    // Options of `optimize` are top-level options, so that they can also be used as defaults of
+    // other subcommands like `serve`. Use `./optimize` for input file of that name.
+    if args.get(1).is_some_and(|arg| arg == "optimize") {
+        args.remove(1);
+    }
+    let path = match config_path() {
+        Some(path) => path,
+        None => {
+            let app = Args::into_app();
+            let matches = app.clone().get_matches_from(&args);
+            let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+            args.explicit_options = given_options(&app, &matches);
+            return Ok(args);
+        }
+    };
+    let config: toml::value::Table = std::fs::read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|config| toml::from_str(&config).map_err(|err| err.to_string()))
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+
+    let app = Args::into_app();
+    let matches = app.clone().get_matches_from(&args);
+    let is_given = |key: &str| {
+        app.get_arguments()
+            .any(|arg| arg.get_long() == Some(key) && matches.occurrences_of(arg.get_name()) > 0)
+    };
+    let unsupported = |key: &str| format!("unsupported value of `{}` in {}", key, path.display());
+    let mut config_args = Vec::new();
+    let mut format_defaults = Vec::new();
+    for (key, value) in &config {
+        // Tables named after formats contain defaults for the output format.
+        if let (Some(format), toml::Value::Table(table)) = (Format::from_ext(key), value) {
+            let mut defaults = FormatDefaults::default();
+            for (key, value) in table {
+                let value = config_value(value).ok_or_else(|| unsupported(key))?;
+                defaults
+                    .set(key, &value)
+                    .map_err(|err| format!("{} in {}", err, path.display()))?;
+                if is_given(key) {
+                    defaults.unset(key);
+                }
+            }
+            format_defaults.push((format, defaults));
+            continue;
+        }
+        if !app
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(key.as_str()))
+        {
+            return Err(format!("unknown option `{}` in {}", key, path.display()));
+        }
+        if is_given(key) {
+            continue;
+        }
+        let option = format!("--{}", key);
+        match value {
+            toml::Value::Boolean(true) => config_args.push(option),
+            toml::Value::Boolean(false) => {}
+            value => {
+                let value = config_value(value).ok_or_else(|| unsupported(key))?;
+                config_args.extend([option, value]);
+            }
+        }
+    }
+
+    // Options from the configuration are placed before the subcommand and positional arguments.
+    let mut args = Args::parse_from(
+        args.iter()
+            .take(1)
+            .cloned()
+            .chain(config_args.into_iter().map(OsString::from))
+            .chain(args.iter().skip(1).cloned()),
+    );
+    args.format_defaults = format_defaults;
+    args.explicit_options = given_options(&app, &matches);
+    args.explicit_options.extend(
+        config
+            .keys()
+            .filter(|key| Format::from_ext(key).is_none())
+            .cloned(),
+    );
+    Ok(args)
+}
+
+// Long names of options given on the command line.
+fn given_options(app: &clap::App, matches: &clap::ArgMatches) -> Vec<String> {
+    app.get_arguments()
+        .filter(|arg| matches.occurrences_of(arg.get_name()) > 0)
+        .filter_map(|arg| arg.get_long().map(str::to_string))
+        .collect()
+}
+
+fn config_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn main() {
+    let mut args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let report_memory = args.report_memory;
+    let optimizing = args.command.is_none();
+    let result = match args.command.take() {
+        Some(Command::Compare {
+            original,
+            compressed,
+        }) => compare(&original, &compressed).map(|()| Outcome::Success),
+        Some(Command::Analyze { input }) => analyze(&args, &input).map(|()| Outcome::Success),
+        Some(Command::Serve {
+            listen,
+            max_body_size,
+            connection_timeout,
+            max_connections,
+        }) => {
+            let options = ServeOptions {
+                max_body_size,
+                connection_timeout,
+                max_connections,
+            };
+            serve(&listen, options, args).map(|()| Outcome::Success)
+        }
+        Some(Command::Calibrate {
+            format,
+            inputs,
+            average,
+            output,
+        }) => calibrate(format, &inputs, average, output).map(|()| Outcome::Success),
+        Some(Command::Curve {
+            input,
+            format,
+            output,
+        }) => curve(&input, format, output).map(|()| Outcome::Success),
+        Some(Command::Bench {
+            inputs,
+            formats,
+            qualities,
+        }) => bench(&inputs, &formats, &qualities).map(|()| Outcome::Success),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::into_app(), "pio", &mut std::io::stdout());
+            Ok(Outcome::Success)
+        }
+        // `optimize` is removed from arguments before parsing, so it's only matched if options
+        // are given before it.
+        Some(Command::Optimize) => {
+            Err("options must be given after `optimize` subcommand".to_string())
+        }
+        None => {
+            let report = args.report.clone();
+            let input = args.input.clone();
+            let started = Instant::now();
+            let result = pio(args).map(|summary| {
+                summary.print(started.elapsed(), reported_memory_usage(report_memory));
+                summary.outcome
+            });
+            if let (Some(report), Err(_)) = (&report, &result) {
+                let row = ReportRow {
+                    format: None,
+                    input_size: None,
+                    output_size: None,
+                    setting: None,
+                    chroma_subsampling: None,
+                    dssim: None,
+                    status: "failed",
+                    peak_memory: reported_memory_usage(report_memory),
+                };
+                if let Err(err) = append_report(report, input.as_deref(), &row) {
+                    eprintln!("warning: {}", err);
+                }
+            }
+            result
+        }
+    };
+    // Summary of optimization includes peak memory usage if it's known.
+    if report_memory && !(optimizing && result.is_ok() && peak_memory_usage().is_some()) {
+        match peak_memory_usage() {
+            Some(bytes) => eprintln!("peak memory usage: {}", format_memory_usage(bytes)),
+            None => eprintln!("peak memory usage: unknown"),
+        }
+    }
+    let outcome = result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    std::process::exit(outcome.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use assert_cmd::Command;
+    use pio::profile::TargetColorSpace;
+    use predicates::prelude::*;
+    use tempfile::tempdir;
+
+    fn convert_image(input: impl AsRef<Path>, output: impl AsRef<Path>) {
+        Command::new("convert")
+            .arg(input.as_ref())
+            .arg("-quality")
+            .arg("100")
+            .arg(output.as_ref())
+            .assert()
+            .success();
+    }
+
+    fn assert_image_similarity(
+        image1: impl AsRef<Path>,
+        image2: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let output = Command::new("compare")
+            .arg("-metric")
+            .arg("PSNR")
+            .arg(image1.as_ref())
+            .arg(image2.as_ref())
+            .arg("/dev/null")
+            .output()?;
+        let psnr: f32 = String::from_utf8(output.stderr)?.parse()?;
+        assert!(psnr > 30.0);
+        Ok(())
+    }
+
+    fn assert_jpeg_sampling_factors(image: impl AsRef<Path>, sampling_factors: &'static str) {
+        Command::new("identify")
+            .arg("-format")
+            .arg("%[jpeg:sampling-factor]")
+            .arg(image.as_ref())
+            .assert()
+            .success()
+            .stdout(sampling_factors);
+    }
+
+    fn exif_data(fields: &[exif::Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut exif = std::io::Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+        exif.into_inner()
+    }
+
+    fn write_png(path: impl AsRef<Path>, chunks: &[(&[u8; 4], Vec<u8>)]) {
+        let mut encoder = lodepng::Encoder::new();
+        for (name, data) in chunks {
+            encoder
+                .info_png_mut()
+                .create_chunk(lodepng::ChunkPosition::IHDR, name, data)
+                .unwrap();
+        }
+        let pixels: Vec<rgb::RGBA8> = (0..64 * 64)
+            .map(|i| rgb::RGBA8::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255))
+            .collect();
+        std::fs::write(path, encoder.encode(&pixels, 64, 64).unwrap()).unwrap();
+    }
+
+    // lodepng reads and writes 16-bit samples in big-endian byte order.
+    fn big_endian(pixels: &[rgb::RGBA<u16>]) -> Vec<rgb::RGBA<u16>> {
+        use rgb::ComponentMap;
+        pixels.iter().map(|c| c.map(u16::to_be)).collect()
+    }
+
+    fn read_png_exif(path: impl AsRef<Path>) -> Option<exif::Exif> {
+        let mut decoder = lodepng::Decoder::new();
+        decoder.remember_unknown_chunks(true);
+        decoder.decode(std::fs::read(path).unwrap()).unwrap();
+        let raw = decoder.info_png().get("eXIf")?.data().to_vec();
+        Some(exif::Reader::new().read_raw(raw).unwrap())
+    }
+
+    #[test]
+    fn fails_with_no_arguments() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .assert()
+            .failure()
+            .stderr("reading from standard input, use `--output` to write to a file or `--output-format` to write to standard output\n");
+        Ok(())
+    }
+
+    #[test]
+    fn reads_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpeg");
+        convert_image("images/image1-original.png", &input);
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert_image_similarity(input, output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = "images/image1-original.png";
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg(input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert_image_similarity(input, output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reads_webp() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.webp");
+        convert_image("images/image1-original.png", &input);
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert_image_similarity(input, output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_webp() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = "images/image1-original.png";
+        let output = dir.path().join("output.webp");
+        Command::cargo_bin("pio")?
+            .arg(input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert_image_similarity(input, output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = "images/image1-original.png";
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg(input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert_image_similarity(input, output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_create_empty_output_on_invalid_input() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("-o")
+            .arg(&output)
+            .write_stdin("RIFF....WEBP....")
+            .assert()
+            .failure();
+        assert!(std::fs::read(&output).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_to_special_files() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg("/dev/null")
+            .arg("--output-format")
+            .arg("jpeg")
+            .assert()
+            .success();
+        Ok(())
+    }
+
+    #[test]
+    fn reports_memory_usage() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg("/dev/null")
+            .arg("--output-format")
+            .arg("jpeg")
+            .arg("--report-memory")
+            .assert()
+            .success()
+            .stderr(predicates::str::contains(", peak memory usage "));
+
+        let dir = tempdir()?;
+        let report = dir.path().join("report.csv");
+        let output = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "jpeg", "--dry-run", "--json"])
+            .arg("--report")
+            .arg(&report)
+            .arg("--report-memory")
+            .output()?;
+        assert!(output.status.success());
+        let json = String::from_utf8(output.stdout)?;
+        assert!(json.contains(", \"peak_memory\": "));
+        let report = std::fs::read_to_string(&report)?;
+        let row: Vec<&str> = report.lines().nth(1).unwrap().split(',').collect();
+        assert!(row[8].parse::<u64>()? > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn strips_private_metadata() -> Result<(), Box<dyn std::error::Error>> {
+        use exif::{Field, In, Tag, Value};
+
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        let exif = exif_data(&[
+            Field {
+                tag: Tag::Artist,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"Artist".to_vec()]),
+            },
+            Field {
+                tag: Tag::BodySerialNumber,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"12345".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLatitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"N".to_vec()]),
+            },
+        ]);
+        write_png(&input, &[(b"eXIf", exif)]);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--metadata")
+            .arg("safe")
+            .assert()
+            .success();
+        let exif = read_png_exif(&output).expect("expected Exif data");
+        assert!(exif.get_field(Tag::Artist, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::BodySerialNumber, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        assert!(read_png_exif(&output).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn strips_exif_thumbnail() -> Result<(), Box<dyn std::error::Error>> {
+        use exif::{Field, In, Tag, Value};
+
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        // Contents of the thumbnail don't matter for Exif.
+        let thumbnail = vec![0; 8192];
+        let mut writer = exif::experimental::Writer::new();
+        let artist = Field {
+            tag: Tag::Artist,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"Artist".to_vec()]),
+        };
+        let maker_note = Field {
+            tag: Tag::MakerNote,
+            ifd_num: In::PRIMARY,
+            value: Value::Undefined(vec![0; 1024], 0),
+        };
+        writer.push_field(&artist);
+        writer.push_field(&maker_note);
+        writer.set_jpeg(&thumbnail, In::THUMBNAIL);
+        let mut exif = std::io::Cursor::new(Vec::new());
+        writer.write(&mut exif, false)?;
+        write_png(&input, &[(b"eXIf", exif.into_inner())]);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--metadata", "all"])
+            .assert()
+            .success();
+        let exif = read_png_exif(&output).expect("expected Exif data");
+        assert!(exif.get_field(Tag::Artist, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::MakerNote, In::PRIMARY).is_none());
+        assert!(exif
+            .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)
+            .is_none());
+        assert!(exif.buf().len() < 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_color_profile() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        // Profile with Adobe RGB (1998) primaries.
+        let profile = lcms2::Profile::new_rgb(
+            &lcms2::CIExyY {
+                x: 0.3127,
+                y: 0.3290,
+                Y: 1.0,
+            },
+            &lcms2::CIExyYTRIPLE {
+                Red: lcms2::CIExyY {
+                    x: 0.64,
+                    y: 0.33,
+                    Y: 1.0,
+                },
+                Green: lcms2::CIExyY {
+                    x: 0.21,
+                    y: 0.71,
+                    Y: 1.0,
+                },
+                Blue: lcms2::CIExyY {
+                    x: 0.15,
+                    y: 0.06,
+                    Y: 1.0,
+                },
+            },
+            &[&lcms2::ToneCurve::new(2.2); 3],
+        )?
+        .icc()?;
+        let mut iccp = flate2::write::ZlibEncoder::new(
+            b"Adobe RGB\0\0".to_vec(),
+            flate2::Compression::default(),
+        );
+        iccp.write_all(&profile)?;
+
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        write_png(&input, &[(b"iCCP", iccp.finish()?)]);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--keep-color-profile")
+            .assert()
+            .success();
+
+        let mut decoder = lodepng::Decoder::new();
+        decoder.remember_unknown_chunks(true);
+        decoder.decode(std::fs::read(&output)?)?;
+        assert_eq!(decoder.get_icc()?, profile);
+        assert!(decoder.info_png().get("sRGB").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn dithers_16_bit_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+
+        // Value halfway between 8-bit levels 128 and 129.
+        let value = 128 * 257 + 128;
+        let mut encoder = lodepng::Encoder::new();
+        encoder.info_raw_mut().set_bitdepth(16);
+        encoder.info_png_mut().color.set_bitdepth(16);
+        let pixels = vec![rgb::RGBA::<u16>::new(value, value, value, 65535); 64 * 64];
+        std::fs::write(&input, encoder.encode(&big_endian(&pixels), 64, 64)?)?;
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+
+        let output = lodepng::decode32_file(&output)?;
+        let mean =
+            output.buffer.iter().map(|c| c.g as f64).sum::<f64>() / output.buffer.len() as f64;
+        assert!((mean - 128.5).abs() < 0.1, "mean {}", mean);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_16_bit_png_lossless() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+
+        let mut encoder = lodepng::Encoder::new();
+        encoder.info_raw_mut().set_bitdepth(16);
+        encoder.info_png_mut().color.set_bitdepth(16);
+        let pixels: Vec<_> = (0..64 * 64)
+            .map(|i| rgb::RGBA::<u16>::new(i * 16 + 1, 32896, 65535 - i * 16, 51400 + 128))
+            .collect();
+        std::fs::write(&input, encoder.encode(&big_endian(&pixels), 64, 64)?)?;
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--lossless")
+            .assert()
+            .success();
+        let mut decoder = lodepng::Decoder::new();
+        decoder.info_raw_mut().set_bitdepth(16);
+        match decoder.decode(std::fs::read(&output)?)? {
+            lodepng::Image::RGBA16(image) => assert!(image.buffer == big_endian(&pixels)),
+            _ => unreachable!(),
+        }
+
+        // Alpha between 8-bit levels is rounded instead of dithered.
+        let image = pio::png::read(&std::fs::read(&input)?, &Default::default())?;
+        assert!(image.data.iter().all(|c| c.a == 200));
+        Ok(())
+    }
+
+    #[test]
+    fn handles_hdr_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+
+        // BT.2100 PQ encoded SDR reference white (203 nits).
+        let value = 38056;
+        let mut encoder = lodepng::Encoder::new();
+        encoder.info_raw_mut().set_bitdepth(16);
+        encoder.info_png_mut().color.set_bitdepth(16);
+        encoder.info_png_mut().create_chunk(
+            lodepng::ChunkPosition::IHDR,
+            b"cICP",
+            &[9, 16, 0, 1],
+        )?;
+        let pixels = vec![rgb::RGBA::<u16>::new(value, value, value, 65535); 64 * 64];
+        std::fs::write(&input, encoder.encode(&big_endian(&pixels), 64, 64)?)?;
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--tone-mapping")
+            .arg("clip")
+            .assert()
+            .success();
+        let mut decoder = lodepng::Decoder::new();
+        decoder.remember_unknown_chunks(true);
+        match decoder.decode(std::fs::read(&output)?)? {
+            lodepng::Image::RGBA(image) => assert!(image.buffer.iter().all(|c| c.g >= 250)),
+            _ => unreachable!(),
+        }
+        assert!(decoder.info_png().get("cICP").is_none());
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("keeping HDR data"));
+        let mut decoder = lodepng::Decoder::new();
+        decoder.remember_unknown_chunks(true);
+        decoder.info_raw_mut().set_bitdepth(16);
+        match decoder.decode(std::fs::read(&output)?)? {
+            lodepng::Image::RGBA16(image) => assert!(image.buffer == big_endian(&pixels)),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            decoder
+                .info_png()
+                .get("cICP")
+                .map(|chunk| chunk.data().to_vec()),
+            Some(vec![9, 16, 0, 1])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn converts_to_display_p3() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.webp");
+        write_png(&input, &[]);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--target-colorspace")
+            .arg("display-p3")
+            .assert()
+            .success();
+
+        let output = std::fs::read(&output)?;
+        let image = pio::webp::read(&output, &Default::default())?;
+        assert!(image.metadata.icc_profile.is_none());
+        let image = pio::webp::read(
+            &output,
+            &pio::common::ReadOptions {
+                color_space: TargetColorSpace::DisplayP3,
+                ..Default::default()
+            },
+        )?;
+        let profile = lcms2::Profile::new_icc(&image.metadata.icc_profile.unwrap())?;
+        assert!(pio::profile::is_display_p3(&profile));
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_grayscale() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--grayscale")
+            .assert()
+            .success();
+        let image = pio::jpeg::read(&std::fs::read(&output)?, &Default::default())?;
+        assert!(image.color_space == pio::common::ColorSpace::Gray);
+        Ok(())
+    }
+
+    fn is_progressive_jpeg(buffer: &[u8]) -> bool {
+        // Start of frame marker for progressive DCT.
+        buffer.windows(2).any(|marker| marker == [0xff, 0xc2])
+    }
+
+    #[test]
+    fn outputs_baseline_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let progressive = dir.path().join("progressive.jpeg");
+        let baseline = dir.path().join("baseline.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&progressive)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&baseline)
+            .arg("--baseline")
+            .assert()
+            .success();
+        assert!(is_progressive_jpeg(&std::fs::read(&progressive)?));
+        assert!(!is_progressive_jpeg(&std::fs::read(&baseline)?));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_jpeg_scan_script() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let scans = dir.path().join("scans.txt");
+        let output = dir.path().join("output.jpeg");
+        std::fs::write(
+            &scans,
+            "# DC first\n0,1,2: 0-0, 0, 0;\n0: 1-63, 0, 0;\n1: 1-63, 0, 0;\n2: 1-63, 0, 0;\n",
+        )?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--scans")
+            .arg(&scans)
+            .assert()
+            .success();
+        let output = std::fs::read(&output)?;
+        assert!(is_progressive_jpeg(&output));
+        // Start of scan markers.
+        assert_eq!(
+            output
+                .windows(2)
+                .filter(|marker| *marker == [0xff, 0xda])
+                .count(),
+            4
+        );
+
+        std::fs::write(&scans, "0,1,2: 0-64, 0, 0;")?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(dir.path().join("invalid.jpeg"))
+            .arg("--scans")
+            .arg(&scans)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "invalid value \"64\" in scan script",
+            ));
+        Ok(())
+    }
+
+    #[test]
+    fn disables_jpeg_tuning() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let default = dir.path().join("default.jpeg");
+        let tuned = dir.path().join("tuned.jpeg");
+        for (output, args) in [
+            (&default, &[][..]),
+            (&tuned, &["--no-trellis", "--no-optimize-huffman"][..]),
+        ] {
+            Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(output)
+                .args(["--min", "80", "--max", "80", "--chroma-subsampling", "420"])
+                .args(args)
+                .assert()
+                .success();
+        }
+        assert!(std::fs::metadata(&tuned)?.len() > std::fs::metadata(&default)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn uses_webp_method() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.webp");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--webp-method", "0"])
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--webp-method", "7"])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("expected value between 0 and 6"));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_png_effort() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--png-effort", "1"])
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--png-effort", "0"])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("expected value between 1 and 10"));
+        Ok(())
+    }
+
+    #[test]
+    fn posterizes_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--posterize", "4", "--search", "fixed"])
+            .assert()
+            .success();
+        // 4-bit values are scaled to 8 bits by repeating them, for example 0xa to 0xaa.
+        let image = lodepng::decode32_file(&output)?;
+        assert!(image.buffer.iter().all(|c| c.r >> 4 == c.r & 0xf));
+        Ok(())
+    }
+
+    #[test]
+    fn tries_near_lossless_webp() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.webp");
+        write_png(&input, &[]);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success()
+            .stderr(predicates::str::contains("near-lossless"));
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--webp-near-lossless", "100"])
+            .assert()
+            .success()
+            .stderr(predicates::str::contains("near-lossless").not());
+        Ok(())
+    }
+
+    #[test]
+    fn uses_webp_alpha_quality() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        // Soft circular shadow.
+        let pixels: Vec<rgb::RGBA8> = (0..64 * 64)
+            .map(|i| {
+                let distance = ((i % 64) as f32 - 32.0).hypot((i / 64) as f32 - 32.0);
+                let alpha = 255.0 * (1.0 - distance / 32.0).max(0.0);
+                rgb::RGBA8::new(200, 50, 50, alpha as u8)
+            })
+            .collect();
+        std::fs::write(&input, lodepng::encode32(&pixels, 64, 64)?)?;
+        let mut sizes = Vec::new();
+        for alpha_quality in ["100", "0"] {
+            let output = dir.path().join(format!("output{}.webp", alpha_quality));
+            Command::cargo_bin("pio")?
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .args(["--webp-alpha-quality", alpha_quality])
+                .args(["--webp-near-lossless", "100"])
+                .assert()
+                .success();
+            sizes.push(std::fs::metadata(&output)?.len());
+        }
+        assert!(sizes[1] < sizes[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn transcodes_jpeg_losslessly() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpeg");
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&input)
+            .args(["--baseline", "--no-optimize-huffman"])
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--lossless-transcode")
+            .assert()
+            .success();
+        let input = std::fs::read(&input)?;
+        let output = std::fs::read(&output)?;
+        assert!(is_progressive_jpeg(&output));
+        assert!(output.len() < input.len());
+        assert!(
+            pio::jpeg::read(&input, &Default::default())?.data
+                == pio::jpeg::read(&output, &Default::default())?.data
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_lossless_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--lossless")
+            .assert()
+            .success();
+        let original = pio::png::read(
+            &std::fs::read("images/image1-original.png")?,
+            &Default::default(),
+        )?;
+        let output = pio::png::read(&std::fs::read(&output)?, &Default::default())?;
+        assert!(original.data == output.data);
+        Ok(())
+    }
+
+    #[test]
+    fn compresses_png_with_zopfli() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let default = dir.path().join("default.png");
+        let max = dir.path().join("max.png");
+        for (output, level) in [(&default, "default"), (&max, "max")] {
+            Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(output)
+                .args(["--png-compression", level])
+                .assert()
+                .success();
+        }
+        let default = std::fs::read(&default)?;
+        let max = std::fs::read(&max)?;
+        assert!(max.len() < default.len());
+        assert!(
+            pio::png::read(&default, &Default::default())?.data
+                == pio::png::read(&max, &Default::default())?.data
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn limits_png_colors() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--png-colors", "16"])
+            .assert()
+            .success();
+        let mut decoder = lodepng::Decoder::new();
+        decoder.inspect(&std::fs::read(&output)?)?;
+        let color = &decoder.info_png().color;
+        assert!(color.palette().len() <= 16);
+        assert_eq!(color.bitdepth(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn uses_png_dithering() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut images = Vec::new();
+        for algorithm in ["none", "ordered", "floyd-steinberg"] {
+            let output = dir.path().join(format!("{}.png", algorithm));
+            // Fixed quality so that only dithering differs between the outputs.
+            Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&output)
+                .args(["--dither", algorithm, "--dither-strength", "50"])
+                .args(["--min", "40", "--max", "40"])
+                .assert()
+                .success();
+            let mut decoder = lodepng::Decoder::new();
+            decoder.inspect(&std::fs::read(&output)?)?;
+            assert!(decoder.info_png().color.colortype == lodepng::ColorType::PALETTE);
+            images.push(lodepng::decode32_file(&output)?.buffer);
+        }
+        assert!(images[0] != images[1]);
+        assert!(images[0] != images[2]);
+        assert!(images[1] != images[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_grayscale_png_without_palette() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        let pixels: Vec<rgb::RGBA8> = (0..256 * 64)
+            .map(|i| rgb::RGBA8::new(i as u8, i as u8, i as u8, 255))
+            .collect();
+        std::fs::write(&input, lodepng::Encoder::new().encode(&pixels, 256, 64)?)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        let mut decoder = lodepng::Decoder::new();
+        decoder.inspect(&std::fs::read(&output)?)?;
+        assert!(decoder.info_png().color.colortype == lodepng::ColorType::GREY);
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_interlaced_png() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--interlace")
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "Interlacing usually increases PNG file size",
+            ));
+        let mut decoder = lodepng::Decoder::new();
+        decoder.inspect(&std::fs::read(&output)?)?;
+        assert_eq!(decoder.info_png().interlace_method, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn selects_smallest_format_automatically() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output");
+        let pixels: Vec<rgb::RGBA8> = (0..64 * 64)
+            .map(|i| rgb::RGBA8::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 128))
+            .collect();
+        std::fs::write(&input, lodepng::Encoder::new().encode(&pixels, 64, 64)?)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--output-format", "auto"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("selected format:"))
+            .stderr(predicate::str::contains("format: JPEG").not());
+        let format = pio::common::Format::from_magic(&std::fs::read(&output)?);
+        assert!(format.is_some_and(|format| format.supports_transparency()));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_output_format_to_extension() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--output-format", "auto"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "using JPEG to match the extension of the output",
+            ));
+        let format = pio::common::Format::from_magic(&std::fs::read(&output)?);
+        assert_eq!(format, Some(pio::common::Format::JPEG));
+        std::fs::remove_file(&output)?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--output-format", "webp"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "output format WebP doesn't match the extension of the output, which is for JPEG",
+            ));
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn compares_images() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.webp");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("compare")
+            .arg("images/image1-original.png")
+            .arg("images/image1-original.png")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("DSSIM: 0.000000"));
+        Command::cargo_bin("pio")?
+            .arg("compare")
+            .arg("images/image1-original.png")
+            .arg(&output)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("DSSIM: 0.0"))
+            .stdout(predicate::str::contains("bytes (-"));
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_write_output_in_dry_run() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--dry-run")
+            .assert()
+            .success()
+            .stdout(predicate::str::is_match(
+                r"^JPEG quality \d+, 0\.\d+ SSIM, \d+ bytes",
+            )?);
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_ssim_map() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        let map = dir.path().join("map.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--ssim-map")
+            .arg(&map)
+            .assert()
+            .success();
+        let original = lodepng::decode32_file("images/image1-original.png")?;
+        let map = lodepng::decode32_file(&map)?;
+        assert_eq!((map.width, map.height), (original.width, original.height));
+        Ok(())
+    }
+
+    #[test]
+    fn analyzes_image() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--min", "60", "--max", "60"])
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("analyze")
+            .arg(&output)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("format:      JPEG"))
+            .stdout(predicate::str::contains("dimensions:  200x133"))
+            .stdout(predicate::str::contains("color space: RGB"))
+            .stdout(predicate::str::contains(
+                "quantizer:   libjpeg quality 60 (estimated)",
+            ))
+            // Higher quality than the source isn't recommended.
+            .stdout(predicate::str::contains(
+                "recommended: --output-format webp --quality 17",
+            ));
+        Command::cargo_bin("pio")?
+            .args([
+                "--quality",
+                "webp=70",
+                "analyze",
+                "images/image1-original.png",
+            ])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "recommended: --output-format webp --quality 70",
+            ));
+        Ok(())
+    }
+
+    #[test]
+    fn reuses_cached_output() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let cache = dir.path().join("cache");
+        let output1 = dir.path().join("output1.jpg");
+        let output2 = dir.path().join("output2.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output1)
+            .arg("--cache-dir")
+            .arg(&cache)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("using cached output").not());
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output2)
+            .arg("--cache-dir")
+            .arg(&cache)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("using cached output"));
+        assert_eq!(std::fs::read(&output1)?, std::fs::read(&output2)?);
+        // Chroma subsampling of the cached JPEG is reported like after a search.
+        let optimize_json = || -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&output2)
+                .arg("--json")
+                .arg("--cache-dir")
+                .arg(&cache)
+                .assert()
+                .success())
+        };
+        optimize_json()?;
+        optimize_json()?
+            .stderr(predicate::str::contains("using cached output"))
+            .stdout(predicate::str::contains(
+                "\"chroma_subsampling\": \"4:2:0\"",
+            ));
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output2)
+            .arg("--cache-dir")
+            .arg(&cache)
+            .args(["--quality", "70"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("using cached output").not());
+        Ok(())
+    }
+
+    #[test]
+    fn caches_by_quality_curve_contents() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let cache = dir.path().join("cache");
+        let curve = dir.path().join("curve.csv");
+        let output = dir.path().join("output.jpg");
+        let write_curve = |scale: f64| {
+            let mut csv = "quality,dssim\n".to_string();
+            for quality in 0..=100 {
+                csv += &format!("{},{}\n", quality, (101 - quality) as f64 * scale);
+            }
+            std::fs::write(&curve, csv)
+        };
+        let optimize = || -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&output)
+                .arg("--cache-dir")
+                .arg(&cache)
+                .arg("--quality-curve")
+                .arg(&curve)
+                .assert()
+                .success())
+        };
+        write_curve(0.0001)?;
+        optimize()?.stderr(predicate::str::contains("using cached output").not());
+        optimize()?.stderr(predicate::str::contains("using cached output"));
+        // Curve with the same path but different values isn't in the cache.
+        write_curve(0.0002)?;
+        optimize()?.stderr(predicate::str::contains("using cached output").not());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_too_large_image() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--max-pixels", "10000"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "image is too large: 200x133 exceeds limit of 10000 pixels",
+            ));
+        assert!(!output.exists());
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--max-memory", "1M"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("image is too large"));
+        Ok(())
+    }
+
+    #[test]
+    fn stops_search_after_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let cache = dir.path().join("cache");
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--timeout", "0s", "--chroma-subsampling", "444"])
+            .arg("--cache-dir")
+            .arg(&cache)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "search timed out, using the best result so far",
+            ));
+        assert!(output.exists());
+        // Result of the interrupted search isn't cached but timeout doesn't change the cache key.
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--chroma-subsampling", "444"])
+            .arg("--cache-dir")
+            .arg(&cache)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("using cached output").not());
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--timeout", "1m", "--chroma-subsampling", "444"])
+            .arg("--cache-dir")
+            .arg(&cache)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("using cached output"));
+        Ok(())
+    }
+
+    #[test]
+    fn serves_optimized_images() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("pio"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        // Keep stderr open while the server is running because it logs requests.
+        let mut stderr = BufReader::new(server.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line)?;
+        let address = line.trim().strip_prefix("listening on ").unwrap();
+
+        let body = std::fs::read("images/image1-original.png")?;
+        let mut stream = std::net::TcpStream::connect(address)?;
+        write!(
+            stream,
+            "POST /?quality=80&format=webp HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        server.kill()?;
+
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: image/webp\r\n"));
+        assert!(response.contains("\r\n\r\nRIFF"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_large_request_bodies() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("pio"))
+            .args(["serve", "--listen", "127.0.0.1:0", "--max-body-size", "1K"])
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let mut stderr = BufReader::new(server.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line)?;
+        let address = line.trim().strip_prefix("listening on ").unwrap();
+
+        let mut stream = std::net::TcpStream::connect(address)?;
+        write!(stream, "POST / HTTP/1.1\r\nContent-Length: 1025\r\n\r\n")?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        server.kill()?;
+
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn answers_health_checks_and_finishes_requests_on_sigterm(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("pio"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let mut stderr = BufReader::new(server.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line)?;
+        let address = line
+            .trim()
+            .strip_prefix("listening on ")
+            .unwrap()
+            .to_string();
+        let get = |path: &str| -> Result<String, Box<dyn std::error::Error>> {
+            let mut stream = std::net::TcpStream::connect(&address)?;
+            write!(stream, "GET {} HTTP/1.1\r\n\r\n", path)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        assert!(get("/healthz")?.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/readyz")?.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let body = std::fs::read("images/image1-original.png")?;
+        let mut stream = std::net::TcpStream::connect(&address)?;
+        write!(
+            stream,
+            "POST /?format=webp HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body[..body.len() / 2])?;
+        // Connections are accepted in order, so the request above is in progress once this one
+        // has been answered.
+        get("/healthz")?;
+        unsafe {
+            libc::kill(server.id() as libc::pid_t, libc::SIGTERM);
+        }
+        stream.write_all(&body[body.len() / 2..])?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        assert!(server.wait()?.success());
+
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: image/webp\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn reads_defaults_from_config_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        std::fs::write(
+            dir.path().join("pio.toml"),
+            "output-format = \"webp\"\nquality = 70\ndry-run = true\n",
+        )?;
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("images/image1-original.png");
+        Command::cargo_bin("pio")?
+            .current_dir(dir.path())
+            .arg(&input)
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("WebP quality"));
+        Command::cargo_bin("pio")?
+            .current_dir(dir.path())
+            .arg(&input)
+            .args(["--output-format", "jpeg"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("JPEG quality"));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_format_defaults_from_config_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        std::fs::write(
+            dir.path().join("pio.toml"),
+            "dry-run = true\n[webp]\nquality = 40\nspread = 0\n",
+        )?;
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("images/image1-original.png");
+        Command::cargo_bin("pio")?
+            .current_dir(dir.path())
+            .arg(&input)
+            .args(["--output-format", "webp"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("WebP quality 40,"));
+        Command::cargo_bin("pio")?
+            .current_dir(dir.path())
+            .arg(&input)
+            .args(["--output-format", "jpeg", "--spread", "0"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("JPEG quality 85,"));
+        Command::cargo_bin("pio")?
+            .current_dir(dir.path())
+            .arg(&input)
+            .args(["--output-format", "webp", "--quality", "60"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("WebP quality 60,"));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_calibrated_quality_curve() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let curve = dir.path().join("curve.csv");
+        let output = Command::cargo_bin("pio")?
+            .args(["calibrate", "--format", "png", "images/image1-original.png"])
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout.split(|b| *b == b'\n').count(), 103);
+        std::fs::write(&curve, &output.stdout)?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "png", "--dry-run", "--quality-curve"])
+            .arg(&curve)
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("PNG quality"));
+        Ok(())
+    }
+
+    #[test]
+    fn prints_calibrated_table_as_rust() -> Result<(), Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin("pio")?
+            .args([
+                "calibrate",
+                "--format",
+                "jpeg",
+                "--average",
+                "mean",
+                "--output",
+                "rust",
+            ])
+            .args(["images/image1-original.png", "images/image2-original.png"])
+            .output()?;
+        assert!(output.status.success());
+        let table = String::from_utf8(output.stdout)?;
+        assert!(
+            table.starts_with("#[rustfmt::skip]\npub const QUALITY_SSIM_JPEG: [f64; 101] = [\n")
+        );
+        assert!(table.ends_with("\n];\n"));
+        assert!(table.lines().all(|line| line.len() <= 100));
+        let values: Vec<f64> = table
+            .lines()
+            .filter(|line| line.starts_with("    "))
+            .flat_map(|line| line.split(',').map(str::trim).filter(|v| !v.is_empty()))
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(values.len(), 101);
+        assert!(values.windows(2).all(|pair| pair[0] >= pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn stops_search_when_candidate_is_larger_than_input() -> Result<(), Box<dyn std::error::Error>>
+    {
+        Command::cargo_bin("pio")?
+            .arg("images/image-subsampling-test.png")
+            .args(["--output-format", "webp", "--dry-run"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "candidate is larger than the original, stopping search",
+            ))
+            .stdout(predicate::str::starts_with("WebP lossless,"));
+        Ok(())
+    }
+
+    #[test]
+    fn skips_optimized_input() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let marked = dir.path().join("marked.png");
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&marked)
+            .arg("--mark-optimized")
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg(&marked)
+            .arg("-o")
+            .arg(&output)
+            .arg("--skip-optimized")
+            .assert()
+            .code(4)
+            .stderr(predicate::str::starts_with(
+                "input is already optimized (pio ",
+            ));
+        assert_eq!(std::fs::read(&marked)?, std::fs::read(&output)?);
+
+        // Skipped input isn't rewritten in place.
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(&marked)?.ino()
+        };
+        Command::cargo_bin("pio")?
+            .arg(&marked)
+            .args(["--in-place", "--backup", "--skip-optimized"])
+            .assert()
+            .code(4);
+        assert!(!dir.path().join("marked.png.orig").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(std::fs::metadata(&marked)?.ino(), inode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn skips_input_optimized_with_same_options() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let marked = dir.path().join("marked.jpeg");
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&marked)
+            .arg("--mark-optimized")
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg(&marked)
+            .arg("-o")
+            .arg(&output)
+            .arg("--mark-optimized")
+            .assert()
+            .code(4)
+            .stderr(predicate::str::starts_with(
+                "input is already optimal with the same options (pio ",
+            ));
+        assert_eq!(std::fs::read(&marked)?, std::fs::read(&output)?);
+        Command::cargo_bin("pio")?
+            .arg(&marked)
+            .arg("-o")
+            .arg(&output)
+            .args(["--mark-optimized", "--quality", "60"])
+            .assert()
+            .success();
+        Ok(())
+    }
+
+    #[test]
+    fn copies_input_when_output_is_larger() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output");
+        Command::cargo_bin("pio")?
+            .arg("images/image-subsampling-test.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--output-format", "jpeg", "--optimization_failed", "copy"])
+            .assert()
+            .code(3);
+        assert_eq!(
+            std::fs::read("images/image-subsampling-test.png")?,
+            std::fs::read(&output)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn copies_input_when_output_saves_too_little() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--optimization_failed", "copy", "--min-savings", "90%"])
+            .assert()
+            .code(3)
+            .stderr(predicate::str::contains(
+                "Output would save less than `--min-savings`",
+            ));
+        assert_eq!(
+            std::fs::read("images/image1-original.png")?,
+            std::fs::read(&output)?
+        );
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--optimization_failed", "copy", "--min-savings", "10K"])
+            .assert()
+            .success();
+        assert!(std::fs::metadata(&output)?.len() < 21411 - 10 * 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn preserves_attributes_in_place() -> Result<(), Box<dyn std::error::Error>> {
+        #[cfg(unix)]
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempdir()?;
+        let path = dir.path().join("image.png");
+        std::fs::copy("images/image1-original.png", &path)?;
+        #[cfg(unix)]
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(mtime)?;
+        Command::cargo_bin("pio")?
+            .arg(&path)
+            .arg("--in-place")
+            .assert()
+            .success();
+        let metadata = std::fs::metadata(&path)?;
+        assert!(metadata.len() < 21411);
+        #[cfg(unix)]
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(metadata.modified()?, mtime);
+        Command::cargo_bin("pio")?
+            .arg(&path)
+            .args(["--in-place", "--no-preserve-attributes"])
+            .assert()
+            .success();
+        assert!(std::fs::metadata(&path)?.modified()? > mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn preserves_times() -> Result<(), Box<dyn std::error::Error>> {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        std::fs::copy("images/image1-original.png", &input)?;
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&input)?
+            .set_modified(mtime)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--preserve-times")
+            .assert()
+            .success();
+        assert_eq!(std::fs::metadata(&output)?.modified()?, mtime);
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("--output-dir")
+            .arg(dir.path().join("out"))
+            .arg("--preserve-times")
+            .assert()
+            .success();
+        let output = dir.path().join("out/input.png");
+        assert_eq!(std::fs::metadata(&output)?.modified()?, mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_output_with_suffix() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        std::fs::copy("images/image1-original.png", &input)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--suffix", ".min"])
+            .assert()
+            .success();
+        assert!(dir.path().join("input.min.png").is_file());
+        assert_eq!(
+            std::fs::read(&input)?,
+            std::fs::read("images/image1-original.png")?
+        );
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--suffix", "", "--force"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("`--suffix` can't be empty"));
+        assert_eq!(
+            std::fs::read(&input)?,
+            std::fs::read("images/image1-original.png")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn handles_existing_output() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let run = || {
+            let mut command = Command::cargo_bin("pio").unwrap();
+            command
+                .arg("images/image1-original.png")
+                .arg("--output-dir")
+                .arg(dir.path())
+                .args(["--output-format", "png"]);
+            command
+        };
+        run().assert().success();
+        run()
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("already exists"));
+        run()
+            .arg("--skip-existing")
+            .assert()
+            .code(4)
+            .stderr(predicate::str::contains("writing").not());
+        run()
+            .arg("--force")
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("writing"));
+        Ok(())
+    }
+
+    #[test]
+    fn backs_up_in_place_input() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("image.png");
+        let original = std::fs::read("images/image1-original.png")?;
+        std::fs::write(&path, &original)?;
+        Command::cargo_bin("pio")?
+            .arg("--backup")
+            .arg(&path)
+            .arg("--in-place")
+            .assert()
+            .success();
+        assert_eq!(std::fs::read(dir.path().join("image.png.orig"))?, original);
+        assert!(std::fs::metadata(&path)?.len() < original.len() as u64);
+        // Running the same command again keeps the first backup.
+        Command::cargo_bin("pio")?
+            .arg("--backup")
+            .arg(&path)
+            .arg("--in-place")
+            .assert()
+            .success();
+        assert_eq!(std::fs::read(dir.path().join("image.png.orig"))?, original);
+        Command::cargo_bin("pio")?
+            .arg(&path)
+            .args(["--in-place", "--backup=.bak"])
+            .assert()
+            .success();
+        assert!(dir.path().join("image.png.bak").is_file());
+        let optimized = std::fs::read(&path)?;
+        Command::cargo_bin("pio")?
+            .arg(&path)
+            .args(["--in-place", "--backup="])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("backup suffix can't be empty"));
+        assert_eq!(std::fs::read(&path)?, optimized);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_output_dir_using_template() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("--output-dir")
+            .arg(dir.path())
+            .args([
+                "--template",
+                "{format}/{stem}-{width}x{height}-q{quality}.{ext}",
+            ])
+            .args(["--output-format", "webp", "--spread", "0"])
+            .assert()
+            .success();
+        assert!(dir
+            .path()
+            .join("webp/image1-original-200x133-q85.webp")
+            .is_file());
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("--output-dir")
+            .arg(dir.path())
+            .args(["--template", "{size}"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "unknown placeholder `{size}` in template",
+            ));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_multiple_output_formats() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        // Soft circular shadow.
+        let pixels: Vec<rgb::RGBA8> = (0..64 * 64)
+            .map(|i| {
+                let distance = ((i % 64) as f32 - 32.0).hypot((i / 64) as f32 - 32.0);
+                let alpha = 255.0 * (1.0 - distance / 32.0).max(0.0);
+                rgb::RGBA8::new(200, 50, 50, alpha as u8)
+            })
+            .collect();
+        std::fs::write(&input, lodepng::encode32(&pixels, 64, 64)?)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("--output-dir")
+            .arg(dir.path().join("output"))
+            .args(["--output-formats", "jpeg,png"])
+            .assert()
+            .success();
+        assert!(dir.path().join("output/input.jpeg").is_file());
+        // Only JPEG output is blended with the background color.
+        let png = lodepng::decode32_file(dir.path().join("output/input.png"))?;
+        assert!(png.buffer.iter().any(|pixel| pixel.a < 255));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_exif_orientation() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpeg");
+        let output = dir.path().join("output.jpeg");
+        let jpeg = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "jpeg"])
+            .output()?
+            .stdout;
+        // Exif orientation for image rotated 90 degrees clockwise.
+        let mut writer = exif::experimental::Writer::new();
+        let field = exif::Field {
+            tag: exif::Tag::Orientation,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Short(vec![6]),
+        };
+        writer.push_field(&field);
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false)?;
+        let app1 = [b"Exif\0\0".as_slice(), tiff.get_ref()].concat();
+        let length = (app1.len() + 2) as u16;
+        std::fs::write(
+            &input,
+            [
+                &jpeg[..2],
+                &[0xff, 0xe1],
+                &length.to_be_bytes(),
+                &app1,
+                &jpeg[2..],
+            ]
+            .concat(),
+        )?;
+        for (options, dimensions, orientation) in [
+            (&["--orientation", "bake"][..], (133, 200), None),
+            (&["--orientation", "keep"][..], (200, 133), Some(6)),
+            (&["--no-auto-orient"][..], (200, 133), None),
+        ] {
+            Command::cargo_bin("pio")?
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .args(options)
+                .assert()
+                .success();
+            let data = std::fs::read(&output)?;
+            assert_eq!(pio::jpeg::dimensions(&data), Some(dimensions));
+            let exif = exif::Reader::new()
+                .read_from_container(&mut std::io::Cursor::new(&data))
+                .ok();
+            assert_eq!(
+                exif.as_ref().and_then(pio::common::exif_orientation),
+                orientation
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_and_flips_image() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.png");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .args(["--rotate", "90", "--flip", "h", "--lossless"])
+            .assert()
+            .success();
+        let input = lodepng::decode32_file("images/image1-original.png")?;
+        let output = lodepng::decode32_file(&output)?;
+        assert_eq!((output.width, output.height), (133, 200));
+        // Rotating clockwise and flipping horizontally transposes the image.
+        for (x, y) in [(0, 0), (10, 20), (199, 132)] {
+            assert_eq!(
+                input.buffer[y * input.width + x],
+                output.buffer[x * output.width + y]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uses_edge_color_as_background() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        // Dark frame with a transparent hole.
+        let pixels: Vec<rgb::RGBA8> = (0..64 * 64)
+            .map(|i| {
+                let hole = (8..56).contains(&(i % 64)) && (8..56).contains(&(i / 64));
+                rgb::RGBA8::new(20, 30, 40, if hole { 0 } else { 255 })
+            })
+            .collect();
+        std::fs::write(&input, lodepng::encode32(&pixels, 64, 64)?)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--background-color", "auto", "--no-transparency"])
+            .assert()
+            .success();
+        let output = lodepng::decode32_file(&output)?;
+        let center = output.buffer[32 * 64 + 32];
+        assert_eq!(center.a, 255);
+        assert!(
+            center.r.abs_diff(20) <= 4 && center.g.abs_diff(30) <= 4 && center.b.abs_diff(40) <= 4
+        );
+        // Empty image has no edge.
+        for (width, height) in [(0, 0), (16, 0), (0, 16)] {
+            let image = pio::common::Image::from_rgba(Vec::new(), width, height);
+            assert_eq!(image.edge_color(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_css_colors() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        let pixels = vec![rgb::RGBA8::new(0, 0, 0, 0); 16 * 16];
+        std::fs::write(&input, lodepng::encode32(&pixels, 16, 16)?)?;
+        for (color, expected) in [
+            ("RebeccaPurple", rgb::RGBA8::new(102, 51, 153, 255)),
+            ("rgb(10, 20, 30)", rgb::RGBA8::new(10, 20, 30, 255)),
+            ("rgba(10 20 30 / 50%)", rgb::RGBA8::new(10, 20, 30, 255)),
+            ("#abc", rgb::RGBA8::new(0xaa, 0xbb, 0xcc, 255)),
+        ] {
+            Command::cargo_bin("pio")?
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .args(["--background-color", color, "--no-transparency"])
+                .assert()
+                .success();
+            let output = lodepng::decode32_file(&output)?;
+            assert_eq!(output.buffer[0], expected, "{}", color);
+        }
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--background-color", "transparentish"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("unknown color `transparentish`"));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_rendering_intent() -> Result<(), Box<dyn std::error::Error>> {
+        use pio::profile::RenderingIntent;
+        use std::io::Write;
+
+        // Media white point is only used with absolute intent.
+        let xy = |x, y| lcms2::CIExyY { x, y, Y: 1.0 };
+        let mut profile = lcms2::Profile::new_rgb(
+            &xy(0.3127, 0.3290),
+            &lcms2::CIExyYTRIPLE {
+                Red: xy(0.64, 0.33),
+                Green: xy(0.30, 0.60),
+                Blue: xy(0.15, 0.06),
+            },
+            &[&lcms2::ToneCurve::new(2.2); 3],
+        )?;
+        // Media white point of illuminant A.
+        profile.write_tag(
+            lcms2::TagSignature::MediaWhitePointTag,
+            lcms2::Tag::CIEXYZ(&lcms2::CIEXYZ {
+                X: 1.0985,
+                Y: 1.0,
+                Z: 0.3558,
+            }),
+        );
+        let profile = profile.icc()?;
+        let mut iccp = flate2::write::ZlibEncoder::new(
+            b"Illuminant A\0\0".to_vec(),
+            flate2::Compression::default(),
+        );
+        iccp.write_all(&profile)?;
+        let mut encoder = lodepng::Encoder::new();
+        encoder.info_png_mut().create_chunk(
+            lodepng::ChunkPosition::IHDR,
+            b"iCCP",
+            &iccp.finish()?,
+        )?;
+        let png = encoder.encode(&[rgb::RGBA8::new(255, 255, 255, 255); 16], 4, 4)?;
+
+        let white = |intent| -> Result<rgb::RGBA8, String> {
+            let options = pio::common::ReadOptions {
+                rendering_intent: intent,
+                ..Default::default()
+            };
+            Ok(pio::png::read(&png, &options)?.data[0])
+        };
+        assert_eq!(
+            white(RenderingIntent::Relative)?,
+            rgb::RGBA8::new(255, 255, 255, 255)
+        );
+        assert_ne!(
+            white(RenderingIntent::Absolute)?,
+            rgb::RGBA8::new(255, 255, 255, 255)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn uses_black_point_compensation() -> Result<(), Box<dyn std::error::Error>> {
+        use pio::profile::RenderingIntent;
+        use std::io::Write;
+
+        // Profile with raised black point.
+        let xy = |x, y| lcms2::CIExyY { x, y, Y: 1.0 };
+        let curve = lcms2::ToneCurve::new_parametric(2, &[2.2, 0.8, 0.2])?;
+        let profile = lcms2::Profile::new_rgb(
+            &xy(0.3127, 0.3290),
+            &lcms2::CIExyYTRIPLE {
+                Red: xy(0.64, 0.33),
+                Green: xy(0.30, 0.60),
+                Blue: xy(0.15, 0.06),
+            },
+            &[&curve; 3],
+        )?
+        .icc()?;
+        let mut iccp =
+            flate2::write::ZlibEncoder::new(b"Faded\0\0".to_vec(), flate2::Compression::default());
+        iccp.write_all(&profile)?;
+        let mut encoder = lodepng::Encoder::new();
+        encoder.info_png_mut().create_chunk(
+            lodepng::ChunkPosition::IHDR,
+            b"iCCP",
+            &iccp.finish()?,
+        )?;
+        let png = encoder.encode(&[rgb::RGBA8::new(0, 0, 0, 255); 16], 4, 4)?;
+
+        let black = |black_point_compensation| -> Result<rgb::RGBA8, String> {
+            let options = pio::common::ReadOptions {
+                rendering_intent: RenderingIntent::Relative,
+                black_point_compensation,
+                ..Default::default()
+            };
+            Ok(pio::png::read(&png, &options)?.data[0])
+        };
+        assert!(black(false)?.g > 10);
+        assert_eq!(black(true)?, rgb::RGBA8::new(0, 0, 0, 255));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_chroma_subsampling_of_jpeg_input() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpg");
+        let output = dir.path().join("output.jpg");
+        for (input_image, sampling) in [
+            ("images/image-subsampling-test.png", "420"),
+            ("images/biandintz-eta-zaldiak.png", "444"),
+            ("images/image1-original.png", "440"),
+            ("images/image1-original.png", "411"),
+        ] {
+            Command::cargo_bin("pio")?
+                .arg(input_image)
+                .arg("-o")
+                .arg(&input)
+                .args(["--chroma-subsampling", sampling, "--quality", "95"])
+                .assert()
+                .success();
+            Command::cargo_bin("pio")?
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .assert()
+                .success();
+            let output = std::fs::read(&output)?;
+            assert_eq!(
+                format!("{:?}", pio::jpeg::chroma_subsampling(&output)),
+                format!("Some(_{})", sampling)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lowers_chroma_quality() -> Result<(), Box<dyn std::error::Error>> {
+        let size = |offset: &str| -> Result<usize, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .args(["--output-format", "jpeg", "--min", "80", "--max", "80"])
+                .args(["--chroma-quality-offset", offset])
+                .output()?;
+            assert!(output.status.success());
+            Ok(output.stdout.len())
+        };
+        assert!(size("-40")? < size("0")?);
+        Ok(())
+    }
+
+    #[test]
+    fn quantizes_regions_with_different_quality() -> Result<(), Box<dyn std::error::Error>> {
+        let size = |options: &[&str]| -> Result<usize, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .args(["--output-format", "jpeg", "--min", "70", "--max", "70"])
+                .args(options)
+                .output()?;
+            assert!(output.status.success());
+            Ok(output.stdout.len())
+        };
+        let size_without_roi = size(&[])?;
+        let lower_quality = size(&["--roi", "0,0,200,133:-20"])?;
+        assert!(size(&["--roi", "0,0,100,60:+20"])? > size_without_roi);
+        assert!(lower_quality < size_without_roi);
+        // Later region takes precedence.
+        assert!(size(&["--roi", "0,0,200,133:-20", "--roi", "0,0,100,60:+0"])? > lower_quality);
+        Ok(())
+    }
+
+    #[cfg(feature = "saliency")]
+    #[test]
+    fn detects_salient_region() {
+        // Dark square on a flat background.
+        let data = (0..256 * 256)
+            .map(|i| {
+                let inside = (160..208).contains(&(i % 256)) && (32..80).contains(&(i / 256));
+                let value = if inside { 0 } else { 200 };
+                rgb::RGBA8::new(value, value, value, 255)
+            })
+            .collect();
+        let image = pio::common::Image::from_rgba(data, 256, 256);
+        let rect = pio::saliency::salient_region(&image).unwrap();
+        assert!(rect.x <= 176 && rect.x + rect.width >= 192);
+        assert!(rect.y <= 48 && rect.y + rect.height >= 64);
+        assert!(rect.width * rect.height < 128 * 128);
+    }
+
+    #[test]
+    fn compares_downscaled_images() {
+        let image = |pixel: &dyn Fn(usize) -> u8| {
+            let data = (0..64 * 64)
+                .map(|i| rgb::RGBA8::new(pixel(i), pixel(i), pixel(i), 255))
+                .collect();
+            pio::common::Image::from_rgba(data, 64, 64)
+        };
+        // Checkerboard with one pixel squares looks flat when downscaled.
+        let original = image(&|i| if (i % 64 + i / 64) % 2 == 0 { 0 } else { 255 });
+        let compressed = image(&|_| 128);
+        let full = pio::ssim::Calculator::new(&original).unwrap();
+        let fast = pio::ssim::Calculator::downscaled(&original, 2).unwrap();
+        assert!(fast.compare(&compressed).unwrap() < full.compare(&compressed).unwrap() / 10.0);
+    }
+
+    #[test]
+    fn skips_search_when_max_quality_is_larger() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.webp");
+        let output = dir.path().join("output.webp");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&input)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .args(["--optimization_failed", "copy"])
+            .assert()
+            .code(3)
+            .stderr(predicate::str::contains(
+                "is not smaller than the original, skipping search",
+            ));
+        assert_eq!(std::fs::read(&input)?, std::fs::read(&output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn uses_quality_of_output_format() -> Result<(), Box<dyn std::error::Error>> {
+        let dry_run = |quality: &str| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .args(["--output-format", "jpeg", "--dry-run", "--quality", quality])
+                .output()?;
+            assert!(output.status.success());
+            Ok(output.stdout)
+        };
+        assert_eq!(dry_run("jpeg=50,webp=95")?, dry_run("50")?);
+        assert_eq!(dry_run("50,jpeg=85")?, dry_run("85")?);
+        assert_ne!(dry_run("50,webp=85")?, dry_run("85")?);
+        Ok(())
+    }
+
+    #[test]
+    fn uses_search_strategy() -> Result<(), Box<dyn std::error::Error>> {
+        let dry_run = |search: &str| -> Result<String, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .args(["--output-format", "jpeg", "--dry-run", "--search", search])
+                .args(["--quality", "70", "--chroma-subsampling", "420"])
+                .output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stdout)?)
+        };
+        let binary = dry_run("binary")?;
+        assert_eq!(dry_run("linear")?, binary);
+        assert_eq!(dry_run("golden")?, binary);
+        assert!(dry_run("fixed")?.starts_with("JPEG quality 70, SSIM not compared,"));
+        Ok(())
+    }
+
+    #[test]
+    fn prints_rate_distortion_curve() -> Result<(), Box<dyn std::error::Error>> {
+        let curve = |output: &str| -> Result<String, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .args(["curve", "images/image1-original.png", "--format", "webp"])
+                .args(["--output", output])
+                .output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stdout)?)
+        };
+        let csv = curve("csv")?;
+        let rows: Vec<Vec<&str>> = csv.lines().map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), 102);
+        assert_eq!(rows[0], ["quality", "dssim", "size"]);
+        let (low, high) = (&rows[1], &rows[101]);
+        assert!(low[1].parse::<f64>()? > high[1].parse::<f64>()?);
+        assert!(low[2].parse::<usize>()? < high[2].parse::<usize>()?);
+        let json = curve("json")?;
+        assert!(json.starts_with('['));
+        assert_eq!(json.matches("\"quality\"").count(), 101);
+        assert!(json.contains(&format!(
+            "{{\"quality\": 100, \"dssim\": {}, \"size\": {}}}",
+            high[1], high[2]
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn predicts_chroma_subsampling() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "jpeg", "--dry-run"])
+            .args(["--chroma-subsampling", "auto"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("chroma subsampling: _420"))
+            .stderr(predicate::str::contains("chroma subsampling: _444").not());
+        Command::cargo_bin("pio")?
+            .arg("images/image-subsampling-test.png")
+            .args(["--output-format", "jpeg", "--dry-run"])
+            .args(["--chroma-subsampling", "auto"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("chroma subsampling: _444"))
+            .stderr(predicate::str::contains("chroma subsampling: _420").not());
+        Ok(())
+    }
+
+    #[test]
+    fn stops_chroma_subsampling_search_early() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .arg("images/image3-original.png")
+            .args(["--output-format", "jpeg", "--dry-run", "--min", "0"])
+            .args(["--max", "100", "--quality", "70"])
+            .args(["--chroma-subsampling", "auto"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "chroma subsampling _420 is close to the target and smallest at quality 41, skipping search of others",
+            ))
+            .stderr(predicate::str::contains("chroma subsampling: _444").not());
+        Ok(())
+    }
+
+    #[test]
+    fn appends_report_rows() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let report = dir.path().join("report.csv");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(dir.path().join("output.jpg"))
+            .args(["--chroma-subsampling", "420", "--report"])
+            .arg(&report)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/missing.png")
+            .arg("-o")
+            .arg(dir.path().join("output.jpg"))
+            .arg("--report")
+            .arg(&report)
+            .assert()
+            .failure();
+        let report = std::fs::read_to_string(&report)?;
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "path,format,input_size,output_size,quality,chroma_subsampling,dssim,status,peak_memory"
+        );
+        let row: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(row[..3], ["images/image1-original.png", "JPEG", "21411"]);
+        assert_eq!(
+            row[3].parse::<u64>()?,
+            std::fs::metadata(dir.path().join("output.jpg"))?.len()
+        );
+        assert_eq!(row[5], "4:2:0");
+        assert!(row[6].parse::<f64>()? > 0.0);
+        assert_eq!(row[7], "optimized");
+        assert_eq!(row[8], "");
+        assert_eq!(lines[2], "images/missing.png,,,,,,,failed,");
+        assert_eq!(lines.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn prints_summary() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpg");
+        let assert = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+        let stderr = String::from_utf8(assert.get_output().stderr.clone())?;
+        let summary = format!(
+            "optimized: 21411 bytes -> JPEG {} bytes (",
+            std::fs::metadata(&output)?.len()
+        );
+        assert!(stderr.lines().last().unwrap().starts_with(&summary));
+        Ok(())
+    }
+
+    #[test]
+    fn benchmarks_formats() -> Result<(), Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin("pio")?
+            .args([
+                "bench",
+                "images/image1-original.png",
+                "images/image2-original.png",
+            ])
+            .args(["--formats", "jpeg,png", "--qualities", "50,90"])
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        let rows: Vec<Vec<&str>> = stdout
+            .lines()
+            .map(|line| line.split(',').collect())
+            .collect();
+        assert_eq!(
+            rows[0],
+            [
+                "format",
+                "quality",
+                "encode_ms",
+                "size",
+                "percent_of_input",
+                "dssim"
+            ]
+        );
+        let settings: Vec<(&str, &str)> = rows[1..].iter().map(|row| (row[0], row[1])).collect();
+        assert_eq!(
+            settings,
+            [("JPEG", "50"), ("JPEG", "90"), ("PNG", "50"), ("PNG", "90")]
+        );
+        // Higher quality is larger and closer to the original.
+        for pair in rows[1..].chunks(2) {
+            assert!(pair[0][3].parse::<usize>()? < pair[1][3].parse::<usize>()?);
+            assert!(pair[0][5].parse::<f64>()? > pair[1][5].parse::<f64>()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn optimizes_with_optimize_subcommand() -> Result<(), Box<dyn std::error::Error>> {
+        let dry_run = |args: &[&str]| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .args(args)
+                .args([
+                    "images/image1-original.png",
+                    "--output-format",
+                    "jpeg",
+                    "--dry-run",
+                ])
+                .output()?;
+            assert!(output.status.success());
+            Ok(output.stdout)
+        };
+        assert_eq!(dry_run(&["optimize"])?, dry_run(&[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn prints_shell_completions() -> Result<(), Box<dyn std::error::Error>> {
+        for shell in ["bash", "zsh", "fish", "powershell"] {
+            Command::cargo_bin("pio")?
+                .args(["completions", shell])
+                .assert()
+                .success()
+                .stdout(predicate::str::contains("chroma-subsampling"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uses_input_format() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("pio")?
+            .args([
+                "--input-format",
+                "png",
+                "--output-format",
+                "jpeg",
+                "--dry-run",
+            ])
+            .pipe_stdin("images/image1-original.png")?
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .args([
+                "--input-format",
+                "webp",
+                "--output-format",
+                "jpeg",
+                "--dry-run",
+            ])
+            .pipe_stdin("images/image1-original.png")?
+            .assert()
+            .failure();
+        Ok(())
+    }
+
+    #[test]
+    fn optimizes_images_in_archive() -> Result<(), Box<dyn std::error::Error>> {
+        use std::fs::File;
+        use std::io::{Read, Write};
+
+        let dir = tempdir()?;
+        let input = dir.path().join("input.zip");
+        let output = dir.path().join("output.zip");
+        let image = std::fs::read("images/image1-original.png")?;
+        let mut writer = zip::ZipWriter::new(File::create(&input)?);
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        writer.start_file("images/image.png", stored)?;
+        writer.write_all(&image)?;
+        writer.start_file("readme.txt", Default::default())?;
+        writer.write_all(b"not an image")?;
+        writer.finish()?;
+
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--archive", "-o"])
+            .arg(&output)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("[1/1] images/image.png\n"))
+            .stderr(predicate::str::contains(
+                "optimized 1 of 1 images in the archive",
+            ))
+            .stderr(predicate::str::contains("chroma subsampling").not());
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--archive", "--dry-run", "--verbose"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("chroma subsampling"));
+
+        let mut archive = zip::ZipArchive::new(File::open(&output)?)?;
+        assert_eq!(archive.len(), 2);
+        let mut optimized = Vec::new();
+        let mut file = archive.by_name("images/image.png")?;
+        assert_eq!(file.compression(), zip::CompressionMethod::Stored);
+        file.read_to_end(&mut optimized)?;
+        drop(file);
+        assert!(optimized.starts_with(b"\x89PNG"));
+        assert!(optimized.len() < image.len());
+        let mut text = String::new();
+        archive.by_name("readme.txt")?.read_to_string(&mut text)?;
+        assert_eq!(text, "not an image");
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn reads_input_from_url() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/image.png", listener.local_addr()?);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let body = std::fs::read("images/image1-original.png").unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        Command::cargo_bin("pio")?
+            .arg(&url)
+            .args(["--output-format", "jpeg", "--dry-run"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("JPEG quality"));
+        Ok(())
     }
 
-    // Try lossless compression if the format supports it. For example, lossless WebP can sometimes
-    // be smaller than lossy WebP for non-photographic images.
-    if let Some(compress) = lossless_compress {
-        eprint!("|                        |");
-        let (_, b) = compress(&image)?;
-        eprintln!(
-            "    lossless  0.000000 SSIM  {:>3} % of original",
-            100 * b.len() as u64 / original_size
+    #[test]
+    fn converts_dynamic_image() {
+        let gray = image::DynamicImage::ImageLuma8(
+            image::GrayImage::from_raw(2, 1, vec![0, 255]).unwrap(),
         );
-        if b.len() < best_buffer.len() {
-            return Ok(b);
-        }
+        let image = pio::common::Image::from(gray.clone());
+        assert!(image.width == 2 && image.height == 1);
+        assert!(image.color_space == pio::common::ColorSpace::Gray);
+        assert_eq!(image::DynamicImage::from(image), gray);
     }
 
-    Ok(best_buffer)
-}
+    #[test]
+    fn reads_raw_pixels_with_stride() {
+        use pio::common::Image;
 
-fn pio(args: Args) -> Result<(), String> {
-    let target = QUALITY_SSIM[args.quality as usize];
+        let data = [255, 0, 0, 0, 255, 0, 9, 9, 0, 0, 255, 255, 255, 255, 9, 9];
+        let image = Image::from_raw_rgb(&data, 2, 2, 8).unwrap();
+        assert_eq!(image.data[1], rgb::RGBA8::new(0, 255, 0, 255));
+        assert_eq!(image.data[2], rgb::RGBA8::new(0, 0, 255, 255));
+        assert!(Image::from_raw_rgba(&data, 2, 2, 8).is_ok());
+        assert!(Image::from_raw_rgba(&data, 2, 3, 8).is_err());
+        assert!(Image::from_raw_rgb(&data, 3, 2, 8).is_err());
+    }
 
-    let min = args
-        .min
-        .unwrap_or_else(|| args.quality.saturating_sub(args.spread));
-    let max = args
-        .max
-        .unwrap_or_else(|| std::cmp::min(args.quality + args.spread, 100));
-    if min > max {
-        return Err("value of `--min` must be less or equal to value of `--max`".to_string());
+    #[test]
+    fn returns_optimize_result() {
+        use pio::common::{ChromaSubsampling, ChromaSubsamplingOption, Format, Image};
+        use pio::search::{QualitySearch, SearchStrategy};
+
+        let data = (0..64 * 64)
+            .map(|i| rgb::RGBA8::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255))
+            .collect();
+        let image = Image::from_rgba(data, 64, 64);
+        let metric = pio::ssim::Calculator::new(&image).unwrap();
+        let search = QualitySearch {
+            target: 0.001,
+            min_quality: 0,
+            max_quality: 100,
+            chroma_subsampling: ChromaSubsamplingOption::Manual(ChromaSubsampling::_420),
+            metric: &metric,
+            lossy: Box::new(|img, q, cs| {
+                pio::jpeg::compress(img, q, cs, &pio::jpeg::CompressOptions::default())
+            }),
+            fixed: Vec::new(),
+            original_size: 64 * 64 * 4,
+            check_max_quality: false,
+            curve: None,
+            strategy: SearchStrategy::Binary,
+            pool: None,
+        };
+        let cancel = pio::cancel::CancellationToken::new();
+        let result = search
+            .optimize(&image, Format::JPEG, &cancel, &mut |_| {})
+            .unwrap();
+        assert_eq!(result.format, Format::JPEG);
+        assert!(result.quality.is_some());
+        assert!(matches!(
+            result.chroma_subsampling,
+            Some(ChromaSubsampling::_420)
+        ));
+        assert!(result.iterations > 0 && result.iterations <= 7);
+        assert_eq!(result.trials.len(), result.iterations);
+        assert_eq!(result.input_size, 64 * 64 * 4);
+        assert!(!result.lossless_used);
+        assert!(result.bytes.starts_with(&[0xff, 0xd8]));
     }
 
-    let (input_format, input_buffer) = {
-        let mut reader: Box<dyn std::io::Read> = match &args.input {
-            None => {
-                if args.output.is_none() && args.output_format.is_none() {
-                    return Err("reading from standard input, use `--output` to write to a file or `--output-format` to write to standard output".to_string());
-                }
-                Box::new(std::io::stdin())
-            }
-            Some(path) => Box::new(
-                File::open(path).map_err(|err| format!("failed to open input file: {}", err))?,
-            ),
+    #[test]
+    fn searches_420_chroma_subsampling_first() {
+        use pio::common::{ChromaSubsampling, ChromaSubsamplingOption, Image};
+        use pio::search::{QualitySearch, SearchStrategy};
+
+        // Color noise with chroma subsampling error between 4 and 20 for all subsamplings, so
+        // that all of them are searched.
+        let data = (0..64 * 64)
+            .map(|i| {
+                let (x, y) = (i % 64, i / 64);
+                let r = 128 + ((x * 7 + y * 13) % 5) * 12 - 24;
+                let b = 128 - ((x * 11 + y * 3) % 7) * 8 + 24;
+                rgb::RGBA8::new(r as u8, 128, b as u8, 255)
+            })
+            .collect();
+        let image = Image::from_rgba(data, 64, 64);
+        let metric = pio::ssim::Calculator::new(&image).unwrap();
+        let search = QualitySearch {
+            target: 0.003,
+            min_quality: 0,
+            max_quality: 100,
+            chroma_subsampling: ChromaSubsamplingOption::Auto,
+            metric: &metric,
+            lossy: Box::new(|img, q, cs| {
+                pio::jpeg::compress(img, q, cs, &pio::jpeg::CompressOptions::default())
+            }),
+            fixed: Vec::new(),
+            original_size: 64 * 64 * 4,
+            check_max_quality: true,
+            curve: None,
+            strategy: SearchStrategy::Binary,
+            pool: None,
         };
+        let cancel = pio::cancel::CancellationToken::new();
+        let mut lines = Vec::new();
+        let result = search
+            .run(&image, &cancel, &mut |line| lines.push(line))
+            .unwrap();
+        assert!(lines
+            .iter()
+            .all(|line| !line.contains("skipping search of other")));
+        // Maximum quality is checked and the search is started with 4:2:0.
+        assert!(result.trials[..2]
+            .iter()
+            .all(|trial| matches!(trial.chroma_subsampling, Some(ChromaSubsampling::_420))));
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains("_411 is close to the target")));
+    }
 
-        // Read enough data to determine input file format by magic number.
-        let mut buf = vec![0; 16];
-        reader
-            .read_exact(&mut buf)
-            .map_err(|err| format!("failed to read magic number: {}", err))?;
-        let fmt = Format::from_magic(&buf)
-            .ok_or_else(|| "unknown input format, expected jpeg, png or webp".to_string())?;
-        // Read rest of the input.
-        reader
-            .read_to_end(&mut buf)
-            .map_err(|err| format!("failed to read input: {}", err))?;
+    #[test]
+    fn searches_chroma_subsamplings_that_may_be_smaller() -> Result<(), Box<dyn std::error::Error>>
+    {
+        use pio::common::ChromaSubsamplingOption;
+        use pio::search::{QualitySearch, SearchStrategy};
 
-        (fmt, buf)
-    };
+        let image = pio::png::read(
+            &std::fs::read("images/image3-original.png")?,
+            &Default::default(),
+        )?;
+        let metric = pio::ssim::Calculator::new(&image).unwrap();
+        let search = QualitySearch {
+            target: 0.003,
+            min_quality: 0,
+            max_quality: 100,
+            chroma_subsampling: ChromaSubsamplingOption::Auto,
+            metric: &metric,
+            lossy: Box::new(|img, q, cs| {
+                pio::jpeg::compress(img, q, cs, &pio::jpeg::CompressOptions::default())
+            }),
+            fixed: Vec::new(),
+            original_size: 1 << 30,
+            check_max_quality: false,
+            curve: None,
+            strategy: SearchStrategy::Binary,
+            pool: None,
+        };
+        let cancel = pio::cancel::CancellationToken::new();
+        let mut lines = Vec::new();
+        search.run(&image, &cancel, &mut |line| lines.push(line))?;
+        // 4:2:0 reaches the target, but 4:1:1 is smaller with the same quality.
+        assert!(lines.iter().all(|line| !line.contains("skipping search")));
+        assert!(lines.iter().any(|line| line == "chroma subsampling: _411"));
+        Ok(())
+    }
 
-    let (output_format, output_writer) = if args.in_place {
-        let format = args.output_format.unwrap_or(input_format);
-        let path = args.input.unwrap(); // validated by clap
-        let output = Output::overwrite_file(path)
-            .map_err(|err| format!("unable to overwrite file: {}", err))?;
-        (format, output)
-    } else {
-        match &args.output {
-            Some(path) => {
-                let format = args.output_format.or_else(|| Format::from_path(path)).ok_or_else(|| {
-                    "failed to determine output format: either use a known file extension (jpeg, png or webp) or specify the format using `--output-format`".to_string()
-                })?;
-                let output = Output::write_file(path)
-                    .map_err(|err| format!("failed to open output file: {}", err))?;
-                (format, output)
-            }
-            None => {
-                let format = args.output_format.ok_or_else(|| "use `--output` to write to a file or `--output-format` to write to standard output".to_string())?;
-                (format, Output::stdout())
+    #[test]
+    fn runs_search_in_thread_pool() {
+        use pio::common::{ChromaSubsamplingOption, Image};
+        use pio::search::{QualitySearch, SearchStrategy};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let data = (0..64 * 64)
+            .map(|i| rgb::RGBA8::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255))
+            .collect();
+        let image = Image::from_rgba(data, 64, 64);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        // Preparation isn't part of the search, so it's run in the pool separately.
+        let metric = pool.install(|| pio::ssim::Calculator::new(&image).unwrap());
+        let threads = Arc::new(AtomicUsize::new(0));
+        let compressor_threads = threads.clone();
+        let search = QualitySearch {
+            target: 0.001,
+            min_quality: 0,
+            max_quality: 100,
+            chroma_subsampling: ChromaSubsamplingOption::Auto,
+            metric: &metric,
+            lossy: Box::new(move |img, q, cs| {
+                compressor_threads.fetch_max(rayon::current_num_threads(), Ordering::Relaxed);
+                pio::jpeg::compress(img, q, cs, &pio::jpeg::CompressOptions::default())
+            }),
+            fixed: Vec::new(),
+            original_size: 64 * 64 * 4,
+            check_max_quality: false,
+            curve: None,
+            strategy: SearchStrategy::Binary,
+            pool: Some(&pool),
+        };
+        let cancel = pio::cancel::CancellationToken::new();
+        let mut lines = Vec::new();
+        search
+            .run(&image, &cancel, &mut |line| lines.push(line))
+            .unwrap();
+        assert_eq!(threads.load(Ordering::Relaxed), 1);
+        assert!(!lines.is_empty());
+    }
+
+    // Poll future on the current thread until it's ready.
+    #[cfg(feature = "async")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
             }
         }
-    };
-
-    let chroma_subsampling = if output_format.supports_chroma_subsampling() {
-        match args.chroma_subsampling.as_str() {
-            "420" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_420),
-            "422" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_422),
-            "444" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_444),
-            "auto" => ChromaSubsamplingOption::Auto,
-            _ => unreachable!(),
+        let waker = std::sync::Arc::new(Unpark(std::thread::current())).into();
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                std::task::Poll::Ready(output) => return output,
+                std::task::Poll::Pending => std::thread::park(),
+            }
         }
-    } else {
-        ChromaSubsamplingOption::None
-    };
-
-    let original_size = input_buffer.len();
-
-    let mut input_image = match input_format {
-        Format::JPEG => jpeg::read(&input_buffer),
-        Format::PNG => png::read(&input_buffer),
-        Format::WEBP => webp::read(&input_buffer),
     }
-    .map_err(|err| format!("failed to read input: {}", err))?;
 
-    let (lossy_compress, lossless_compress): (LossyCompressor, Option<LosslessCompressor>) =
-        match output_format {
-            Format::JPEG => (Box::new(jpeg::compress), None),
-            Format::PNG => (Box::new(|img, q, _cs| png::compress(img, q)), None),
-            Format::WEBP => (
-                Box::new(|img, q, _cs| webp::compress(img, q, false)),
-                Some(Box::new(|img| webp::compress(img, 100, true))),
-            ),
-        };
+    #[cfg(feature = "async")]
+    #[test]
+    fn optimizes_asynchronously() {
+        use pio::common::Format;
+        use pio::search::OptimizeOptions;
 
-    if !output_format.supports_transparency() || args.no_transparency {
-        input_image.alpha_blend(args.background_color);
-    }
+        let input = std::fs::read("images/image1-original.png").unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let task = pio::optimize_async(input, OptimizeOptions::default(), move |line| {
+            sender.send(line).unwrap()
+        });
+        let result = block_on(task).unwrap();
+        assert_eq!(result.format, Format::JPEG);
+        assert!(result.quality.is_some());
+        assert!(receiver.try_iter().count() > 0);
 
-    match compress_image(
-        input_image,
-        lossy_compress,
-        lossless_compress,
-        target,
-        min,
-        max,
-        original_size as u64,
-        chroma_subsampling,
-    ) {
-        Ok(output_buffer) => {
-            if output_buffer.len() <= original_size as usize {
-                output_writer
-                    .write(&output_buffer)
-                    .map_err(|err| format!("failed to write output: {}", err))?;
-                Ok(())
-            } else {
-                match args.fail_strategy {
-                    FailStrategy::None => {
-                        eprintln!("warning: Output is larger than input but still writing output normally. This behavior can be changed with `--optimization-failed` option.");
-                        output_writer
-                            .write(&output_buffer)
-                            .map_err(|err| format!("failed to write output: {}", err))?;
-                        Ok(())
-                    }
-                    FailStrategy::Exit => {
-                        Err("error: Output would be larger than input, exiting now...".to_string())
-                    }
-                    FailStrategy::Copy => {
-                        eprintln!("warning: Output would be larger than input, copying input to output...");
-                        output_writer
-                            .write(&output_buffer)
-                            .map_err(|err| format!("failed to write output: {}", err))?;
-                        Ok(())
-                    }
-                }
+        // Dropping the task cancels the work.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let task = pio::task::spawn_blocking(move |cancel| {
+            while !cancel.is_cancelled() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
             }
-        }
-        Err(err) => Err(format!("failed to compress image: {}", err)),
+            sender.send(()).unwrap();
+        });
+        drop(task);
+        receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
     }
-}
 
-fn main() {
-    let args = Args::parse();
-    pio(args).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        std::process::exit(1);
-    })
-}
+    #[cfg(feature = "capi")]
+    #[test]
+    fn optimizes_through_c_interface() -> Result<(), Box<dyn std::error::Error>> {
+        use pio::capi::{pio_free, pio_optimize, PioOptions, PioOutput, PIO_FORMAT_WEBP};
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+        let input = std::fs::read("images/image1-original.png")?;
+        let options = PioOptions {
+            format: PIO_FORMAT_WEBP,
+            quality: 80,
+            target: 0.0,
+            min_quality: 40,
+            max_quality: 95,
+        };
+        let mut output = std::mem::MaybeUninit::<PioOutput>::uninit();
+        let (status, bytes) = unsafe {
+            let status = pio_optimize(input.as_ptr(), input.len(), &options, output.as_mut_ptr());
+            let output = output.assume_init_mut();
+            let bytes = std::slice::from_raw_parts(output.data, output.len).to_vec();
+            pio_free(output);
+            (status, bytes)
+        };
+        assert_eq!(status, 0);
+        assert_eq!(
+            pio::common::Format::from_magic(&bytes),
+            Some(pio::common::Format::WEBP)
+        );
 
-    use assert_cmd::Command;
-    use tempfile::tempdir;
+        let (status, error) = unsafe {
+            let status = pio_optimize(input.as_ptr(), 10, &options, output.as_mut_ptr());
+            let output = output.assume_init_mut();
+            let error = std::ffi::CStr::from_ptr(output.error).to_owned();
+            pio_free(output);
+            (status, error)
+        };
+        assert_eq!(status, -1);
+        assert!(!error.to_bytes().is_empty());
 
-    fn convert_image(input: impl AsRef<Path>, output: impl AsRef<Path>) {
-        Command::new("convert")
-            .arg(input.as_ref())
-            .arg("-quality")
-            .arg("100")
-            .arg(output.as_ref())
-            .assert()
-            .success();
+        for (input, options) in [
+            (std::ptr::null(), &options as *const _),
+            (input.as_ptr(), std::ptr::null()),
+        ] {
+            let (status, error) = unsafe {
+                let status = pio_optimize(input, 10, options, output.as_mut_ptr());
+                let output = output.assume_init_mut();
+                let error = std::ffi::CStr::from_ptr(output.error).to_owned();
+                pio_free(output);
+                (status, error)
+            };
+            assert_eq!(status, -1);
+            assert_eq!(error.to_str()?, "input and options must not be null");
+        }
+        Ok(())
     }
 
-    fn assert_image_similarity(
-        image1: impl AsRef<Path>,
-        image2: impl AsRef<Path>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let output = Command::new("compare")
-            .arg("-metric")
-            .arg("PSNR")
-            .arg(image1.as_ref())
-            .arg(image2.as_ref())
-            .arg("/dev/null")
+    #[test]
+    fn prints_trials_as_json() -> Result<(), Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "jpeg", "--dry-run", "--json"])
             .output()?;
-        let psnr: f32 = String::from_utf8(output.stderr)?.parse()?;
-        assert!(psnr > 30.0);
+        assert!(output.status.success());
+        let json = String::from_utf8(output.stdout)?;
+        assert!(json.starts_with("{\"status\": \"optimized\", \"format\": \"JPEG\""));
+        assert!(json.contains("\"trials\": [{\"setting\": \"quality "));
+        assert_eq!(json.lines().count(), 1);
+
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--output-format", "jpeg", "--json"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("`--json` can't be used"));
         Ok(())
     }
 
-    fn assert_jpeg_sampling_factors(image: impl AsRef<Path>, sampling_factors: &'static str) {
-        Command::new("identify")
-            .arg("-format")
-            .arg("%[jpeg:sampling-factor]")
-            .arg(image.as_ref())
+    #[test]
+    fn uses_truecolor_png_for_gradient() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("gradient.png");
+        let data: Vec<rgb::RGB8> = (0..256 * 256)
+            .map(|i| {
+                rgb::RGB8::new(
+                    (i % 256) as u8,
+                    (i / 256) as u8,
+                    (i % 256 + i / 256) as u8 / 2,
+                )
+            })
+            .collect();
+        lodepng::encode24_file(&input, &data, 256, 256)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--output-format", "png", "--dry-run"])
             .assert()
             .success()
-            .stdout(sampling_factors);
+            .stdout(predicate::str::starts_with("PNG truecolor,"));
+        Ok(())
     }
 
     #[test]
-    fn fails_with_no_arguments() -> Result<(), Box<dyn std::error::Error>> {
+    fn uses_preset() -> Result<(), Box<dyn std::error::Error>> {
         Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--preset", "auto", "--output-format", "auto", "--dry-run"])
             .assert()
-            .failure()
-            .stderr("reading from standard input, use `--output` to write to a file or `--output-format` to write to standard output\n");
+            .success()
+            .stderr(predicate::str::contains("detected preset: Photo"))
+            .stdout(predicate::str::starts_with("PNG").not());
+
+        // Stripes of flat color like in a screenshot.
+        let dir = tempdir()?;
+        let input = dir.path().join("screenshot.png");
+        let data: Vec<rgb::RGB8> = (0..400 * 300)
+            .map(|i| match i / 400 / 20 % 3 {
+                0 => rgb::RGB8::new(255, 255, 255),
+                1 => rgb::RGB8::new(30, 30, 30),
+                _ => rgb::RGB8::new(40, 90, 200),
+            })
+            .collect();
+        lodepng::encode24_file(&input, &data, 400, 300)?;
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--preset", "auto", "--output-format", "auto", "--dry-run"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("detected preset: Screenshot"))
+            .stdout(predicate::str::starts_with("JPEG").not());
+
+        // Options given explicitly take precedence over the preset.
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args([
+                "--preset",
+                "screenshot",
+                "--output-format",
+                "jpeg",
+                "--dry-run",
+            ])
+            .args(["--chroma-subsampling", "420"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("chroma subsampling: _420"));
         Ok(())
     }
 
     #[test]
-    fn reads_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+    fn sets_palette_size_and_metric_with_preset() {
+        use super::{Args, Preset};
+        use clap::Parser;
+
+        let args = Args::parse_from(["pio", "--png-colors", "200"]);
+        let icon = args.with_preset(Preset::Icon);
+        assert_eq!(icon.png_colors, 64);
+        assert!(!icon.fast_ssim);
+        assert!(args.with_preset(Preset::Photo).fast_ssim);
+
+        let args = Args {
+            explicit_options: vec!["png-colors".to_string()],
+            ..args
+        };
+        assert_eq!(args.with_preset(Preset::Icon).png_colors, 200);
+    }
+
+    #[test]
+    fn removes_film_grain() -> Result<(), Box<dyn std::error::Error>> {
+        // Gradient with uniform noise from a linear congruential generator.
         let dir = tempdir()?;
-        let input = dir.path().join("input.jpeg");
-        convert_image("images/image1-original.png", &input);
-        let output = dir.path().join("output.jpeg");
+        let input = dir.path().join("grain.png");
+        let mut state = 1u32;
+        let data: Vec<rgb::RGB8> = (0..400 * 300)
+            .map(|i| {
+                let mut channel = |base: u32| {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    (base + (state >> 16) % 24) as u8
+                };
+                rgb::RGB8::new(
+                    channel(100 + i % 400 / 4),
+                    channel(80 + i / 400 / 3),
+                    channel(120),
+                )
+            })
+            .collect();
+        lodepng::encode24_file(&input, &data, 400, 300)?;
         Command::cargo_bin("pio")?
             .arg(&input)
-            .arg("-o")
-            .arg(&output)
+            .args(["--output-format", "jpeg", "--dry-run", "--remove-grain"])
             .assert()
-            .success();
-        assert_image_similarity(input, output)?;
+            .success()
+            .stderr(predicate::str::contains("removing film grain"));
+        Command::cargo_bin("pio")?
+            .arg("images/image3-original.png")
+            .args(["--output-format", "jpeg", "--dry-run", "--remove-grain"])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("no film grain found"));
         Ok(())
     }
 
     #[test]
-    fn outputs_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+    fn denoises_input() -> Result<(), Box<dyn std::error::Error>> {
         let dir = tempdir()?;
-        let input = "images/image1-original.png";
-        let output = dir.path().join("output.jpeg");
-        Command::cargo_bin("pio")?
-            .arg(input)
-            .arg("-o")
-            .arg(&output)
-            .assert()
-            .success();
-        assert_image_similarity(input, output)?;
+        let mut sizes = Vec::new();
+        for level in ["none", "light", "strong"] {
+            let output = dir.path().join(format!("{}.jpeg", level));
+            let mut command = Command::cargo_bin("pio")?;
+            command
+                .arg("images/image3-original.png")
+                .arg("-o")
+                .arg(&output)
+                .args(["--search", "fixed"]);
+            if level != "none" {
+                command.args(["--denoise", level]);
+            }
+            command.assert().success();
+            sizes.push(std::fs::metadata(&output)?.len());
+        }
+        assert!(sizes[0] > sizes[1] && sizes[1] > sizes[2]);
         Ok(())
     }
 
     #[test]
-    fn reads_webp() -> Result<(), Box<dyn std::error::Error>> {
+    fn preserves_density() -> Result<(), Box<dyn std::error::Error>> {
         let dir = tempdir()?;
-        let input = dir.path().join("input.webp");
-        convert_image("images/image1-original.png", &input);
-        let output = dir.path().join("output.jpeg");
+        let input = dir.path().join("input.png");
+        let jpeg = dir.path().join("output.jpg");
+        let png = dir.path().join("output.png");
+        // 300 DPI in pixels per meter.
+        let phys = [&11811u32.to_be_bytes()[..], &11811u32.to_be_bytes(), &[1]].concat();
+        write_png(&input, &[(b"pHYs", phys)]);
         Command::cargo_bin("pio")?
             .arg(&input)
             .arg("-o")
-            .arg(&output)
+            .arg(&jpeg)
+            .args(["--metadata", "none"])
             .assert()
             .success();
-        assert_image_similarity(input, output)?;
-        Ok(())
-    }
-
-    #[test]
-    fn outputs_webp() -> Result<(), Box<dyn std::error::Error>> {
-        let dir = tempdir()?;
-        let input = "images/image1-original.png";
-        let output = dir.path().join("output.webp");
+        let buffer = std::fs::read(&jpeg)?;
+        assert_eq!(&buffer[6..11], b"JFIF\0");
+        assert_eq!(&buffer[13..18], &[1, 0x01, 0x2c, 0x01, 0x2c]);
         Command::cargo_bin("pio")?
-            .arg(input)
+            .arg(&jpeg)
             .arg("-o")
-            .arg(&output)
+            .arg(&png)
             .assert()
             .success();
-        assert_image_similarity(input, output)?;
+        let mut decoder = lodepng::Decoder::new();
+        decoder.decode(std::fs::read(&png)?)?;
+        let info = decoder.info_png();
+        assert!(info.phys_defined);
+        assert_eq!(
+            (info.phys_unit, info.phys_x, info.phys_y),
+            (1, 11811, 11811)
+        );
         Ok(())
     }
 
     #[test]
-    fn outputs_png() -> Result<(), Box<dyn std::error::Error>> {
+    fn keeps_png_text() -> Result<(), Box<dyn std::error::Error>> {
         let dir = tempdir()?;
-        let input = "images/image1-original.png";
+        let input = dir.path().join("input.png");
         let output = dir.path().join("output.png");
+        write_png(
+            &input,
+            &[
+                (b"tEXt", b"Title\0Sunset".to_vec()),
+                (b"tEXt", b"Software\0pio 0.1.0, quality 80".to_vec()),
+            ],
+        );
+        let texts = |path: &Path| {
+            let mut decoder = lodepng::Decoder::new();
+            decoder.decode(std::fs::read(path).unwrap()).unwrap();
+            decoder
+                .info_png()
+                .text_keys()
+                .map(|(keyword, text)| (keyword.to_vec(), text.to_vec()))
+                .collect::<Vec<_>>()
+        };
         Command::cargo_bin("pio")?
-            .arg(input)
+            .arg(&input)
             .arg("-o")
             .arg(&output)
             .assert()
             .success();
-        assert_image_similarity(input, output)?;
+        assert!(texts(&output).is_empty());
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--keep-text")
+            .assert()
+            .success();
+        assert_eq!(texts(&output), [(b"Title".to_vec(), b"Sunset".to_vec())]);
         Ok(())
     }
 
     #[test]
-    fn does_not_create_empty_output_on_invalid_input() -> Result<(), Box<dyn std::error::Error>> {
+    fn copies_xmp() -> Result<(), Box<dyn std::error::Error>> {
         let dir = tempdir()?;
-        let output = dir.path().join("output.png");
+        let input = dir.path().join("input.png");
+        let jpeg = dir.path().join("output.jpg");
+        let webp = dir.path().join("output.webp");
+        let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><dc:rights>CC BY</dc:rights></x:xmpmeta>";
+        write_png(
+            &input,
+            &[(b"iTXt", [&b"XML:com.adobe.xmp\0\0\0\0\0"[..], xmp].concat())],
+        );
+        let contains = |buffer: &[u8], needle: &[u8]| {
+            buffer.windows(needle.len()).any(|window| window == needle)
+        };
         Command::cargo_bin("pio")?
+            .arg(&input)
             .arg("-o")
-            .arg(&output)
-            .write_stdin("RIFF....WEBP....")
+            .arg(&jpeg)
+            .args(["--metadata", "safe"])
             .assert()
-            .failure();
-        assert!(std::fs::read(&output).is_err());
+            .success();
+        assert!(!contains(&std::fs::read(&jpeg)?, xmp));
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&jpeg)
+            .args(["--metadata", "all"])
+            .assert()
+            .success();
+        let buffer = std::fs::read(&jpeg)?;
+        assert!(contains(
+            &buffer,
+            b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta"
+        ));
+        Command::cargo_bin("pio")?
+            .arg(&jpeg)
+            .arg("-o")
+            .arg(&webp)
+            .args(["--metadata", "all"])
+            .assert()
+            .success();
+        let buffer = std::fs::read(&webp)?;
+        assert!(contains(
+            &buffer,
+            &[&b"XMP "[..], &(xmp.len() as u32).to_le_bytes(), xmp].concat()
+        ));
         Ok(())
     }
 
     #[test]
-    fn outputs_to_special_files() -> Result<(), Box<dyn std::error::Error>> {
+    fn returns_jpeg_encoder_error() {
+        use pio::common::{ChromaSubsampling, Image};
+
+        let image = Image::from_rgba(vec![rgb::RGBA8::new(0, 0, 0, 255); 70000], 70000, 1);
+        let result = pio::jpeg::compress(
+            &image,
+            80,
+            ChromaSubsampling::_420,
+            &pio::jpeg::CompressOptions::default(),
+        );
+        assert!(matches!(result, Err(err) if err.contains("65500")));
+    }
+
+    #[test]
+    fn fails_to_transcode_corrupt_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let valid = dir.path().join("valid.jpg");
         Command::cargo_bin("pio")?
             .arg("images/image1-original.png")
             .arg("-o")
-            .arg("/dev/null")
-            .arg("--output-format")
-            .arg("jpeg")
+            .arg(&valid)
             .assert()
             .success();
+        let input = dir.path().join("input.jpg");
+        std::fs::write(&input, &std::fs::read(&valid)?[..300])?;
+        std::fs::remove_file(&valid)?;
+        let output = dir.path().join("output.jpg");
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--lossless-transcode")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("failed to transcode image"));
+        assert!(!output.exists());
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .args(["--in-place", "--lossless-transcode"])
+            .assert()
+            .failure();
+        let files: Vec<_> = std::fs::read_dir(dir.path())?.collect::<Result<_, _>>()?;
+        assert_eq!(files.len(), 1);
         Ok(())
     }
 
@@ -0,0 +1,234 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use clap::ArgEnum;
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Tag, Value};
+
+use crate::hdr::Cicp;
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum MetadataPolicy {
+    /// Strip all metadata
+    None,
+    /// Keep all metadata except thumbnails and maker notes
+    All,
+    /// Keep metadata except GPS coordinates, serial numbers and thumbnails
+    Safe,
+}
+
+// Tags that may identify the photographer or their equipment.
+const PRIVATE_TAGS: &[Tag] = &[
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::CameraOwnerName,
+    Tag::ImageUniqueID,
+];
+
+#[derive(Clone, Default)]
+pub struct Metadata {
+    /// Raw Exif data in TIFF format
+    pub exif: Option<Vec<u8>>,
+    /// Raw XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// ICC profile of the image data, sRGB is assumed if not set
+    pub icc_profile: Option<Vec<u8>>,
+    /// Color space of HDR image data, takes precedence over ICC profile
+    pub cicp: Option<Cicp>,
+    /// Exif orientation of image data that was not rotated while reading
+    pub orientation: Option<u32>,
+    /// Physical resolution of the image
+    pub density: Option<Density>,
+    /// Textual information like title, author and software
+    pub texts: Vec<Text>,
+}
+
+/// Keyword and text of PNG text chunk.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Text {
+    pub keyword: String,
+    /// Language tag and translated keyword of international text
+    pub language: Option<(String, String)>,
+    pub text: String,
+}
+
+/// Horizontal and vertical density of pixels.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Density {
+    /// Pixels per meter
+    Meter(u32, u32),
+    /// Pixel aspect ratio without a physical unit
+    Ratio(u32, u32),
+}
+
+const METERS_PER_INCH: f64 = 0.0254;
+
+impl Density {
+    /// Read from JFIF density unit and values.
+    pub fn from_jfif(unit: u8, x: u16, y: u16) -> Option<Self> {
+        let (x, y) = (x as u32, y as u32);
+        match unit {
+            _ if x == 0 || y == 0 => None,
+            // Aspect ratio of 1:1 is the default when the density is not known.
+            0 if x == y => None,
+            0 => Some(Self::Ratio(x, y)),
+            1 => Some(Self::Meter(
+                (x as f64 / METERS_PER_INCH).round() as u32,
+                (y as f64 / METERS_PER_INCH).round() as u32,
+            )),
+            2 => Some(Self::Meter(x * 100, y * 100)),
+            _ => None,
+        }
+    }
+
+    /// JFIF density unit and values. Dots per inch are preferred if they convert back to the
+    /// same pixels per meter, so that the common 72 and 300 DPI are written as is.
+    pub fn to_jfif(self) -> (u8, u16, u16) {
+        let clamp = |value: f64| value.round().max(1.0).min(u16::MAX as f64) as u16;
+        match self {
+            Self::Ratio(x, y) => (0, clamp(x as f64), clamp(y as f64)),
+            Self::Meter(x, y) => {
+                let (dpi_x, dpi_y) = (x as f64 * METERS_PER_INCH, y as f64 * METERS_PER_INCH);
+                let inch =
+                    |dpi: f64, ppm: u32| (dpi.round() / METERS_PER_INCH).round() as u32 == ppm;
+                if inch(dpi_x, x) && inch(dpi_y, y) {
+                    (1, clamp(dpi_x), clamp(dpi_y))
+                } else {
+                    (2, clamp(x as f64 / 100.0), clamp(y as f64 / 100.0))
+                }
+            }
+        }
+    }
+
+    /// Read from PNG `pHYs` unit and values.
+    pub fn from_png(unit: u8, x: u32, y: u32) -> Option<Self> {
+        match unit {
+            _ if x == 0 || y == 0 => None,
+            0 if x == y => None,
+            0 => Some(Self::Ratio(x, y)),
+            1 => Some(Self::Meter(x, y)),
+            _ => None,
+        }
+    }
+
+    /// PNG `pHYs` unit and values.
+    pub fn to_png(self) -> (u8, u32, u32) {
+        match self {
+            Self::Meter(x, y) => (1, x, y),
+            Self::Ratio(x, y) => (0, x, y),
+        }
+    }
+
+    /// Read from Exif resolution tags.
+    pub fn from_exif(exif: &exif::Exif) -> Option<Self> {
+        let resolution = |tag| {
+            match &exif.get_field(tag, In::PRIMARY)?.value {
+                Value::Rational(values) => values.first().map(|value| value.to_f64()),
+                _ => None,
+            }
+            .filter(|value| value.is_finite() && *value > 0.0)
+        };
+        let (x, y) = (resolution(Tag::XResolution)?, resolution(Tag::YResolution)?);
+        // Resolution unit defaults to inches.
+        let meters = match exif
+            .get_field(Tag::ResolutionUnit, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .unwrap_or(2)
+        {
+            2 => METERS_PER_INCH,
+            3 => 0.01,
+            _ => return None,
+        };
+        Some(Self::Meter(
+            (x / meters).round() as u32,
+            (y / meters).round() as u32,
+        ))
+    }
+}
+
+impl Metadata {
+    pub fn from_exif(exif: Option<&exif::Exif>) -> Self {
+        Self {
+            exif: exif.map(|exif| exif.buf().to_vec()),
+            xmp: None,
+            icc_profile: None,
+            cicp: None,
+            orientation: None,
+            density: exif.and_then(Density::from_exif),
+            texts: Vec::new(),
+        }
+    }
+
+    // Color profile, CICP, orientation and density describe the image data, so they are kept
+    // regardless of the policy.
+    pub fn apply_policy(self, policy: MetadataPolicy) -> Self {
+        let orientation = self.orientation.unwrap_or(1);
+        self.apply_policy_with_orientation(policy, orientation)
+    }
+
+    // Same as `apply_policy` but for image data that is not rotated while reading, in which case
+    // the orientation must be kept regardless of the policy.
+    pub fn apply_policy_with_orientation(self, policy: MetadataPolicy, orientation: u32) -> Self {
+        Self {
+            exif: match (policy, orientation) {
+                (MetadataPolicy::None, 1) => None,
+                _ => self
+                    .exif
+                    .and_then(|exif| rewrite_exif(&exif, policy, orientation)),
+            },
+            // XMP may contain anything, including GPS coordinates and serial numbers, so it's
+            // only kept when all metadata is kept.
+            xmp: match policy {
+                MetadataPolicy::All => self.xmp,
+                _ => None,
+            },
+            ..self
+        }
+    }
+}
+
+fn is_kept(field: &Field, policy: MetadataPolicy) -> bool {
+    match policy {
+        MetadataPolicy::None => false,
+        // Thumbnail in the 1st IFD and maker notes, which are vendor-specific blobs often
+        // containing serial numbers, are usually larger than the rest of metadata together.
+        MetadataPolicy::All => field.ifd_num == In::PRIMARY && field.tag != Tag::MakerNote,
+        MetadataPolicy::Safe => {
+            is_kept(field, MetadataPolicy::All)
+                && field.tag.context() != Context::Gps
+                && !PRIVATE_TAGS.contains(&field.tag)
+        }
+    }
+}
+
+// Decode Exif data and encode it again with only the fields allowed by the policy.
+fn rewrite_exif(buffer: &[u8], policy: MetadataPolicy, orientation: u32) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_raw(buffer.to_vec()).ok()?;
+    let fields: Vec<&Field> = exif
+        .fields()
+        .filter(|field| field.tag != Tag::Orientation && is_kept(field, policy))
+        .collect();
+    if fields.is_empty() && orientation == 1 {
+        return None;
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let orientation = Field {
+        tag: Tag::Orientation,
+        ifd_num: In::PRIMARY,
+        value: Value::Short(vec![orientation as u16]),
+    };
+    writer.push_field(&orientation);
+
+    let mut output = std::io::Cursor::new(Vec::new());
+    match writer.write(&mut output, exif.little_endian()) {
+        Ok(()) => Some(output.into_inner()),
+        Err(err) => {
+            eprintln!("Failed to write Exif data: {}", err);
+            None
+        }
+    }
+}
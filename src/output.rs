@@ -5,6 +5,7 @@ use std::fs::File;
 use std::io::Write;
 use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub enum Output {
     /// Write to standard output or special file (e.g. /dev/null)
@@ -14,7 +15,9 @@ pub enum Output {
     WriteFile {
         path: PathBuf,
         file: ManuallyDrop<File>,
-        dir: File,
+        dir: Option<File>,
+        /// Modification time to set after writing
+        modified: Option<SystemTime>,
         finished: bool,
     },
 
@@ -22,7 +25,11 @@ pub enum Output {
     OverwriteFile {
         dst_path: PathBuf,
         tmp_path: PathBuf,
-        dst_dir: File,
+        dst_dir: Option<File>,
+        /// Metadata of the original file to copy to the new file
+        dst_metadata: Option<Box<std::fs::Metadata>>,
+        /// Path to keep the original file at
+        backup_path: Option<PathBuf>,
         tmp_file: ManuallyDrop<File>,
         tmp_file_closed: bool,
         finished: bool,
@@ -53,6 +60,104 @@ fn random_file(root: impl AsRef<Path>) -> std::io::Result<(PathBuf, File)> {
     }
 }
 
+// Copy ownership, permissions and timestamps of the original file. Changing the owner requires
+// privileges, so it's done only if possible.
+fn copy_attributes(file: &File, metadata: &std::fs::Metadata) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, MetadataExt};
+        if fchown(file, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+            fchown(file, None, Some(metadata.gid())).unwrap_or(());
+        }
+    }
+    file.set_permissions(metadata.permissions())?;
+    let mut times = std::fs::FileTimes::new().set_modified(metadata.modified()?);
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    file.set_times(times)
+}
+
+// Open directory for syncing new files and renames to disk. Directories can't be opened like files
+// on Windows, where `replace_file` flushes the rename instead.
+#[cfg(not(windows))]
+fn open_directory(path: impl AsRef<Path>) -> std::io::Result<Option<File>> {
+    File::open(path).map(Some)
+}
+
+#[cfg(windows)]
+fn open_directory(_path: impl AsRef<Path>) -> std::io::Result<Option<File>> {
+    Ok(None)
+}
+
+fn sync_directory(dir: &mut Option<File>) -> std::io::Result<()> {
+    match dir {
+        Some(dir) => dir.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// Replace `dst` with `src` atomically. POSIX `rename` does this for files in the same directory.
+#[cfg(not(windows))]
+fn replace_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::rename(src, dst)
+}
+
+#[cfg(windows)]
+fn replace_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+
+    const MOVEFILE_REPLACE_EXISTING: u32 = 0x1;
+    const MOVEFILE_WRITE_THROUGH: u32 = 0x8;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn MoveFileExW(existing: *const u16, new: *const u16, flags: u32) -> i32;
+    }
+
+    let wide =
+        |path: &Path| -> Vec<u16> { path.as_os_str().encode_wide().chain(Some(0)).collect() };
+    let (src, dst) = (wide(src), wide(dst));
+    // Write-through returns only after the rename is flushed to disk.
+    let ret = unsafe {
+        MoveFileExW(
+            src.as_ptr(),
+            dst.as_ptr(),
+            MOVEFILE_REPLACE_EXISTING | MOVEFILE_WRITE_THROUGH,
+        )
+    };
+    if ret == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Keep the original file at `backup` unless a backup exists already, so that running the same
+// command again doesn't replace the original with the output of the earlier run. Hard link keeps
+// the original in place, so that it's still replaced atomically. Copying is the fallback for file
+// systems without hard links. The copy is first made under a temporary name, so that an
+// incomplete backup is never left behind.
+fn backup_file(original: &Path, backup: &Path) -> std::io::Result<()> {
+    match std::fs::hard_link(original, backup) {
+        Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {}
+        _ => return Ok(()),
+    }
+    let (tmp_path, tmp_file) = random_file(backup)?;
+    drop(tmp_file);
+    let result = std::fs::copy(original, &tmp_path).and_then(|_| {
+        if std::fs::symlink_metadata(backup).is_ok() {
+            std::fs::remove_file(&tmp_path)
+        } else {
+            std::fs::rename(&tmp_path, backup)
+        }
+    });
+    if result.is_err() {
+        std::fs::remove_file(&tmp_path).unwrap_or(());
+    }
+    result
+}
+
 fn file_directory(path: impl AsRef<Path>) -> PathBuf {
     match path.as_ref().parent() {
         Some(parent) => {
@@ -74,7 +179,8 @@ impl Output {
             Ok(Self::WriteFile {
                 path: path.to_path_buf(),
                 file: ManuallyDrop::new(file),
-                dir: File::open(file_directory(path))?,
+                dir: open_directory(file_directory(path))?,
+                modified: None,
                 finished: false,
             })
         } else {
@@ -82,23 +188,52 @@ impl Output {
         }
     }
 
-    pub fn overwrite_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        if !std::fs::metadata(&path)?.is_file() {
+    /// Overwrite file atomically. If `backup_suffix` is set, the original file is kept with the
+    /// suffix added to its name.
+    pub fn overwrite_file(
+        path: impl AsRef<Path>,
+        preserve_attributes: bool,
+        backup_suffix: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let metadata = std::fs::metadata(&path)?;
+        if !metadata.is_file() {
             return Err("expected regular file".into());
         }
         let path = path.as_ref();
+        // Empty suffix would make the backup the file being overwritten.
+        if backup_suffix == Some("") {
+            return Err("backup suffix can't be empty".into());
+        }
         let (tmp_path, tmp_file) = random_file(path)?;
-        let dst_dir = File::open(file_directory(path))?;
+        let dst_dir = open_directory(file_directory(path))?;
+        let backup_path = backup_suffix.map(|suffix| {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(suffix);
+            path.with_file_name(name)
+        });
         Ok(Self::OverwriteFile {
             dst_path: path.to_path_buf(),
             tmp_path,
             tmp_file: ManuallyDrop::new(tmp_file),
             tmp_file_closed: false,
             dst_dir,
+            backup_path,
+            dst_metadata: if preserve_attributes {
+                Some(Box::new(metadata))
+            } else {
+                None
+            },
             finished: false,
         })
     }
 
+    /// Set modification time of the written file. Streams are not affected.
+    pub fn set_modified(&mut self, time: SystemTime) {
+        if let Output::WriteFile { modified, .. } = self {
+            *modified = Some(time);
+        }
+    }
+
     pub fn stdout() -> Self {
         Self::Stream(Box::new(std::io::stdout()))
     }
@@ -113,11 +248,15 @@ impl Output {
                 ref mut file,
                 ref mut finished,
                 ref mut dir,
+                ref modified,
                 ..
             } => {
                 file.write_all(buf)?;
+                if let Some(modified) = modified {
+                    file.set_modified(*modified)?;
+                }
                 file.sync_all()?;
-                dir.sync_all()?;
+                sync_directory(dir)?;
                 *finished = true;
             }
             Output::OverwriteFile {
@@ -125,15 +264,23 @@ impl Output {
                 ref tmp_path,
                 ref mut tmp_file,
                 ref mut dst_dir,
+                ref dst_metadata,
+                ref backup_path,
                 ref mut finished,
                 ref mut tmp_file_closed,
             } => {
                 tmp_file.write_all(buf)?;
+                if let Some(metadata) = dst_metadata {
+                    copy_attributes(tmp_file, metadata)?;
+                }
                 tmp_file.sync_all()?;
                 unsafe { ManuallyDrop::drop(tmp_file) }
                 *tmp_file_closed = true;
-                std::fs::rename(tmp_path, dst_path)?;
-                dst_dir.sync_all()?;
+                if let Some(backup_path) = backup_path {
+                    backup_file(dst_path, backup_path)?;
+                }
+                replace_file(tmp_path, dst_path)?;
+                sync_directory(dst_dir)?;
                 *finished = true;
             }
         };
@@ -153,7 +300,7 @@ impl Drop for Output {
             } => {
                 unsafe { ManuallyDrop::drop(file) }
                 if !*finished {
-                    std::fs::remove_file(path).unwrap_or_else(|_err| {});
+                    std::fs::remove_file(path).unwrap_or(());
                 }
             }
             Output::OverwriteFile {
@@ -167,7 +314,7 @@ impl Drop for Output {
                     unsafe { ManuallyDrop::drop(tmp_file) }
                 }
                 if !*finished {
-                    std::fs::remove_file(tmp_path).unwrap_or_else(|_err| {});
+                    std::fs::remove_file(tmp_path).unwrap_or(());
                 }
             }
         }
@@ -1,102 +1,421 @@
 // SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::common::{exif_orientation, orient_image, CompressResult, Image, ReadResult};
-use crate::profile::is_srgb;
+use std::io::Write;
 
-pub fn read(buffer: &[u8]) -> ReadResult {
+use clap::ArgEnum;
+use rgb::{ComponentMap, RGBA16, RGBA8};
+
+use crate::common::{exif_orientation, ColorSpace, CompressResult, Image, ReadOptions, ReadResult};
+use crate::hdr::{tone_map, Cicp, ToneMapping};
+use crate::metadata::{Density, Metadata, Text};
+use crate::profile::{read_transform, transform_rgba16, transform_rgba8, Profile, ReadTransform};
+
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum CompressionLevel {
+    /// Compress image data using the default DEFLATE implementation
+    Default,
+    /// Compress image data of the final output using Zopfli, which is much slower
+    Max,
+}
+
+// 4x4 Bayer matrix for ordered dithering.
+#[rustfmt::skip]
+const BAYER_MATRIX: [[u8; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5],
+];
+
+// Reduce 16-bit image data to 8 bits using ordered dithering to avoid banding in smooth gradients.
+// The same threshold is used for every color channel of a pixel so that gray pixels stay gray.
+// Alpha is rounded instead, because noise in transparency shows up as speckles.
+fn dither_to_8bit(data: &[RGBA16], width: usize) -> Vec<RGBA8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, pixel)| {
+            let threshold = (BAYER_MATRIX[i / width % 4][i % width % 4] as f32 + 0.5) / 16.0;
+            pixel
+                .rgb()
+                .map(|c| (c as f32 / 257.0 + threshold).floor().min(255.0) as u8)
+                .alpha((pixel.a as f32 / 257.0).round() as u8)
+        })
+        .collect()
+}
+
+// Tone map and transform 16-bit image data. It's done before reducing the data to 8 bits to
+// preserve precision.
+fn transform_16bit(
+    mut data: Vec<RGBA16>,
+    tone_mapping: Option<(Cicp, ToneMapping)>,
+    source: Option<Profile>,
+    options: &ReadOptions,
+) -> Result<Vec<RGBA16>, String> {
+    if let Some((cicp, operator)) = tone_mapping {
+        tone_map(&mut data, &cicp, operator);
+    }
+    if let Some(profile) = source {
+        transform_rgba16(&profile, &mut data, options)?;
+    }
+    Ok(data)
+}
+
+// Keyword of `iTXt` chunk containing XMP packet.
+const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+pub fn read(buffer: &[u8], options: &ReadOptions) -> ReadResult {
     let mut decoder = lodepng::Decoder::new();
     decoder.remember_unknown_chunks(true);
+    let (width, height) = decoder.inspect(buffer).map_err(|err| err.to_string())?;
+    options.check_dimensions(width, height)?;
+    let bitdepth = decoder.info_png().color.bitdepth();
     decoder.info_raw_mut().colortype = lodepng::ColorType::RGBA;
+    decoder
+        .info_raw_mut()
+        .set_bitdepth(if bitdepth == 16 { 16 } else { 8 });
 
-    let mut png = match decoder.decode(&buffer) {
-        Ok(lodepng::Image::RGBA(data)) => data,
-        Ok(_) => return Err("Color conversion failed".to_string()),
-        Err(err) => return Err(err.to_string()),
-    };
+    let png = decoder.decode(buffer).map_err(|err| err.to_string())?;
 
-    let orientation = decoder
+    let exif = decoder
         .info_png()
         .get("eXIf")
-        .and_then(|raw| exif::Reader::new().read_raw(raw.data().to_vec()).ok())
-        .and_then(exif_orientation)
-        .unwrap_or(1);
-
-    if let Ok(icc) = decoder.get_icc() {
-        eprintln!("transforming to srgb...");
-        match lcms2::Profile::new_icc(&icc) {
-            Ok(profile) => {
-                if !is_srgb(&profile) {
-                    let transform = lcms2::Transform::new(
-                        &profile,
-                        lcms2::PixelFormat::RGBA_8,
-                        &lcms2::Profile::new_srgb(),
-                        lcms2::PixelFormat::RGBA_8,
-                        lcms2::Intent::Perceptual,
-                    )
-                    .map_err(|err| err.to_string())?;
-                    transform.transform_in_place(&mut png.buffer);
-                }
+        .and_then(|raw| exif::Reader::new().read_raw(raw.data().to_vec()).ok());
+    let orientation = exif.as_ref().and_then(exif_orientation).unwrap_or(1);
+
+    // CICP takes precedence over ICC profile.
+    let cicp = decoder
+        .info_png()
+        .get("cICP")
+        .and_then(|chunk| Cicp::from_png_chunk(chunk.data()))
+        .filter(Cicp::is_hdr);
+    let tone_mapping = cicp.and(options.tone_mapping);
+
+    let color = match (cicp, tone_mapping) {
+        (Some(_), None) => ReadTransform {
+            source: None,
+            icc_profile: None,
+        },
+        // Tone mapped HDR data is in sRGB.
+        (Some(_), Some(_)) => read_transform(None, options),
+        (None, _) => read_transform(decoder.get_icc().ok().as_deref(), options),
+    };
+    if tone_mapping.is_some() {
+        eprintln!("tone mapping to srgb...");
+    }
+    if color.source.is_some() {
+        eprintln!("transforming to {}...", options.color_space);
+    }
+
+    let (data, data16, width, height) = match png {
+        lodepng::Image::RGBA(mut png) if tone_mapping.is_none() => {
+            if let Some(profile) = color.source {
+                transform_rgba8(&profile, &mut png.buffer, options)?;
             }
-            Err(err) => {
-                eprintln!("Failed to read ICC profile: {}", err);
+            (png.buffer, None, png.width, png.height)
+        }
+        lodepng::Image::RGBA(png) => {
+            let data = transform_16bit(
+                png.buffer
+                    .iter()
+                    .map(|c| c.map(|c| c as u16 * 257))
+                    .collect(),
+                cicp.zip(tone_mapping),
+                color.source,
+                options,
+            )?;
+            (
+                dither_to_8bit(&data, png.width),
+                None,
+                png.width,
+                png.height,
+            )
+        }
+        // 16-bit data is kept for lossless output. lodepng returns samples in big-endian byte
+        // order.
+        lodepng::Image::RGBA16(png) => {
+            let data = transform_16bit(
+                png.buffer.iter().map(|c| c.map(u16::from_be)).collect(),
+                cicp.zip(tone_mapping),
+                color.source,
+                options,
+            )?;
+            (
+                dither_to_8bit(&data, png.width),
+                Some(data),
+                png.width,
+                png.height,
+            )
+        }
+        _ => return Err("Color conversion failed".to_string()),
+    };
+
+    let mut image = Image::from_rgba(data, width, height);
+    image.data16 = data16;
+    image.metadata = Metadata::from_exif(exif.as_ref());
+    image.metadata.icc_profile = color.icc_profile;
+    let info = decoder.info_png();
+    image.metadata.xmp = info
+        .itext_keys()
+        .find(|(keyword, ..)| *keyword == XMP_KEYWORD)
+        .map(|(.., text)| text.as_bytes().to_vec());
+    // `tEXt` and `zTXt` chunks are in Latin-1.
+    let latin1 = |bytes: &[u8]| bytes.iter().map(|&byte| byte as char).collect();
+    image.metadata.texts = info
+        .text_keys()
+        .map(|(keyword, text)| Text {
+            keyword: latin1(keyword),
+            language: None,
+            text: latin1(text),
+        })
+        .chain(
+            info.itext_keys()
+                .filter(|(keyword, ..)| *keyword != XMP_KEYWORD)
+                .map(|(keyword, language, translated, text)| Text {
+                    keyword: keyword.to_string(),
+                    language: Some((language.to_string(), translated.to_string())),
+                    text: text.to_string(),
+                }),
+        )
+        .collect();
+    if info.phys_defined {
+        if let Some(density) = Density::from_png(info.phys_unit, info.phys_x, info.phys_y) {
+            image.metadata.density = Some(density);
+        }
+    }
+    if tone_mapping.is_none() {
+        image.metadata.cicp = cicp;
+    }
+
+    Ok(options.orientation.apply(image, orientation))
+}
+
+// Data of `iCCP` chunk: profile name, compression method and zlib compressed profile.
+fn iccp_chunk(profile: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder =
+        flate2::write::ZlibEncoder::new(b"ICC profile\0\0".to_vec(), flate2::Compression::best());
+    encoder.write_all(profile).map_err(|err| err.to_string())?;
+    encoder.finish().map_err(|err| err.to_string())
+}
+
+// Encoder with chunks describing color space and metadata of the image.
+fn new_encoder(image: &Image, options: &CompressOptions) -> Result<lodepng::Encoder, String> {
+    let mut encoder = lodepng::Encoder::new();
+    if options.interlace {
+        encoder.info_png_mut().interlace_method = 1;
+    }
+
+    match (&image.metadata.cicp, &image.metadata.icc_profile) {
+        (Some(cicp), _) => {
+            encoder
+                .info_png_mut()
+                .create_chunk(lodepng::ChunkPosition::IHDR, b"cICP", &cicp.to_png_chunk())
+                .map_err(|err| err.to_string())?;
+        }
+        (None, Some(icc)) => {
+            encoder
+                .info_png_mut()
+                .create_chunk(lodepng::ChunkPosition::IHDR, b"iCCP", &iccp_chunk(icc)?)
+                .map_err(|err| err.to_string())?;
+        }
+        (None, None) => {
+            // `sRGB` chunk where 0x00 specifies perceptual rendering intent.
+            encoder
+                .info_png_mut()
+                .create_chunk(lodepng::ChunkPosition::IHDR, b"sRGB", b"\x00")
+                .map_err(|err| err.to_string())?;
+            // Recommended chunks from PNG 1.2 specification for compatibility with
+            // applications that do not support the `sRGB` chunk.
+            encoder
+                .info_png_mut()
+                .create_chunk(
+                    lodepng::ChunkPosition::IHDR,
+                    b"gAMA",
+                    /* Gamma: 0. */ &45455u32.to_be_bytes(),
+                )
+                .map_err(|err| err.to_string())?;
+            encoder
+                .info_png_mut()
+                .create_chunk(
+                    lodepng::ChunkPosition::IHDR,
+                    b"cHRM",
+                    &[
+                        /* White Point x: 0. */ 31270u32.to_be_bytes(),
+                        /* White Point y: 0. */ 32900u32.to_be_bytes(),
+                        /* Red x:         0. */ 64000u32.to_be_bytes(),
+                        /* Red y:         0. */ 33000u32.to_be_bytes(),
+                        /* Green x:       0. */ 30000u32.to_be_bytes(),
+                        /* Green y:       0. */ 60000u32.to_be_bytes(),
+                        /* Blue x:        0. */ 15000u32.to_be_bytes(),
+                        /* Blue y:        0.0 */ 6000u32.to_be_bytes(),
+                    ]
+                    .concat(),
+                )
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    if let Some(exif) = &image.metadata.exif {
+        encoder
+            .info_png_mut()
+            .create_chunk(lodepng::ChunkPosition::IHDR, b"eXIf", exif)
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some(xmp) = &image.metadata.xmp {
+        // XMP packet is in UTF-8 unless it's malformed.
+        if let Ok(xmp) = std::str::from_utf8(xmp) {
+            encoder
+                .info_png_mut()
+                .add_itext(XMP_KEYWORD, "", "", xmp)
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    for text in &image.metadata.texts {
+        let info = encoder.info_png_mut();
+        // Non-ASCII text without language is written as international text, which is in UTF-8.
+        match &text.language {
+            None if text.keyword.is_ascii() && text.text.is_ascii() => {
+                info.add_text(&text.keyword, &text.text)
+            }
+            None => info.add_itext(&text.keyword, "", "", &text.text),
+            Some((language, translated)) => {
+                info.add_itext(&text.keyword, language, translated, &text.text)
             }
         }
+        .map_err(|err| err.to_string())?;
+    }
+    if let Some(density) = image.metadata.density {
+        let (unit, x, y) = density.to_png();
+        let info = encoder.info_png_mut();
+        info.phys_defined = true;
+        info.phys_unit = unit;
+        info.phys_x = x;
+        info.phys_y = y;
     }
 
-    Ok(orient_image(
-        Image::from_rgba(png.buffer, png.width, png.height),
-        orientation,
-    ))
+    Ok(encoder)
 }
 
-pub fn compress(image: &Image, quality: u8) -> CompressResult {
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum Dithering {
+    /// Map pixels to the closest palette color, which compresses flat-color graphics best
+    None,
+    /// Dither using a Bayer matrix, which keeps repeating patterns compressible
+    Ordered,
+    /// Diffuse quantization error to neighboring pixels, which looks best for photos
+    FloydSteinberg,
+}
+
+#[derive(Copy, Clone)]
+pub struct CompressOptions {
+    /// Maximum number of colors in palette between 2 and 256
+    pub max_colors: u32,
+    pub dithering: Dithering,
+    /// Strength of dithering between 0.0 and 1.0
+    pub dithering_level: f32,
+    /// Use Adam7 interlacing for progressive rendering
+    pub interlace: bool,
+    /// Effort of palette quantization between 1 (fastest) and 10 (best quality)
+    pub effort: u8,
+    /// Number of least significant bits between 0 and 4 ignored in each channel before
+    /// quantization
+    pub posterize: u8,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            max_colors: 256,
+            dithering: Dithering::FloydSteinberg,
+            dithering_level: 1.0,
+            interlace: false,
+            effort: 7,
+            posterize: 0,
+        }
+    }
+}
+
+fn closest_color(palette: &[RGBA8], pixel: [f32; 4]) -> u8 {
+    let distance = |color: &RGBA8| {
+        let color = [color.r, color.g, color.b, color.a];
+        color
+            .iter()
+            .zip(&pixel)
+            .map(|(a, b)| (*a as f32 - b).powi(2))
+            .sum::<f32>()
+    };
+    (0..palette.len())
+        .min_by(|a, b| distance(&palette[*a]).total_cmp(&distance(&palette[*b])))
+        .unwrap() as u8
+}
+
+// Map pixels to palette using ordered dithering. Amount of noise is scaled by half of the average
+// distance between palette colors.
+fn remap_ordered(image: &Image, palette: &[RGBA8], level: f32) -> Vec<u8> {
+    use rayon::prelude::*;
+
+    let spread = level * 128.0 / (palette.len() as f32).cbrt();
+    image
+        .data
+        .par_iter()
+        .enumerate()
+        .map(|(i, pixel)| {
+            let threshold =
+                (BAYER_MATRIX[i / image.width % 4][i % image.width % 4] as f32 + 0.5) / 16.0 - 0.5;
+            let offset = threshold * spread;
+            closest_color(
+                palette,
+                [
+                    pixel.r as f32 + offset,
+                    pixel.g as f32 + offset,
+                    pixel.b as f32 + offset,
+                    pixel.a as f32,
+                ],
+            )
+        })
+        .collect()
+}
+
+// Smallest bit depth that can index every color of the palette.
+fn palette_bitdepth(colors: usize) -> u32 {
+    match colors {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+fn compress_palette(image: &Image, quality: u8, options: &CompressOptions) -> CompressResult {
     let (palette, pixels) = {
         let mut liq = imagequant::new();
         liq.set_quality(0, quality).unwrap();
+        // Speed of libimagequant is between 1 (slowest) and 10 (fastest).
+        liq.set_speed(11 - options.effort as i32)
+            .map_err(|err| err.to_string())?;
+        liq.set_min_posterization(options.posterize)
+            .map_err(|err| err.to_string())?;
+        liq.set_max_colors(options.max_colors)
+            .map_err(|err| err.to_string())?;
         let img = &mut (liq
             .new_image(&*image.data, image.width, image.height, 0.0)
             .map_err(|err| err.to_string())?);
         let mut res = liq.quantize(img).map_err(|err| err.to_string())?;
-        res.set_dithering_level(1.0).unwrap();
-        res.remapped(img).map_err(|err| err.to_string())?
+        let level = match options.dithering {
+            Dithering::FloydSteinberg => options.dithering_level,
+            Dithering::None | Dithering::Ordered => 0.0,
+        };
+        res.set_dithering_level(level).unwrap();
+        let (palette, pixels) = res.remapped(img).map_err(|err| err.to_string())?;
+        match options.dithering {
+            Dithering::Ordered => {
+                let pixels = remap_ordered(image, &palette, options.dithering_level);
+                (palette, pixels)
+            }
+            Dithering::None | Dithering::FloydSteinberg => (palette, pixels),
+        }
     };
     let buffer = {
-        let mut encoder = lodepng::Encoder::new();
-
-        // `sRGB` chunk where 0x00 specifies perceptual rendering intent.
-        encoder
-            .info_png_mut()
-            .create_chunk(lodepng::ChunkPosition::IHDR, b"sRGB", b"\x00")
-            .map_err(|err| err.to_string())?;
-        // Recommended chunks from PNG 1.2 specification for compatibility with applications that
-        // do not support the `sRGB` chunk.
-        encoder
-            .info_png_mut()
-            .create_chunk(
-                lodepng::ChunkPosition::IHDR,
-                b"gAMA",
-                /* Gamma: 0. */ &45455u32.to_be_bytes(),
-            )
-            .map_err(|err| err.to_string())?;
-        encoder
-            .info_png_mut()
-            .create_chunk(
-                lodepng::ChunkPosition::IHDR,
-                b"cHRM",
-                &[
-                    /* White Point x: 0. */ 31270u32.to_be_bytes(),
-                    /* White Point y: 0. */ 32900u32.to_be_bytes(),
-                    /* Red x:         0. */ 64000u32.to_be_bytes(),
-                    /* Red y:         0. */ 33000u32.to_be_bytes(),
-                    /* Green x:       0. */ 30000u32.to_be_bytes(),
-                    /* Green y:       0. */ 60000u32.to_be_bytes(),
-                    /* Blue x:        0. */ 15000u32.to_be_bytes(),
-                    /* Blue y:        0.0 */ 6000u32.to_be_bytes(),
-                ]
-                .concat(),
-            )
-            .map_err(|err| err.to_string())?;
+        let mut encoder = new_encoder(image, options)?;
 
         for color in &palette {
             encoder
@@ -112,7 +431,10 @@ pub fn compress(image: &Image, quality: u8) -> CompressResult {
         encoder.info_raw_mut().colortype = lodepng::ColorType::PALETTE;
         encoder.info_raw_mut().set_bitdepth(8);
         encoder.info_png_mut().color.colortype = lodepng::ColorType::PALETTE;
-        encoder.info_png_mut().color.set_bitdepth(8);
+        encoder
+            .info_png_mut()
+            .color
+            .set_bitdepth(palette_bitdepth(palette.len()));
         encoder.set_auto_convert(false);
 
         encoder
@@ -122,3 +444,198 @@ pub fn compress(image: &Image, quality: u8) -> CompressResult {
     let result = pixels.iter().map(|i| palette[*i as usize]).collect();
     Ok((Image::from_rgba(result, image.width, image.height), buffer))
 }
+
+// Compress grayscale image without palette. Color type and bit depth are chosen by lodepng to
+// minimize the output size.
+fn compress_gray(image: &Image, options: &CompressOptions) -> CompressResult {
+    // Gray pixels may have slightly different channel values, use red channel like lodepng.
+    let data: Vec<RGBA8> = image
+        .data
+        .iter()
+        .map(|c| RGBA8::new(c.r, c.r, c.r, c.a))
+        .collect();
+    let buffer = new_encoder(image, options)?
+        .encode(&data, image.width, image.height)
+        .map_err(|err| err.to_string())?;
+    Ok((Image::from_rgba(data, image.width, image.height), buffer))
+}
+
+pub fn compress(image: &Image, quality: u8, options: &CompressOptions) -> CompressResult {
+    // HDR data would band in a palette.
+    if image.metadata.cicp.is_some() {
+        return compress_lossless(image, options);
+    }
+    let (palette_image, palette_buffer) = compress_palette(image, quality, options)?;
+    // Grayscale color types can't be used with RGB color profile.
+    let gray = matches!(image.color_space, ColorSpace::Gray | ColorSpace::GrayAlpha)
+        && image.metadata.icc_profile.is_none()
+        && image.metadata.cicp.is_none();
+    if gray {
+        let (gray_image, gray_buffer) = compress_gray(image, options)?;
+        if gray_buffer.len() <= palette_buffer.len() {
+            return Ok((gray_image, gray_buffer));
+        }
+    }
+    Ok((palette_image, palette_buffer))
+}
+
+/// Compress image without changing pixel values. Color type, bit depth and filters are chosen to
+/// minimize the output size. 16-bit input is written with its original 16-bit pixels.
+pub fn compress_lossless(image: &Image, options: &CompressOptions) -> CompressResult {
+    // Grayscale color types can't be used with RGB color profile, so use truecolor instead.
+    let truecolor = image.metadata.icc_profile.is_some() || image.metadata.cicp.is_some();
+    let has_alpha = image.data.iter().any(|c| c.a < 255);
+    // lodepng expects 16-bit samples in big-endian byte order.
+    let data16: Option<Vec<RGBA16>> = image
+        .data16
+        .as_ref()
+        .map(|data16| data16.iter().map(|c| c.map(u16::to_be)).collect());
+
+    let mut best_buffer: Option<Vec<u8>> = None;
+    for strategy in [
+        lodepng::FilterStrategy::ZERO,
+        lodepng::FilterStrategy::MINSUM,
+        lodepng::FilterStrategy::ENTROPY,
+    ] {
+        let mut encoder = new_encoder(image, options)?;
+        encoder.set_filter_strategy(strategy, false);
+        encoder.settings_mut().zlibsettings.set_level(9);
+        if truecolor {
+            encoder.set_auto_convert(false);
+            encoder.info_png_mut().color.colortype = if has_alpha {
+                lodepng::ColorType::RGBA
+            } else {
+                lodepng::ColorType::RGB
+            };
+            encoder
+                .info_png_mut()
+                .color
+                .set_bitdepth(if data16.is_some() { 16 } else { 8 });
+        }
+        let buffer = match &data16 {
+            Some(data16) => {
+                encoder.info_raw_mut().colortype = lodepng::ColorType::RGBA;
+                encoder.info_raw_mut().set_bitdepth(16);
+                encoder.encode(data16, image.width, image.height)
+            }
+            None => encoder.encode(&image.data, image.width, image.height),
+        }
+        .map_err(|err| err.to_string())?;
+        if best_buffer
+            .as_ref()
+            .is_none_or(|best| buffer.len() < best.len())
+        {
+            best_buffer = Some(buffer);
+        }
+    }
+
+    Ok((
+        Image::from_rgba(image.data.clone(), image.width, image.height),
+        best_buffer.unwrap(),
+    ))
+}
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn write_chunk(output: &mut Vec<u8>, name: &[u8], data: &[u8]) {
+    let mut crc = flate2::Crc::new();
+    crc.update(name);
+    crc.update(data);
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(name);
+    output.extend_from_slice(data);
+    output.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+// Name and data of PNG chunk.
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+// Split PNG file into its chunks.
+fn read_chunks(buffer: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut chunks = Vec::new();
+    let mut rest = buffer
+        .strip_prefix(SIGNATURE)
+        .ok_or_else(|| "Invalid PNG signature".to_string())?;
+    while !rest.is_empty() {
+        let length = rest
+            .get(..4)
+            .map(|length| u32::from_be_bytes([length[0], length[1], length[2], length[3]]))
+            .ok_or_else(|| "Invalid PNG chunk".to_string())? as usize;
+        let name = rest.get(4..8);
+        let data = rest.get(8..8 + length);
+        match (name, data) {
+            (Some(name), Some(data)) => chunks.push((name, data)),
+            _ => return Err("Invalid PNG chunk".to_string()),
+        }
+        rest = rest
+            .get(12 + length..)
+            .ok_or_else(|| "Invalid PNG chunk".to_string())?;
+    }
+    Ok(chunks)
+}
+
+/// Compress image data of PNG again using Zopfli. Filtered scanlines and other chunks are kept as
+/// is.
+pub fn recompress_zopfli(buffer: &[u8]) -> Result<Vec<u8>, String> {
+    let chunks = read_chunks(buffer)?;
+
+    let mut decoder = flate2::write::ZlibDecoder::new(Vec::new());
+    for (_name, data) in chunks.iter().filter(|(name, _data)| *name == b"IDAT") {
+        decoder.write_all(data).map_err(|err| err.to_string())?;
+    }
+    let scanlines = decoder.finish().map_err(|err| err.to_string())?;
+    let mut idat = Vec::new();
+    zopfli::compress(
+        zopfli::Options::default(),
+        zopfli::Format::Zlib,
+        scanlines.as_slice(),
+        &mut idat,
+    )
+    .map_err(|err| err.to_string())?;
+
+    let mut output = SIGNATURE.to_vec();
+    let mut idat = Some(idat);
+    for (name, data) in chunks {
+        if name == b"IDAT" {
+            // Replace the first IDAT chunk and drop the rest.
+            if let Some(idat) = idat.take() {
+                write_chunk(&mut output, name, &idat);
+            }
+        } else {
+            write_chunk(&mut output, name, data);
+        }
+    }
+    Ok(output)
+}
+
+/// Add `tEXt` chunk after the header chunk.
+pub fn add_text(buffer: &[u8], keyword: &str, text: &str) -> Result<Vec<u8>, String> {
+    let mut output = SIGNATURE.to_vec();
+    for (index, (name, data)) in read_chunks(buffer)?.into_iter().enumerate() {
+        write_chunk(&mut output, name, data);
+        if index == 0 {
+            write_chunk(
+                &mut output,
+                b"tEXt",
+                &[keyword.as_bytes(), b"\0", text.as_bytes()].concat(),
+            );
+        }
+    }
+    Ok(output)
+}
+
+/// Read texts of `tEXt` chunks with the keyword.
+pub fn read_texts(buffer: &[u8], keyword: &str) -> Vec<String> {
+    read_chunks(buffer)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(name, _)| *name == b"tEXt")
+        .filter_map(|(_, data)| data.strip_prefix([keyword.as_bytes(), b"\0"].concat().as_slice()))
+        .map(|text| String::from_utf8_lossy(text).into_owned())
+        .collect()
+}
+
+/// Read width and height from the header chunk.
+pub fn dimensions(buffer: &[u8]) -> Option<(usize, usize)> {
+    lodepng::Decoder::new().inspect(buffer).ok()
+}
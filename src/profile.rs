@@ -1,9 +1,15 @@
 // SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use clap::ArgEnum;
+use rgb::{RGBA16, RGBA8};
+
+use crate::common::ReadOptions;
+
 pub const SRGB_PROFILE: &[u8] = include_bytes!("../profiles/sRGB-v2-nano.icc");
 pub const GRAY_PROFILE: &[u8] = include_bytes!("../profiles/sGrey-v2-nano.icc");
 
+#[cfg(feature = "codecs")]
 pub fn is_srgb(profile: &lcms2::Profile) -> bool {
     match profile
         .info(lcms2::InfoType::Description, lcms2::Locale::none())
@@ -23,3 +29,222 @@ pub fn is_srgb(profile: &lcms2::Profile) -> bool {
         None => false,
     }
 }
+
+// Display P3 by Apple
+// (https://www.color.org/chardata/rgb/DisplayP3.xalter)
+#[cfg(feature = "codecs")]
+pub fn is_display_p3(profile: &lcms2::Profile) -> bool {
+    match profile
+        .info(lcms2::InfoType::Description, lcms2::Locale::none())
+        .as_deref()
+    {
+        Some(desc) => desc.to_ascii_lowercase().contains("display p3"),
+        None => false,
+    }
+}
+
+#[cfg(feature = "codecs")]
+fn display_p3_profile() -> lcms2::Profile {
+    let xy = |x, y| lcms2::CIExyY { x, y, Y: 1.0 };
+    // Display P3 uses the sRGB transfer function.
+    let curve = lcms2::ToneCurve::new_parametric(
+        4,
+        &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045],
+    )
+    .unwrap();
+    let mut profile = lcms2::Profile::new_rgb(
+        &xy(0.3127, 0.3290),
+        &lcms2::CIExyYTRIPLE {
+            Red: xy(0.680, 0.320),
+            Green: xy(0.265, 0.690),
+            Blue: xy(0.150, 0.060),
+        },
+        &[&curve; 3],
+    )
+    .unwrap();
+    let mut description = lcms2::MLU::new(1);
+    description.set_text_ascii("Display P3", lcms2::Locale::none());
+    profile.write_tag(
+        lcms2::TagSignature::ProfileDescriptionTag,
+        lcms2::Tag::MLU(&description),
+    );
+    profile
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default, ArgEnum)]
+pub enum TargetColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+}
+
+impl std::fmt::Display for TargetColorSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Srgb => write!(f, "sRGB"),
+            Self::DisplayP3 => write!(f, "Display P3"),
+        }
+    }
+}
+
+#[cfg(feature = "codecs")]
+impl TargetColorSpace {
+    pub fn profile(&self) -> lcms2::Profile {
+        match self {
+            Self::Srgb => lcms2::Profile::new_srgb(),
+            Self::DisplayP3 => display_p3_profile(),
+        }
+    }
+
+    /// ICC profile to embed in image data. `None` is returned for sRGB which is handled by the
+    /// encoders.
+    pub fn icc(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Srgb => None,
+            Self::DisplayP3 => display_p3_profile().icc().ok(),
+        }
+    }
+
+    pub fn matches(&self, profile: &lcms2::Profile) -> bool {
+        match self {
+            Self::Srgb => is_srgb(profile),
+            Self::DisplayP3 => is_display_p3(profile),
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default, ArgEnum)]
+pub enum RenderingIntent {
+    #[default]
+    Perceptual,
+    Relative,
+    Saturation,
+    Absolute,
+}
+
+#[cfg(feature = "codecs")]
+impl RenderingIntent {
+    pub fn intent(&self) -> lcms2::Intent {
+        match self {
+            Self::Perceptual => lcms2::Intent::Perceptual,
+            Self::Relative => lcms2::Intent::RelativeColorimetric,
+            Self::Saturation => lcms2::Intent::Saturation,
+            Self::Absolute => lcms2::Intent::AbsoluteColorimetric,
+        }
+    }
+}
+
+#[cfg(feature = "codecs")]
+pub fn is_rgb(profile: &lcms2::Profile) -> bool {
+    profile.color_space() == lcms2::ColorSpaceSignature::RgbData
+}
+
+// Whether image data should be kept in the color space of the profile instead of transforming it
+// to sRGB.
+#[cfg(feature = "codecs")]
+pub fn should_keep(profile: &lcms2::Profile, options: &ReadOptions) -> bool {
+    options.keep_color_profile && is_rgb(profile) && !is_srgb(profile)
+}
+
+#[cfg(feature = "codecs")]
+pub use lcms2::Profile;
+
+/// Color profiles can't be parsed without lcms2, so there is never a profile to transform from.
+#[cfg(not(feature = "codecs"))]
+pub enum Profile {}
+
+pub struct ReadTransform {
+    /// Profile to transform image data from, image data is kept as is if not set
+    pub source: Option<Profile>,
+    /// ICC profile of the image data after the transform
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+// Decide how to transform image data with optional embedded ICC profile to the target color
+// space. Image data without a profile is assumed to be in sRGB.
+#[cfg(feature = "codecs")]
+pub fn read_transform(icc: Option<&[u8]>, options: &ReadOptions) -> ReadTransform {
+    let profile = icc.and_then(|icc| match lcms2::Profile::new_icc(icc) {
+        Ok(profile) => Some(profile),
+        Err(err) => {
+            eprintln!("Failed to read ICC profile: {}", err);
+            None
+        }
+    });
+    match profile {
+        Some(profile) if should_keep(&profile, options) => ReadTransform {
+            source: None,
+            icc_profile: icc.map(<[u8]>::to_vec),
+        },
+        profile => {
+            let profile = profile.unwrap_or_else(lcms2::Profile::new_srgb);
+            ReadTransform {
+                source: if options.color_space.matches(&profile) {
+                    None
+                } else {
+                    Some(profile)
+                },
+                icc_profile: options.color_space.icc(),
+            }
+        }
+    }
+}
+
+// Without color management image data is never transformed and an embedded profile is kept as is.
+#[cfg(not(feature = "codecs"))]
+pub fn read_transform(icc: Option<&[u8]>, _options: &ReadOptions) -> ReadTransform {
+    ReadTransform {
+        source: None,
+        icc_profile: icc.map(<[u8]>::to_vec),
+    }
+}
+
+#[cfg(feature = "codecs")]
+fn transform_in_place<T: Copy>(
+    source: &Profile,
+    format: lcms2::PixelFormat,
+    data: &mut [T],
+    options: &ReadOptions,
+) -> Result<(), String> {
+    let transform = lcms2::Transform::new_flags(
+        source,
+        format,
+        &options.color_space.profile(),
+        format,
+        options.rendering_intent.intent(),
+        options.transform_flags(),
+    )
+    .map_err(|err| err.to_string())?;
+    transform.transform_in_place(data);
+    Ok(())
+}
+
+/// Transform 8-bit RGBA data from `source` to the target color space.
+pub fn transform_rgba8(
+    source: &Profile,
+    data: &mut [RGBA8],
+    options: &ReadOptions,
+) -> Result<(), String> {
+    #[cfg(feature = "codecs")]
+    return transform_in_place(source, lcms2::PixelFormat::RGBA_8, data, options);
+    #[cfg(not(feature = "codecs"))]
+    {
+        let _ = (data, options);
+        match *source {}
+    }
+}
+
+/// Transform 16-bit RGBA data from `source` to the target color space.
+pub fn transform_rgba16(
+    source: &Profile,
+    data: &mut [RGBA16],
+    options: &ReadOptions,
+) -> Result<(), String> {
+    #[cfg(feature = "codecs")]
+    return transform_in_place(source, lcms2::PixelFormat::RGBA_16, data, options);
+    #[cfg(not(feature = "codecs"))]
+    {
+        let _ = (data, options);
+        match *source {}
+    }
+}
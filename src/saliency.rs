@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Saliency detection using the spectral residual method by Hou and Zhang (2007).
+
+use crate::common::{Image, Rect};
+
+// Saliency is computed on a small thumbnail, which is enough to find visually important regions.
+const SIZE: usize = 64;
+
+// Complex number as real and imaginary part.
+type Complex = (f64, f64);
+
+// Two-dimensional discrete Fourier transform of square data computed separately for rows and
+// columns. Naive DFT is fast enough for the thumbnail size.
+fn dft(data: &mut [Complex], inverse: bool) {
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let twiddles: Vec<Complex> = (0..SIZE)
+        .map(|k| {
+            let angle = sign * 2.0 * std::f64::consts::PI * k as f64 / SIZE as f64;
+            (angle.cos(), angle.sin())
+        })
+        .collect();
+    let transform = |line: &[Complex]| -> Vec<Complex> {
+        (0..SIZE)
+            .map(|k| {
+                line.iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(re, im), (n, (a, b))| {
+                        let (c, d) = twiddles[k * n % SIZE];
+                        (re + a * c - b * d, im + a * d + b * c)
+                    })
+            })
+            .collect()
+    };
+    for row in data.chunks_mut(SIZE) {
+        let output = transform(row);
+        row.copy_from_slice(&output);
+    }
+    for x in 0..SIZE {
+        let column: Vec<Complex> = (0..SIZE).map(|y| data[y * SIZE + x]).collect();
+        for (y, value) in transform(&column).into_iter().enumerate() {
+            data[y * SIZE + x] = value;
+        }
+    }
+}
+
+// Average of the values in a square window, clamped at the edges.
+fn box_blur(data: &[f64], radius: usize) -> Vec<f64> {
+    let mut output = vec![0.0; data.len()];
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let (mut sum, mut count) = (0.0, 0.0);
+            for yy in y.saturating_sub(radius)..(y + radius + 1).min(SIZE) {
+                for xx in x.saturating_sub(radius)..(x + radius + 1).min(SIZE) {
+                    sum += data[yy * SIZE + xx];
+                    count += 1.0;
+                }
+            }
+            output[y * SIZE + x] = sum / count;
+        }
+    }
+    output
+}
+
+// Saliency map of the image scaled to `SIZE`x`SIZE`.
+fn saliency_map(image: &Image) -> Vec<f64> {
+    // Gray thumbnail using box filter. The image is at least `SIZE` pixels wide and high, so each
+    // box contains at least one pixel.
+    let mut data = vec![(0.0, 0.0); SIZE * SIZE];
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let (x0, x1) = (x * image.width / SIZE, (x + 1) * image.width / SIZE);
+            let (y0, y1) = (y * image.height / SIZE, (y + 1) * image.height / SIZE);
+            let mut sum = 0.0;
+            for yy in y0..y1 {
+                for c in &image.data[yy * image.width + x0..yy * image.width + x1] {
+                    sum += (c.r as f64 + c.g as f64 + c.b as f64) / (3.0 * 255.0);
+                }
+            }
+            data[y * SIZE + x].0 = sum / ((x1 - x0) * (y1 - y0)) as f64;
+        }
+    }
+
+    // Spectral residual is the difference of log amplitude spectrum and its local average.
+    dft(&mut data, false);
+    let log_amplitude: Vec<f64> = data.iter().map(|(re, im)| re.hypot(*im).ln_1p()).collect();
+    let average = box_blur(&log_amplitude, 1);
+    for ((value, log), average) in data.iter_mut().zip(&log_amplitude).zip(&average) {
+        let phase = value.1.atan2(value.0);
+        let amplitude = (log - average).exp();
+        *value = (amplitude * phase.cos(), amplitude * phase.sin());
+    }
+    dft(&mut data, true);
+    let map: Vec<f64> = data.iter().map(|(re, im)| re * re + im * im).collect();
+    box_blur(&map, 2)
+}
+
+/// Bounding box of the visually important parts of the image, `None` if saliency is spread
+/// evenly over the image.
+pub fn salient_region(image: &Image) -> Option<Rect> {
+    if image.width < SIZE || image.height < SIZE {
+        return None;
+    }
+    let map = saliency_map(image);
+    // Threshold from the paper.
+    let threshold = 3.0 * map.iter().sum::<f64>() / map.len() as f64;
+    let salient = |x: usize, y: usize| map[y * SIZE + x] > threshold;
+    let xs: Vec<usize> = (0..SIZE)
+        .filter(|x| (0..SIZE).any(|y| salient(*x, y)))
+        .collect();
+    let ys: Vec<usize> = (0..SIZE)
+        .filter(|y| (0..SIZE).any(|x| salient(x, *y)))
+        .collect();
+    let (x0, x1) = (*xs.first()?, *xs.last()? + 1);
+    let (y0, y1) = (*ys.first()?, *ys.last()? + 1);
+    // Region covering most of the image doesn't make a difference.
+    if (x1 - x0) * (y1 - y0) > SIZE * SIZE / 2 {
+        return None;
+    }
+    let x = x0 * image.width / SIZE;
+    let y = y0 * image.height / SIZE;
+    Some(Rect {
+        x,
+        y,
+        width: x1 * image.width / SIZE - x,
+        height: y1 * image.height / SIZE - y,
+    })
+}
@@ -0,0 +1,1005 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::cancel::CancellationToken;
+use crate::common::{
+    ChromaSubsampling, ChromaSubsamplingOption, CompressResult, Format, Image, ReadOptions,
+};
+use crate::hdr::ToneMapping;
+#[cfg(feature = "codecs")]
+use crate::{jpeg, webp};
+use crate::{png, ssim};
+use clap::ArgEnum;
+
+/// Compressor with quality setting, for example lossy JPEG.
+pub type LossyCompressor =
+    Box<dyn Fn(&Image, u8, ChromaSubsampling) -> CompressResult + Send + Sync>;
+/// Compressor without quality search, for example lossless WebP.
+pub type FixedCompressor = Box<dyn Fn(&Image) -> CompressResult + Send + Sync>;
+
+/// Target DSSIM for each quality setting of JPEG. The tables are calibrated separately for each
+/// output format, so that the search range around the target quality matches the encoder's own
+/// quality scale. Tables can be regenerated with `pio calibrate --format <FORMAT> --output rust
+/// images/`.
+#[rustfmt::skip]
+pub const QUALITY_SSIM_JPEG: [f64; 101] = [
+    0.64405, 0.64405, 0.493921, 0.3717685, 0.2875005, 0.226447, 0.18505, 0.155942,
+    0.13402550000000002, 0.1161245, 0.10214999999999999, 0.09164900000000001, 0.0830645,
+    0.0747825, 0.0686465, 0.0636275, 0.058777499999999996, 0.054973999999999995, 0.0509935,
+    0.048128000000000004, 0.0452685, 0.0428175, 0.0404645, 0.0387125, 0.036169999999999994,
+    0.034700999999999996, 0.03334, 0.0319895, 0.029954, 0.029339499999999998, 0.028261,
+    0.0271415, 0.025916, 0.0248545, 0.0244545, 0.023451, 0.022603, 0.022269, 0.021344, 0.020581,
+    0.0202495, 0.019450000000000002, 0.019161499999999998, 0.0189065, 0.018063, 0.017832,
+    0.0169555, 0.016857999999999998, 0.016676, 0.0159105, 0.0157275, 0.015555,
+    0.014891499999999998, 0.014727, 0.0145845, 0.013921, 0.0137565, 0.0135065, 0.012928,
+    0.012669, 0.0125305, 0.011922499999999999, 0.011724, 0.011544, 0.0112675, 0.0107825,
+    0.010481, 0.010245, 0.009772, 0.0095075, 0.009262, 0.008721, 0.0084715, 0.008324999999999999,
+    0.007556500000000001, 0.0074540000000000006, 0.007243, 0.0067735, 0.0066254999999999994,
+    0.006356499999999999, 0.005924499999999999, 0.005674500000000001, 0.005422, 0.0050215,
+    0.0047565, 0.0044755, 0.0041294999999999995, 0.0038510000000000003, 0.00361, 0.003372,
+    0.0029255, 0.0027010000000000003, 0.0024415, 0.002091, 0.0017955, 0.001591, 0.001218,
+    0.0009805, 0.000749, 0.000548, 0.0004,
+];
+
+/// Target DSSIM for each quality setting of PNG. Median over the photos in `images/`, adjusted so
+/// that higher quality never has a larger target.
+#[rustfmt::skip]
+pub const QUALITY_SSIM_PNG: [f64; 101] = [
+    0.10054, 0.0210671, 0.0203945, 0.0150062, 0.0128962, 0.0120078, 0.010426, 0.00848269,
+    0.00848269, 0.0082711, 0.0082711, 0.00734014, 0.00726688, 0.00726688, 0.00684811, 0.00670798,
+    0.00670526, 0.00649154, 0.00648967, 0.00648967, 0.00648939, 0.00648939, 0.00589959, 0.00589959,
+    0.00556527, 0.00523164, 0.00522921, 0.00513032, 0.00513032, 0.00488631, 0.00488631, 0.00488631,
+    0.00488631, 0.0047092, 0.0047092, 0.00470387, 0.00453931, 0.00439545, 0.00422986, 0.00417073,
+    0.00393841, 0.00393069, 0.003797, 0.0037964, 0.0037964, 0.00376627, 0.00376627, 0.00376134,
+    0.00362099, 0.00356524, 0.00354261, 0.00350127, 0.00342484, 0.0032866, 0.00307894, 0.00273753,
+    0.00273753, 0.00256963, 0.00256599, 0.00256599, 0.00244393, 0.00244393, 0.00244393, 0.00233716,
+    0.00216799, 0.00216782, 0.00191968, 0.00191968, 0.00191968, 0.00178307, 0.00169012, 0.00161697,
+    0.00154733, 0.00144978, 0.00141346, 0.0013604, 0.00133168, 0.00126169, 0.00122825, 0.00117059,
+    0.00108733, 0.00104285, 0.00100483, 0.000952156, 0.000887692, 0.000867968, 0.000867968,
+    0.000851605, 0.000748373, 0.000580415, 0.000524841, 0.000497925, 0.000456183, 0.0003973,
+    0.000303348, 0.000242097, 0.000198544, 0.000193637, 0.000193637, 0.000193637, 0.000193637,
+];
+
+/// Target DSSIM for each quality setting of WebP. Median over the photos in `images/`, adjusted so
+/// that higher quality never has a larger target.
+#[rustfmt::skip]
+pub const QUALITY_SSIM_WEBP: [f64; 101] = [
+    0.0385356, 0.0245485, 0.0235961, 0.0218369, 0.0209768, 0.0204266, 0.019022, 0.0182149,
+    0.0179081, 0.0168877, 0.0167414, 0.0158192, 0.0149126, 0.0145042, 0.0136388, 0.0131456,
+    0.012811, 0.0116302, 0.0116241, 0.0109032, 0.0106281, 0.0102484, 0.00972888, 0.00954873,
+    0.00894513, 0.00853942, 0.00826766, 0.00792858, 0.00764605, 0.00732168, 0.00705319, 0.00654734,
+    0.00611988, 0.00588803, 0.00587223, 0.00560548, 0.00529332, 0.00520404, 0.00516935, 0.0049403,
+    0.00491478, 0.00475314, 0.00472924, 0.00457862, 0.00440796, 0.00409815, 0.00409815, 0.00409815,
+    0.00387485, 0.00387485, 0.00370657, 0.00370282, 0.00345636, 0.00342759, 0.00342205, 0.00333585,
+    0.00323483, 0.00320681, 0.00304244, 0.0030341, 0.00289741, 0.00289741, 0.00288977, 0.00273612,
+    0.00265031, 0.00265031, 0.00255087, 0.00252143, 0.00252143, 0.00252143, 0.00252143, 0.00247597,
+    0.00247597, 0.00237808, 0.00233915, 0.00232907, 0.0021167, 0.00196483, 0.00196483, 0.0018065,
+    0.00173683, 0.00169322, 0.00154431, 0.00145833, 0.00129131, 0.0011853, 0.00105903, 0.00100158,
+    0.000895041, 0.00080354, 0.000724644, 0.000676056, 0.000605271, 0.000527843, 0.000459082,
+    0.000399687, 0.000349666, 0.000314065, 0.000302721, 0.000279859, 0.000279859,
+];
+
+/// Target DSSIM for each quality of the default compressor of `format`.
+pub fn quality_curve(format: Format) -> &'static [f64; 101] {
+    match format {
+        Format::JPEG => &QUALITY_SSIM_JPEG,
+        Format::PNG => &QUALITY_SSIM_PNG,
+        Format::WEBP => &QUALITY_SSIM_WEBP,
+    }
+}
+
+/// Setting that produced a compressed image.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Setting {
+    /// Quality of lossy compressor
+    Quality(u8),
+    /// Name of compression mode without quality search
+    Fixed(String),
+}
+
+impl std::fmt::Display for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Quality(quality) => write!(f, "quality {}", quality),
+            Self::Fixed(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Setting {
+    /// Whether the setting compresses without losing any image data.
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, Self::Fixed(name) if name == "lossless" || name == "lossless transcode")
+    }
+}
+
+impl std::str::FromStr for Setting {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("quality ") {
+            Some(quality) => Ok(Self::Quality(quality.parse()?)),
+            None => Ok(Self::Fixed(s.to_string())),
+        }
+    }
+}
+
+/// Result of compression with the setting that produced it.
+#[derive(Clone)]
+pub struct Compressed {
+    pub setting: Setting,
+    /// DSSIM compared to the original, NaN if the image wasn't compared
+    pub dssim: f64,
+    pub buffer: Vec<u8>,
+    /// Chroma subsampling of lossy compressor, `None` for fixed compressors
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    /// Number of compressions done to find the result
+    pub iterations: usize,
+    /// Compressions done to find the result in the order they were done
+    pub trials: Vec<Trial>,
+}
+
+/// Single compression done during the search.
+#[derive(Clone, Debug)]
+pub struct Trial {
+    pub setting: Setting,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    /// DSSIM compared to the original, NaN if the image wasn't compared
+    pub dssim: f64,
+    pub size: usize,
+}
+
+impl Compressed {
+    /// Trial that produced this result alone.
+    pub fn trial(&self) -> Trial {
+        Trial {
+            setting: self.setting.clone(),
+            chroma_subsampling: self.chroma_subsampling,
+            dssim: self.dssim,
+            size: self.buffer.len(),
+        }
+    }
+}
+
+impl Compressed {
+    /// Result in `format` with statistics, where `input_size` is the size of the original file.
+    pub fn into_result(self, format: Format, input_size: u64) -> OptimizeResult {
+        OptimizeResult {
+            quality: match self.setting {
+                Setting::Quality(quality) => Some(quality),
+                Setting::Fixed(_) => None,
+            },
+            lossless_used: self.setting.is_lossless(),
+            bytes: self.buffer,
+            format,
+            chroma_subsampling: self.chroma_subsampling,
+            dssim: self.dssim,
+            iterations: self.iterations,
+            trials: self.trials,
+            input_size,
+        }
+    }
+}
+
+/// Optimized image with statistics about how it was found.
+#[derive(Clone, Debug)]
+pub struct OptimizeResult {
+    pub bytes: Vec<u8>,
+    pub format: Format,
+    /// Quality of lossy compressor, `None` if a fixed compressor was used
+    pub quality: Option<u8>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    /// DSSIM compared to the original, NaN if the image wasn't compared
+    pub dssim: f64,
+    pub iterations: usize,
+    /// Size of the original file
+    pub input_size: u64,
+    pub lossless_used: bool,
+    pub trials: Vec<Trial>,
+}
+
+/// Algorithm for searching quality of the lossy compressor.
+#[derive(PartialEq, Copy, Clone, Debug, ArgEnum)]
+pub enum SearchStrategy {
+    /// Binary search, which predicts the first steps from the curve if set
+    Binary,
+    /// Try each quality from the minimum upwards until the target is reached
+    Linear,
+    /// Golden-section search for quality with SSIM closest to the target
+    Golden,
+    /// Compress once with the maximum quality without comparing to the original
+    Fixed,
+}
+
+/// Search for compressed image with SSIM closest to the target.
+///
+/// Lossy compressor is searched between minimum and maximum quality for each chroma subsampling.
+/// Fixed compressors are tried after that and their result is used if it's smaller and looks at
+/// least as good as the best result so far.
+pub struct QualitySearch<'a> {
+    /// Target DSSIM
+    pub target: f64,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    pub chroma_subsampling: ChromaSubsamplingOption,
+    /// SSIM calculator of the original image
+    pub metric: &'a ssim::Calculator,
+    pub lossy: LossyCompressor,
+    pub fixed: Vec<(&'static str, FixedCompressor)>,
+    /// Size of the original file
+    pub original_size: u64,
+    /// Compress with the maximum quality first and skip the search if the result is larger than
+    /// the original, which is common for inputs that are already optimized
+    pub check_max_quality: bool,
+    /// Typical DSSIM of each quality, for example the table the target was taken from. If set,
+    /// qualities are predicted from the curve instead of using plain binary search.
+    pub curve: Option<&'a [f64; 101]>,
+    pub strategy: SearchStrategy,
+    /// Thread pool to run the search in instead of the global rayon pool. Compressors and SSIM use
+    /// rayon too, so this limits all parallelism of the search, for example to keep a server
+    /// embedding pio from using all cores for one request. Preparing the input isn't part of the
+    /// search: reading with tone mapping, `denoise`, `Image::alpha_blend` and creating
+    /// `ssim::Calculator` use the pool they're called from, so call them inside
+    /// `ThreadPool::install` to limit them too.
+    pub pool: Option<&'a rayon::ThreadPool>,
+}
+
+// Relative difference from the target within which the search of the first chroma subsampling is
+// considered good enough to skip the others if they are larger.
+const EARLY_STOP_TOLERANCE: f64 = 0.05;
+
+// Golden ratio minus one, the fraction of the range kept by each step of golden-section search.
+const INV_PHI: f64 = 0.618_033_988_749_895;
+
+// Number of steps to predict quality from the curve before falling back to binary search in case
+// the image behaves very differently from the curve.
+const INTERPOLATION_STEPS: usize = 3;
+
+// Fractional quality at which non-increasing curve reaches DSSIM.
+fn curve_quality(curve: &[f64; 101], dssim: f64) -> f64 {
+    if dssim >= curve[0] {
+        return 0.0;
+    }
+    for quality in 1..=100 {
+        if curve[quality] <= dssim {
+            let (a, b) = (curve[quality - 1], curve[quality]);
+            return (quality - 1) as f64 + (a - dssim) / (a - b);
+        }
+    }
+    100.0
+}
+
+// Root mean square difference of JPEG chroma (Cb and Cr) values from their average in blocks of
+// pixels sharing a chroma sample with the subsampling. This approximates how much color detail is
+// lost by subsampling before any compression.
+fn chroma_subsampling_error(image: &Image, sampling: ChromaSubsampling) -> f64 {
+    let (block_width, block_height) = match sampling {
+        ChromaSubsampling::_444 => return 0.0,
+        ChromaSubsampling::_422 => (2, 1),
+        ChromaSubsampling::_440 => (1, 2),
+        ChromaSubsampling::_420 => (2, 2),
+        ChromaSubsampling::_411 => (4, 1),
+    };
+    let mut error = 0.0;
+    for block_y in (0..image.height).step_by(block_height) {
+        for block_x in (0..image.width).step_by(block_width) {
+            // Sum of squared differences from the mean is sum of squares minus n times squared
+            // mean.
+            let (mut n, mut cb_sum, mut cr_sum, mut square_sum) = (0.0, 0.0, 0.0, 0.0);
+            for y in block_y..(block_y + block_height).min(image.height) {
+                for x in block_x..(block_x + block_width).min(image.width) {
+                    let p = image.data[y * image.width + x];
+                    let (r, g, b) = (p.r as f64, p.g as f64, p.b as f64);
+                    let cb = -0.168736 * r - 0.331264 * g + 0.5 * b;
+                    let cr = 0.5 * r - 0.418688 * g - 0.081312 * b;
+                    n += 1.0;
+                    cb_sum += cb;
+                    cr_sum += cr;
+                    square_sum += cb * cb + cr * cr;
+                }
+            }
+            error += square_sum - (cb_sum * cb_sum + cr_sum * cr_sum) / n;
+        }
+    }
+    (error.max(0.0) / (image.width * image.height) as f64).sqrt()
+}
+
+// Chroma subsampling error below which subsampling is expected to be invisible, and above which
+// it's expected to be clearly visible. Natural photos are usually below 4, while images with
+// colored text or lines on a contrasting background are far above 20.
+const TRANSPARENT_CHROMA_ERROR: f64 = 4.0;
+const VISIBLE_CHROMA_ERROR: f64 = 20.0;
+
+// Predict from the color detail of the image which chroma subsamplings are worth searching, so
+// that several searches are needed only when the prediction is uncertain.
+fn predict_chroma_subsamplings(
+    image: &Image,
+    log: &mut dyn FnMut(String),
+) -> Vec<ChromaSubsampling> {
+    if chroma_subsampling_error(image, ChromaSubsampling::_420) < TRANSPARENT_CHROMA_ERROR {
+        log("little color detail, skipping search of other chroma subsamplings".to_string());
+        return vec![ChromaSubsampling::_420];
+    }
+    // 4:2:0 is kept last, because it's expected to give the smallest output. 4:1:1 has as few
+    // chroma samples but loses more horizontal detail, so it usually needs a higher quality.
+    [
+        ChromaSubsampling::_444,
+        ChromaSubsampling::_422,
+        ChromaSubsampling::_440,
+        ChromaSubsampling::_411,
+        ChromaSubsampling::_420,
+    ]
+    .iter()
+    .copied()
+    .filter(|sampling| chroma_subsampling_error(image, *sampling) < VISIBLE_CHROMA_ERROR)
+    .collect()
+}
+
+impl QualitySearch<'_> {
+    fn compare(&self, compressed: &Image) -> Result<f64, String> {
+        self.metric
+            .compare(compressed)
+            .ok_or_else(|| "Failed to calculate SSIM image".to_string())
+    }
+
+    // Predict quality closest to the target from the curve. Measured DSSIM values are mapped to
+    // the quality scale of the curve, where the image is expected to differ from the curve by a
+    // roughly linear function. The prediction is interpolated from the closest attempts on both
+    // sides of the target, or extrapolated from the last two attempts if all of them are on the
+    // same side.
+    fn predict_quality(&self, curve: &[f64; 101], attempts: &[(u8, f64)]) -> f64 {
+        let target = curve_quality(curve, self.target);
+        let secant = |(q0, d0): (u8, f64), (q1, d1): (u8, f64)| {
+            let (q0, q1) = (q0 as f64, q1 as f64);
+            let (x0, x1) = (curve_quality(curve, d0), curve_quality(curve, d1));
+            // Ignore attempts where DSSIM doesn't decrease with quality.
+            if (x1 - x0) * (q1 - q0) > 0.0 {
+                Some(q0 + (q1 - q0) * (target - x0) / (x1 - x0))
+            } else {
+                None
+            }
+        };
+        let low = attempts
+            .iter()
+            .filter(|(_, dssim)| *dssim > self.target)
+            .max_by_key(|(quality, _)| *quality);
+        let high = attempts
+            .iter()
+            .filter(|(_, dssim)| *dssim <= self.target)
+            .min_by_key(|(quality, _)| *quality);
+        let prediction = match (low, high, attempts) {
+            (Some(low), Some(high), _) => secant(*low, *high),
+            (_, _, [.., previous, last]) => secant(*previous, *last),
+            _ => None,
+        };
+        match (prediction, attempts.last()) {
+            (Some(prediction), _) => prediction,
+            (None, Some((quality, dssim))) => {
+                *quality as f64 + target - curve_quality(curve, *dssim)
+            }
+            (None, None) => target,
+        }
+    }
+
+    /// Run the search. Progress is reported as lines of text to `log`.
+    pub fn run(
+        &self,
+        image: &Image,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => return self.search_all(image, cancel, log),
+        };
+        // Work moved to the pool must be `Send`, which the log isn't, so progress is sent back to
+        // this thread.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let search = scope.spawn(move || {
+                pool.install(|| {
+                    self.search_all(image, cancel, &mut |line| sender.send(line).unwrap_or(()))
+                })
+            });
+            for line in receiver {
+                log(line);
+            }
+            search
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err))
+        })
+    }
+
+    fn search_all(
+        &self,
+        image: &Image,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let samplings = match self.chroma_subsampling {
+            ChromaSubsamplingOption::Auto => predict_chroma_subsamplings(image, log),
+            ChromaSubsamplingOption::Manual(sampling) => vec![sampling],
+            ChromaSubsamplingOption::None => vec![ChromaSubsampling::_444],
+        };
+
+        if self.strategy == SearchStrategy::Fixed {
+            // Chroma subsampling can't be searched without comparing, so use the usual default.
+            let sampling = match self.chroma_subsampling {
+                ChromaSubsamplingOption::Manual(sampling) => sampling,
+                ChromaSubsamplingOption::Auto => ChromaSubsampling::_420,
+                ChromaSubsamplingOption::None => ChromaSubsampling::_444,
+            };
+            let (_, buffer) = (self.lossy)(image, self.max_quality, sampling)?;
+            log(format!(
+                "quality {} with chroma subsampling {:?}, skipping search",
+                self.max_quality, sampling
+            ));
+            let mut compressed = Compressed {
+                setting: Setting::Quality(self.max_quality),
+                dssim: f64::NAN,
+                buffer,
+                chroma_subsampling: Some(sampling),
+                iterations: 1,
+                trials: Vec::new(),
+            };
+            compressed.trials.push(compressed.trial());
+            return Ok(compressed);
+        }
+
+        let mut iterations = 0;
+        let mut trials = Vec::new();
+        if self.check_max_quality {
+            iterations += 1;
+            // The last chroma subsampling is expected to give the smallest output.
+            let sampling = *samplings.last().unwrap();
+            let (compressed, buffer) = (self.lossy)(image, self.max_quality, sampling)?;
+            // The image is compared only if the search is skipped.
+            trials.push(Trial {
+                setting: Setting::Quality(self.max_quality),
+                chroma_subsampling: Some(sampling),
+                dssim: f64::NAN,
+                size: buffer.len(),
+            });
+            if buffer.len() as u64 >= self.original_size {
+                log(format!(
+                    "quality {} with chroma subsampling {:?} is not smaller than the original, skipping search",
+                    self.max_quality, sampling
+                ));
+                let dssim = self.compare(&compressed)?;
+                trials[0].dssim = dssim;
+                // Fixed compressors may still be smaller, for example truecolor PNG.
+                let compressed = Compressed {
+                    setting: Setting::Quality(self.max_quality),
+                    dssim,
+                    buffer,
+                    chroma_subsampling: Some(sampling),
+                    iterations,
+                    trials,
+                };
+                return self.try_fixed(image, compressed, cancel, log);
+            }
+        }
+
+        let results = if let [sampling] = samplings[..] {
+            log(format!("chroma subsampling: {:?}", sampling));
+            vec![self.search(image, sampling, cancel, log)?]
+        } else {
+            use rayon::prelude::*;
+
+            // Search the last chroma subsampling first, because it's expected to give the
+            // smallest output. If it's 4:2:0 and reaches the target, others are compressed once
+            // with the same quality. If they are all larger, they are unlikely to give a smaller
+            // output with a similar SSIM, so there is no need to search them.
+            let (last, rest) = samplings.split_last().unwrap();
+            log(format!("chroma subsampling: {:?}", last));
+            let mut first = self.search(image, *last, cancel, log)?;
+            trials.append(&mut first.trials);
+            let close = (first.dssim - self.target).abs() <= EARLY_STOP_TOLERANCE * self.target;
+            if let (ChromaSubsampling::_420, true, Setting::Quality(quality)) =
+                (last, close, &first.setting)
+            {
+                let probes = rest
+                    .par_iter()
+                    .map(|sampling| {
+                        let (_, buffer) = (self.lossy)(image, *quality, *sampling)?;
+                        // The image is compared only if the sampling is searched.
+                        Ok(Trial {
+                            setting: Setting::Quality(*quality),
+                            chroma_subsampling: Some(*sampling),
+                            dssim: f64::NAN,
+                            size: buffer.len(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                iterations += probes.len();
+                let smallest = probes.iter().all(|probe| probe.size > first.buffer.len());
+                trials.extend(probes);
+                if smallest {
+                    log(format!(
+                        "chroma subsampling {:?} is close to the target and smallest at quality {}, skipping search of others",
+                        last, quality
+                    ));
+                    first.iterations += iterations;
+                    first.trials = trials;
+                    return self.try_fixed(image, first, cancel, log);
+                }
+            }
+
+            // Search the rest in parallel. Progress is logged after all searches are done to
+            // keep lines of different searches from being interleaved.
+            let searches: Vec<(Vec<String>, Result<Compressed, String>)> = rest
+                .par_iter()
+                .map(|sampling| {
+                    let mut lines = vec![format!("chroma subsampling: {:?}", sampling)];
+                    let result =
+                        self.search(image, *sampling, cancel, &mut |line| lines.push(line));
+                    (lines, result)
+                })
+                .collect();
+            let mut results = Vec::new();
+            for (lines, result) in searches {
+                for line in lines {
+                    log(line);
+                }
+                results.push(result?);
+            }
+            results.push(first);
+            results
+        };
+
+        let mut best: Option<Compressed> = None;
+        for mut compressed in results {
+            iterations += compressed.iterations;
+            trials.append(&mut compressed.trials);
+            if best.as_ref().is_none_or(|best| {
+                (compressed.dssim - self.target).abs() < (best.dssim - self.target).abs()
+            }) {
+                best = Some(compressed);
+            }
+        }
+        let mut best = best.unwrap();
+        best.iterations = iterations;
+        best.trials = trials;
+        self.try_fixed(image, best, cancel, log)
+    }
+
+    /// Run the search and return the result in `format` with statistics.
+    pub fn optimize(
+        &self,
+        image: &Image,
+        format: Format,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<OptimizeResult, String> {
+        let compressed = self.run(image, cancel, log)?;
+        Ok(compressed.into_result(format, self.original_size))
+    }
+
+    // Compare the best result of quality search with fixed compressors.
+    fn try_fixed(
+        &self,
+        image: &Image,
+        mut best: Compressed,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        // Try compression modes without quality search if the format supports them. For example,
+        // lossless WebP can sometimes be smaller than lossy WebP for non-photographic images.
+        // Lossy modes are used only if the result looks at least as good as the best result so
+        // far, and larger results only if the lossy compressor doesn't reach the target.
+        let tried_highest = best
+            .trials
+            .iter()
+            .any(|t| t.setting == Setting::Quality(100));
+        let lossy_reached = best.trials.iter().any(|t| t.dssim <= self.target);
+        let lossy_unreachable = tried_highest && !lossy_reached;
+        for (name, compress) in &self.fixed {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let (compressed, buffer) = compress(image)?;
+            let dssim = self.compare(&compressed)?;
+            best.iterations += 1;
+            best.trials.push(Trial {
+                setting: Setting::Fixed(name.to_string()),
+                chroma_subsampling: None,
+                dssim,
+                size: buffer.len(),
+            });
+            log(format!(
+                "|                        |{:>13} {:.6} SSIM  {:>3} % of original",
+                name,
+                dssim,
+                100 * buffer.len() as u64 / self.original_size
+            ));
+            let smaller = buffer.len() < best.buffer.len() && dssim <= best.dssim.max(self.target);
+            // Larger output is used if the lossy compressor can't reach the target even with the
+            // highest quality.
+            let reaches_target = lossy_unreachable && dssim <= self.target;
+            if smaller || reaches_target {
+                best = Compressed {
+                    setting: Setting::Fixed(name.to_string()),
+                    dssim,
+                    buffer,
+                    chroma_subsampling: None,
+                    iterations: best.iterations,
+                    trials: std::mem::take(&mut best.trials),
+                };
+            }
+        }
+
+        Ok(best)
+    }
+
+    // Whether DSSIM is closer to the target than the best result so far.
+    fn is_better(&self, dssim: f64, best: &Option<Compressed>) -> bool {
+        best.as_ref()
+            .is_none_or(|best| (dssim - self.target).abs() < (best.dssim - self.target).abs())
+    }
+
+    // Compress image with a single quality and log the result with the remaining search range.
+    #[allow(clippy::too_many_arguments)]
+    fn attempt(
+        &self,
+        image: &Image,
+        quality: u8,
+        chroma_subsampling: ChromaSubsampling,
+        min: u8,
+        max: u8,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let (compressed, buffer) = (self.lossy)(image, quality, chroma_subsampling)?;
+
+        let progress: String = (0..=100 / 4)
+            .map(|x| {
+                if x == quality / 4 {
+                    'O'
+                } else if x == 0 || x == 100 / 4 {
+                    '|'
+                } else if x == min / 4 {
+                    '['
+                } else if x == max / 4 {
+                    ']'
+                } else if x > min / 4 && x < max / 4 {
+                    '-'
+                } else {
+                    ' '
+                }
+            })
+            .collect();
+
+        let dssim = self.compare(&compressed)?;
+
+        log(format!(
+            "{} {:>3} quality  {:.6} SSIM  {:>3} % of original",
+            progress,
+            quality,
+            dssim,
+            100 * buffer.len() as u64 / self.original_size,
+        ));
+
+        Ok(Compressed {
+            setting: Setting::Quality(quality),
+            dssim,
+            buffer,
+            chroma_subsampling: Some(chroma_subsampling),
+            iterations: 1,
+            trials: Vec::new(),
+        })
+    }
+
+    // Search quality of the lossy compressor with a single chroma subsampling.
+    fn search(
+        &self,
+        image: &Image,
+        chroma_subsampling: ChromaSubsampling,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        match self.strategy {
+            SearchStrategy::Binary => self.binary_search(image, chroma_subsampling, cancel, log),
+            SearchStrategy::Linear => self.linear_search(image, chroma_subsampling, cancel, log),
+            SearchStrategy::Golden => self.golden_search(image, chroma_subsampling, cancel, log),
+            SearchStrategy::Fixed => unreachable!(),
+        }
+    }
+
+    fn binary_search(
+        &self,
+        image: &Image,
+        chroma_subsampling: ChromaSubsampling,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let mut min = self.min_quality;
+        let mut max = self.max_quality;
+        let mut best: Option<Compressed> = None;
+        let mut attempts = Vec::new();
+        let mut trials = Vec::new();
+
+        // Compress image with different qualities and find which is closest to the SSIM target.
+        // Binary search is used to speed up the search. Since there are 101 possible quality
+        // values, only ceil(log2(101)) = 7 comparisons are needed at maximum. With a curve, the
+        // first steps are predicted from the curve and usually only 2-4 comparisons are needed.
+        for step in 0.. {
+            // Cancellation is checked between steps. At least one step is done so that there is
+            // always a result to return.
+            if best.is_some() && cancel.is_cancelled() {
+                log("search timed out, using the best result so far".to_string());
+                break;
+            }
+
+            let quality = match self.curve {
+                Some(curve) if step < INTERPOLATION_STEPS => {
+                    let predicted = self.predict_quality(curve, &attempts);
+                    predicted.round().clamp(min as f64, max as f64) as u8
+                }
+                // Overflow is not possible because `min` and `max` are in range 0-100.
+                _ => (min + max) / 2,
+            };
+
+            let compressed = self.attempt(image, quality, chroma_subsampling, min, max, log)?;
+            trials.push(compressed.trial());
+            let dssim = compressed.dssim;
+            let buffer_len = compressed.buffer.len() as u64;
+
+            // Last steps of the binary search are pretty close to each other, so the final step
+            // may not actually have SSIM closest to the target. Instead of using the last step,
+            // keep track of the best attempt so far.
+            if self.is_better(dssim, &best) {
+                best = Some(compressed);
+            }
+
+            // Binary search step.
+            attempts.push((quality, dssim));
+            if dssim > self.target {
+                min = quality + 1;
+            } else {
+                // Prevent underflow because comparison is unreliable at low qualities.
+                if quality == 0 {
+                    break;
+                }
+                max = quality - 1;
+            }
+
+            if min > max {
+                break;
+            }
+
+            // Higher qualities are usually even larger, so there is no point in searching them
+            // when the candidate is already larger than the original but doesn't reach the target.
+            if dssim > self.target && buffer_len > self.original_size {
+                log("candidate is larger than the original, stopping search".to_string());
+                break;
+            }
+        }
+
+        let mut best = best.unwrap();
+        best.iterations = attempts.len();
+        best.trials = trials;
+        Ok(best)
+    }
+
+    // Try qualities in increasing order and stop at the first one reaching the target. This needs
+    // many more comparisons than binary search but doesn't assume anything about the curve.
+    fn linear_search(
+        &self,
+        image: &Image,
+        chroma_subsampling: ChromaSubsampling,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let mut best: Option<Compressed> = None;
+        let mut iterations = 0;
+        let mut trials = Vec::new();
+
+        for quality in self.min_quality..=self.max_quality {
+            if best.is_some() && cancel.is_cancelled() {
+                log("search timed out, using the best result so far".to_string());
+                break;
+            }
+
+            let compressed = self.attempt(
+                image,
+                quality,
+                chroma_subsampling,
+                quality,
+                self.max_quality,
+                log,
+            )?;
+            iterations += 1;
+            trials.push(compressed.trial());
+            let reached = compressed.dssim <= self.target;
+            let larger = compressed.buffer.len() as u64 > self.original_size;
+            if self.is_better(compressed.dssim, &best) {
+                best = Some(compressed);
+            }
+
+            if reached {
+                break;
+            }
+            if larger {
+                log("candidate is larger than the original, stopping search".to_string());
+                break;
+            }
+        }
+
+        let mut best = best.unwrap();
+        best.iterations = iterations;
+        best.trials = trials;
+        Ok(best)
+    }
+
+    // Narrow the range around the quality closest to the target with golden-section search.
+    // Distance from the target decreases and then increases with quality, so one end of the range
+    // can be dropped after comparing two qualities inside it, and one of them is usually reused in
+    // the next step.
+    fn golden_search(
+        &self,
+        image: &Image,
+        chroma_subsampling: ChromaSubsampling,
+        cancel: &CancellationToken,
+        log: &mut dyn FnMut(String),
+    ) -> Result<Compressed, String> {
+        let mut best: Option<Compressed> = None;
+        let mut attempts: Vec<(u8, f64)> = Vec::new();
+        let mut trials = Vec::new();
+        let mut low = self.min_quality;
+        let mut high = self.max_quality;
+
+        let mut distance = |quality: u8, low: u8, high: u8| -> Result<f64, String> {
+            if let Some((_, distance)) = attempts.iter().find(|(q, _)| *q == quality) {
+                return Ok(*distance);
+            }
+            let compressed = self.attempt(image, quality, chroma_subsampling, low, high, log)?;
+            let distance = (compressed.dssim - self.target).abs();
+            attempts.push((quality, distance));
+            trials.push(compressed.trial());
+            if self.is_better(compressed.dssim, &best) {
+                best = Some(compressed);
+            }
+            Ok(distance)
+        };
+
+        while high - low > 2 {
+            let step = ((high - low) as f64 * (1.0 - INV_PHI)).round() as u8;
+            let (a, b) = (low + step, high - step);
+            let (distance_a, distance_b) = (distance(a, low, high)?, distance(b, low, high)?);
+            if distance_a <= distance_b {
+                high = b;
+            } else {
+                low = a;
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+        }
+        // Only a few qualities are left, so try all of them unless cancelled.
+        for quality in low..=high {
+            if quality > low && cancel.is_cancelled() {
+                log("search timed out, using the best result so far".to_string());
+                break;
+            }
+            distance(quality, low, high)?;
+        }
+
+        let mut best = best.unwrap();
+        best.iterations = attempts.len();
+        best.trials = trials;
+        Ok(best)
+    }
+}
+
+/// Options of [`optimize_image`].
+#[derive(Clone, Debug)]
+pub struct OptimizeOptions {
+    pub format: Format,
+    /// Target quality, converted to DSSIM with [`quality_curve`]
+    pub quality: u8,
+    /// Target DSSIM overriding `quality`
+    pub target: Option<f64>,
+    pub min_quality: u8,
+    pub max_quality: u8,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            format: Format::JPEG,
+            quality: 85,
+            target: None,
+            min_quality: 75,
+            max_quality: 95,
+        }
+    }
+}
+
+/// Optimize image to a format with its default lossy compressor, like the command line tool
+/// without options. `original_size` is the size of the input file.
+pub fn optimize_image(
+    image: &Image,
+    original_size: u64,
+    options: &OptimizeOptions,
+    cancel: &CancellationToken,
+    log: &mut dyn FnMut(String),
+) -> Result<OptimizeResult, String> {
+    if options.quality > 100
+        || options.min_quality > options.max_quality
+        || options.max_quality > 100
+    {
+        return Err("expected quality range between 0 and 100".to_string());
+    }
+    let format = options.format;
+    let curve = quality_curve(format);
+    let metric =
+        ssim::Calculator::new(image).ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
+    let lossy: LossyCompressor = match format {
+        #[cfg(feature = "codecs")]
+        Format::JPEG => Box::new(|img, q, cs| jpeg::compress(img, q, cs, &Default::default())),
+        Format::PNG => Box::new(|img, q, _cs| png::compress(img, q, &Default::default())),
+        #[cfg(feature = "codecs")]
+        Format::WEBP => {
+            Box::new(|img, q, _cs| webp::compress(img, q, webp::Mode::Lossy, &Default::default()))
+        }
+        #[cfg(not(feature = "codecs"))]
+        Format::JPEG | Format::WEBP => return Err(codecs_required(format)),
+    };
+    let search = QualitySearch {
+        target: options.target.unwrap_or(curve[options.quality as usize]),
+        min_quality: options.min_quality,
+        max_quality: options.max_quality,
+        chroma_subsampling: if format.supports_chroma_subsampling() {
+            ChromaSubsamplingOption::Auto
+        } else {
+            ChromaSubsamplingOption::None
+        },
+        metric: &metric,
+        lossy,
+        fixed: Vec::new(),
+        original_size,
+        check_max_quality: false,
+        curve: Some(curve),
+        strategy: SearchStrategy::Binary,
+        pool: None,
+    };
+    search.optimize(image, format, cancel, log)
+}
+
+#[cfg(not(feature = "codecs"))]
+fn codecs_required(format: Format) -> String {
+    format!("{} support requires the codecs feature", format.extension())
+}
+
+/// Optimize JPEG, PNG or WebP image in `input` like [`optimize_image`]. Transparency is blended
+/// with white if the output format doesn't support it, and metadata isn't copied.
+pub fn optimize_bytes(
+    input: &[u8],
+    options: &OptimizeOptions,
+    cancel: &CancellationToken,
+    log: &mut dyn FnMut(String),
+) -> Result<OptimizeResult, String> {
+    let format = options.format;
+    let read_options = ReadOptions {
+        tone_mapping: if format.supports_hdr() {
+            None
+        } else {
+            Some(ToneMapping::Hable)
+        },
+        ..Default::default()
+    };
+    let mut image = match Format::from_magic(input) {
+        #[cfg(feature = "codecs")]
+        Some(Format::JPEG) => jpeg::read(input, &read_options),
+        Some(Format::PNG) => png::read(input, &read_options),
+        #[cfg(feature = "codecs")]
+        Some(Format::WEBP) => webp::read(input, &read_options),
+        #[cfg(not(feature = "codecs"))]
+        Some(format) => Err(codecs_required(format)),
+        None => Err("unknown input format, expected jpeg, png or webp".to_string()),
+    }?;
+    if !format.supports_transparency() {
+        image.alpha_blend(rgb::RGB8::new(255, 255, 255));
+    }
+    image.metadata = Default::default();
+    optimize_image(&image, input.len() as u64, options, cancel, log)
+}
@@ -1,29 +1,99 @@
 // SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::borrow::Cow;
+
 use dssim_core::{Dssim, DssimImage};
+use rgb::RGBA8;
 
 use crate::common::Image;
 
+// Map value in range 0-1 to black-red-yellow-white color scale.
+fn heat_color(t: f32) -> RGBA8 {
+    let channel = |offset: f32| ((t * 3.0 - offset).clamp(0.0, 1.0) * 255.0).round() as u8;
+    RGBA8::new(channel(0.0), channel(1.0), channel(2.0), 255)
+}
+
 pub struct Calculator {
     attr: Dssim,
     original: DssimImage<f32>,
+    // Alpha channel is compared separately because degradation in transparent areas may not be
+    // visible in the blended color image.
+    original_alpha: Option<DssimImage<f32>>,
+    // Images are downscaled by this factor before comparison.
+    scale: usize,
 }
 
 impl Calculator {
     pub fn new(original: &Image) -> Option<Self> {
+        Self::downscaled(original, 1)
+    }
+
+    /// Calculator comparing images downscaled by an integer factor, which is much faster for
+    /// large images but less accurate.
+    pub fn downscaled(original: &Image, scale: usize) -> Option<Self> {
+        let downscaled;
+        let original = if scale > 1 {
+            downscaled = original.downscale(scale);
+            &downscaled
+        } else {
+            original
+        };
         let attr = Dssim::new();
+        let original_alpha = if original.data.iter().any(|c| c.a < 255) {
+            Some(attr.create_image(&original.to_alpha())?)
+        } else {
+            None
+        };
         Some(Self {
             original: attr.create_image(&original.to_rgbaplu())?,
+            original_alpha,
             attr,
+            scale,
         })
     }
 
+    // Downscale compressed image to the size of the original.
+    fn scaled<'a>(&self, compressed: &'a Image) -> Cow<'a, Image> {
+        if self.scale > 1 {
+            Cow::Owned(compressed.downscale(self.scale))
+        } else {
+            Cow::Borrowed(compressed)
+        }
+    }
+
     pub fn compare(&self, compressed: &Image) -> Option<f64> {
+        let compressed = self.scaled(compressed);
         let (dssim, _ssim_maps) = self.attr.compare(
             &self.original,
             self.attr.create_image(&compressed.to_rgbaplu())?,
         );
-        Some(dssim.into())
+        let dssim: f64 = dssim.into();
+        match &self.original_alpha {
+            Some(original_alpha) => {
+                let (alpha_dssim, _ssim_maps) = self.attr.compare(
+                    original_alpha,
+                    self.attr.create_image(&compressed.to_alpha())?,
+                );
+                Some(dssim.max(alpha_dssim.into()))
+            }
+            None => Some(dssim),
+        }
+    }
+    /// False-color map of differences between original and compressed image. Colors are scaled
+    /// so that the largest difference is white.
+    pub fn ssim_map(&self, compressed: &Image) -> Option<Image> {
+        let compressed = self.scaled(compressed);
+        let mut attr = Dssim::new();
+        attr.set_save_ssim_maps(1);
+        let (_dssim, ssim_maps) =
+            attr.compare(&self.original, attr.create_image(&compressed.to_rgbaplu())?);
+        let map = &ssim_maps.first()?.map;
+        let max = map.pixels().map(|ssim| 1.0 - ssim).fold(0.0, f32::max);
+        let data = map
+            .pixels()
+            .map(|ssim| heat_color(if max > 0.0 { (1.0 - ssim) / max } else { 0.0 }))
+            .collect();
+        Some(Image::from_rgba(data, map.width(), map.height()))
     }
 }
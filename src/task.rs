@@ -0,0 +1,105 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Running optimization from async code without blocking the executor.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+
+use crate::cancel::CancellationToken;
+use crate::search::{self, OptimizeOptions, OptimizeResult};
+
+struct State<T> {
+    result: Option<std::thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Future of work running in the shared thread pool. Dropping the task cancels its token, so the
+/// quality search stops after the current iteration when the result isn't needed anymore.
+pub struct Task<T> {
+    state: Arc<Mutex<State<T>>>,
+    cancel: CancellationToken,
+}
+
+impl<T> Task<T> {
+    /// Token passed to the work, for example to cancel it while still waiting for the result.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            // Panic of the work is raised where the result is awaited.
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+// Thread pool shared by all tasks, which limits how many of them run at the same time. Quality
+// searches of the tasks run their parallel parts in the same pool.
+fn pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .thread_name(|i| format!("pio-task-{}", i))
+            .build()
+            .expect("failed to create thread pool")
+    })
+}
+
+/// Run blocking work in a shared thread pool and return a future of its result. The work gets a
+/// token that is cancelled when the future is dropped.
+pub fn spawn_blocking<T, F>(work: F) -> Task<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&CancellationToken) -> T + Send + 'static,
+{
+    let state = Arc::new(Mutex::new(State {
+        result: None,
+        waker: None,
+    }));
+    let cancel = CancellationToken::new();
+    let (thread_state, thread_cancel) = (state.clone(), cancel.clone());
+    pool().spawn(move || {
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| work(&thread_cancel)));
+        let mut state = thread_state.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    Task { state, cancel }
+}
+
+/// Optimize JPEG, PNG or WebP image in `input` with the default compressor of the format of
+/// `options`. Decoding, search and encoding run in the shared thread pool. Progress lines of the
+/// search are passed to `log`, which can for example send them to an async channel.
+pub fn optimize_async<L>(
+    input: Vec<u8>,
+    options: OptimizeOptions,
+    mut log: L,
+) -> Task<Result<OptimizeResult, String>>
+where
+    L: FnMut(String) + Send + 'static,
+{
+    spawn_blocking(move |cancel| search::optimize_bytes(&input, &options, cancel, &mut log))
+}
@@ -5,23 +5,34 @@ use libwebp_sys::*;
 use rgb::RGBA8;
 use std::mem::MaybeUninit;
 
-use crate::common::{exif_orientation, orient_image, CompressResult, Image, ReadResult};
-use crate::profile::{is_srgb, SRGB_PROFILE};
+use crate::common::{exif_orientation, CompressResult, Image, ReadOptions, ReadResult};
+use crate::metadata::Metadata;
+use crate::profile::{read_transform, SRGB_PROFILE};
 
-pub fn read(buffer: &[u8]) -> ReadResult {
+// Owned mux object deleted when dropped, so that it's released on every return path.
+struct Mux(*mut WebPMux);
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        // `WebPMuxDelete` accepts null.
+        unsafe { WebPMuxDelete(self.0) }
+    }
+}
+
+pub fn read(buffer: &[u8], options: &ReadOptions) -> ReadResult {
     unsafe {
         let data = WebPData {
             bytes: buffer.as_ptr(),
             size: buffer.len(),
         };
 
-        let mux = WebPMuxCreateInternal(&data, 0, WEBP_MUX_ABI_VERSION);
-        if mux.is_null() {
+        let mux = Mux(WebPMuxCreateInternal(&data, 0, WEBP_MUX_ABI_VERSION));
+        if mux.0.is_null() {
             return Err("failed to create mux".to_string());
         }
 
         let mut image = MaybeUninit::uninit();
-        let ret = WebPMuxGetFrame(mux, 1, image.as_mut_ptr());
+        let ret = WebPMuxGetFrame(mux.0, 1, image.as_mut_ptr());
         if ret != WebPMuxError::WEBP_MUX_OK {
             return Err("failed to get frame 1".to_string());
         }
@@ -29,6 +40,20 @@ pub fn read(buffer: &[u8]) -> ReadResult {
 
         let mut width = 0;
         let mut height = 0;
+        if WebPGetInfo(
+            image.bitstream.bytes,
+            image.bitstream.size,
+            &mut width,
+            &mut height,
+        ) == 0
+        {
+            WebPDataClear(&mut image.bitstream);
+            return Err("failed to read image header".to_string());
+        }
+        if let Err(err) = options.check_dimensions(width as usize, height as usize) {
+            WebPDataClear(&mut image.bitstream);
+            return Err(err);
+        }
         let rgba = WebPDecodeRGBA(
             image.bitstream.bytes,
             image.bitstream.size,
@@ -36,6 +61,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
             &mut height,
         );
         if rgba.is_null() {
+            WebPDataClear(&mut image.bitstream);
             return Err("failed to decode image data".to_string());
         }
 
@@ -51,7 +77,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
 
         let mut exif_chunk = MaybeUninit::uninit();
         let ret = WebPMuxGetChunk(
-            mux,
+            mux.0,
             b"EXIF" as *const _ as *const _,
             exif_chunk.as_mut_ptr(),
         );
@@ -64,10 +90,25 @@ pub fn read(buffer: &[u8]) -> ReadResult {
             WebPMuxError::WEBP_MUX_NOT_FOUND => None,
             error => return Err(format!("error while reading EXIF chunk: {:?}", error)),
         };
-        let orientation = exif.and_then(exif_orientation).unwrap_or(1);
+        let orientation = exif.as_ref().and_then(exif_orientation).unwrap_or(1);
+
+        let mut xmp_chunk = MaybeUninit::uninit();
+        let ret = WebPMuxGetChunk(
+            mux.0,
+            b"XMP " as *const _ as *const _,
+            xmp_chunk.as_mut_ptr(),
+        );
+        let xmp = match ret {
+            WebPMuxError::WEBP_MUX_OK => {
+                let xmp_chunk = xmp_chunk.assume_init();
+                Some(std::slice::from_raw_parts(xmp_chunk.bytes, xmp_chunk.size).to_vec())
+            }
+            WebPMuxError::WEBP_MUX_NOT_FOUND => None,
+            error => return Err(format!("error while reading XMP chunk: {:?}", error)),
+        };
 
         let mut icc = MaybeUninit::uninit();
-        let ret = WebPMuxGetChunk(mux, b"ICCP" as *const _ as *const _, icc.as_mut_ptr());
+        let ret = WebPMuxGetChunk(mux.0, b"ICCP" as *const _ as *const _, icc.as_mut_ptr());
         let icc_data = match ret {
             WebPMuxError::WEBP_MUX_OK => {
                 let icc = icc.assume_init();
@@ -76,70 +117,101 @@ pub fn read(buffer: &[u8]) -> ReadResult {
             WebPMuxError::WEBP_MUX_NOT_FOUND => None,
             error => return Err(format!("{:?}", error)),
         };
-        if let Some(icc) = icc_data {
-            eprintln!("transforming to srgb...");
-            match lcms2::Profile::new_icc(icc) {
-                Ok(profile) => {
-                    if !is_srgb(&profile) {
-                        let transform = lcms2::Transform::new(
-                            &profile,
-                            lcms2::PixelFormat::RGBA_8,
-                            &lcms2::Profile::new_srgb(),
-                            lcms2::PixelFormat::RGBA_8,
-                            lcms2::Intent::Perceptual,
-                        )
-                        .map_err(|err| err.to_string())?;
-                        transform.transform_in_place(&mut buffer);
-                    }
-                }
-                Err(err) => {
-                    eprintln!("Failed to read ICC profile: {}", err);
-                }
-            }
+        let color = read_transform(icc_data, options);
+        if let Some(profile) = color.source {
+            eprintln!("transforming to {}...", options.color_space);
+            let transform = lcms2::Transform::new_flags(
+                &profile,
+                lcms2::PixelFormat::RGBA_8,
+                &options.color_space.profile(),
+                lcms2::PixelFormat::RGBA_8,
+                options.rendering_intent.intent(),
+                options.transform_flags(),
+            )
+            .map_err(|err| err.to_string())?;
+            transform.transform_in_place(&mut buffer);
         }
 
-        WebPMuxDelete(mux);
+        let mut image = Image::from_rgba(buffer, width as usize, height as usize);
+        image.metadata = Metadata::from_exif(exif.as_ref());
+        image.metadata.icc_profile = color.icc_profile;
+        image.metadata.xmp = xmp;
 
-        Ok(orient_image(
-            Image::from_rgba(buffer, width as usize, height as usize),
-            orientation,
-        ))
+        Ok(options.orientation.apply(image, orientation))
     }
 }
 
-pub fn compress(image: &Image, quality: u8, lossless: bool) -> CompressResult {
+#[derive(Copy, Clone)]
+pub struct CompressOptions {
+    /// Compression method between 0 (fastest) and 6 (slowest but smallest output)
+    pub method: u8,
+    /// Quality of alpha channel between 0 and 100
+    pub alpha_quality: u8,
+    /// Use slower but more accurate RGB to YUV conversion for lossy compression
+    pub sharp_yuv: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            method: 6,
+            alpha_quality: 100,
+            sharp_yuv: true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    Lossy,
+    Lossless,
+    /// Lossless compression of preprocessed image data. Level is between 0 (most preprocessing)
+    /// and 100 (no preprocessing).
+    NearLossless(u8),
+}
+
+pub fn compress(
+    image: &Image,
+    quality: u8,
+    mode: Mode,
+    options: &CompressOptions,
+) -> CompressResult {
     unsafe {
         let mut config = MaybeUninit::<WebPConfig>::uninit();
         let ret = WebPConfigInitInternal(
             config.as_mut_ptr(),
             WebPPreset::WEBP_PRESET_DEFAULT,
             quality as f32,
-            WEBP_ENCODER_ABI_VERSION as i32,
+            WEBP_ENCODER_ABI_VERSION,
         );
         if ret == 0 {
             return Err("libwebp version mismatch".to_string());
         }
         let mut config = config.assume_init();
-        config.method = 6;
-        config.use_sharp_yuv = 1;
-        if lossless {
-            config.lossless = 1;
+        config.method = options.method as i32;
+        config.alpha_quality = options.alpha_quality as i32;
+        config.use_sharp_yuv = options.sharp_yuv as i32;
+        match mode {
+            Mode::Lossy => {}
+            Mode::Lossless => config.lossless = 1,
+            Mode::NearLossless(level) => {
+                config.lossless = 1;
+                config.near_lossless = level as i32;
+            }
         }
 
-        let mut wrt = MaybeUninit::<WebPMemoryWriter>::uninit();
-        WebPMemoryWriterInit(wrt.as_mut_ptr());
-        let mut wrt = wrt.assume_init();
+        let mut encoded = Vec::new();
 
         let mut pic = MaybeUninit::<WebPPicture>::uninit();
-        WebPPictureInitInternal(pic.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION as i32);
+        let ret = WebPPictureInitInternal(pic.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION);
         if ret == 0 {
             return Err("libwebp version mismatch".to_string());
         }
         let mut pic = pic.assume_init();
         pic.width = image.width as i32;
         pic.height = image.height as i32;
-        pic.writer = Some(WebPMemoryWrite);
-        pic.custom_ptr = &mut wrt as *mut _ as *mut std::ffi::c_void;
+        pic.writer = Some(write_vec);
+        pic.custom_ptr = &mut encoded as *mut Vec<u8> as *mut std::ffi::c_void;
         // This behavior is copied from `cwebp`. For example `use_sharp_yuv` doesn't seem to do
         // anything if `use_argb` is not enabled.
         if config.lossless == 1 || config.use_sharp_yuv == 1 || config.preprocessing > 0 {
@@ -150,72 +222,186 @@ pub fn compress(image: &Image, quality: u8, lossless: bool) -> CompressResult {
         let ret = WebPPictureImportRGBA(&mut pic, image.as_bytes().as_ptr(), stride);
         if ret == 0 {
             WebPPictureFree(&mut pic);
-            WebPMemoryWriterClear(&mut wrt);
             return Err("Failed to import image data".to_string());
         }
 
         let ret = WebPEncode(&config, &mut pic);
         WebPPictureFree(&mut pic);
-
         if ret == 0 {
-            WebPMemoryWriterClear(&mut wrt);
             return Err("Failed to encode image data".to_string());
         }
 
-        let data = WebPData {
-            bytes: wrt.mem,
-            size: wrt.size,
-        };
-
-        let mux = WebPMuxCreateInternal(&data, 0, WEBP_MUX_ABI_VERSION);
-        if mux.is_null() {
-            return Err("failed to create mux".to_string());
-        }
-
-        let profile = WebPData {
-            bytes: SRGB_PROFILE.as_ptr(),
-            size: SRGB_PROFILE.len(),
-        };
-
-        let ret = WebPMuxSetChunk(
-            mux,
-            b"ICCP" as *const _ as *const _,
-            &profile as *const _,
-            0,
-        );
-        if ret != WebPMuxError::WEBP_MUX_OK {
-            return Err("failed set ICCP chunk".to_string());
-        }
+        let profile = image
+            .metadata
+            .icc_profile
+            .as_deref()
+            .unwrap_or(SRGB_PROFILE);
+        let buffer = add_metadata(
+            &encoded,
+            image.width,
+            image.height,
+            profile,
+            image.metadata.exif.as_deref(),
+            image.metadata.xmp.as_deref(),
+        )
+        .ok_or_else(|| "Failed to parse encoded image data".to_string())?;
 
-        let mut output = MaybeUninit::<WebPData>::uninit();
-        let ret = WebPMuxAssemble(mux, output.as_mut_ptr());
-        if ret != WebPMuxError::WEBP_MUX_OK {
-            return Err("failed to assemble".to_string());
+        // Lossless compression keeps visible pixels as is, so the input can be used for
+        // comparison. Color of fully transparent pixels may change but it doesn't affect SSIM.
+        if mode == Mode::Lossless {
+            return Ok((
+                Image::from_rgba(image.data.clone(), image.width, image.height),
+                buffer,
+            ));
         }
-        let mut output = output.assume_init();
-
-        WebPMuxDelete(mux);
-
-        let capacity = image.width * image.height;
-        let mut pixels = vec![RGBA8::new(0, 0, 0, 0); capacity];
 
+        let mut pixels = vec![RGBA8::new(0, 0, 0, 0); image.width * image.height];
         let ret = WebPDecodeRGBAInto(
-            output.bytes,
-            output.size,
+            buffer.as_ptr(),
+            buffer.len(),
             pixels.as_mut_ptr() as *mut u8,
             4 * image.width * image.height,
             (4 * image.width) as i32,
         );
         if ret.is_null() {
-            WebPDataClear(&mut output);
             return Err("Failed to decode image data".to_string());
         }
 
-        // XXX: unnecessary copy
-        let buffer = std::slice::from_raw_parts(output.bytes, output.size as usize).to_vec();
+        Ok((Image::from_rgba(pixels, image.width, image.height), buffer))
+    }
+}
 
-        WebPDataClear(&mut output);
+// Writer for `WebPPicture` which appends encoded data to `Vec<u8>` pointed by `custom_ptr`.
+unsafe extern "C" fn write_vec(
+    data: *const u8,
+    data_size: usize,
+    picture: *const WebPPicture,
+) -> std::os::raw::c_int {
+    let output = &mut *((*picture).custom_ptr as *mut Vec<u8>);
+    output.extend_from_slice(std::slice::from_raw_parts(data, data_size));
+    1
+}
 
-        Ok((Image::from_rgba(pixels, image.width, image.height), buffer))
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    match bytes {
+        [a, b, c, d, ..] => Some(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+fn write_chunk(output: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(name);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    // Chunks are padded to even size.
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+// Add ICC profile, Exif and XMP chunks to WebP file created by the encoder. This is equivalent to
+// using WebPMux but avoids copying the image data multiple times.
+fn add_metadata(
+    encoded: &[u8],
+    width: usize,
+    height: usize,
+    icc: &[u8],
+    exif: Option<&[u8]>,
+    xmp: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    let mut has_alpha = false;
+    let mut image_chunks = Vec::new();
+    let mut rest = encoded.get(12..)?;
+    while !rest.is_empty() {
+        let name = rest.get(0..4)?;
+        let size = read_u32(rest.get(4..)?)? as usize;
+        let data = rest.get(8..8 + size)?;
+        let chunk = &rest[..(8 + size + size % 2).min(rest.len())];
+        match name {
+            b"VP8X" => {
+                has_alpha |= data.first()? & 0x10 != 0;
+                rest = &rest[chunk.len()..];
+                continue;
+            }
+            b"ALPH" => has_alpha = true,
+            // Bit 28 of the lossless bitstream header after the signature byte.
+            b"VP8L" => has_alpha |= read_u32(data.get(1..)?)? >> 28 & 1 == 1,
+            _ => {}
+        }
+        image_chunks.push(chunk);
+        rest = &rest[chunk.len()..];
+    }
+
+    let mut flags = 0x20; // ICC profile
+    if has_alpha {
+        flags |= 0x10;
+    }
+    if exif.is_some() {
+        flags |= 0x08;
+    }
+    if xmp.is_some() {
+        flags |= 0x04;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width as u32 - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height as u32 - 1).to_le_bytes()[..3]);
+
+    let capacity =
+        encoded.len() + icc.len() + exif.map_or(0, <[u8]>::len) + xmp.map_or(0, <[u8]>::len) + 64;
+    let mut output = Vec::with_capacity(capacity);
+    output.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    write_chunk(&mut output, b"VP8X", &vp8x);
+    write_chunk(&mut output, b"ICCP", icc);
+    for chunk in image_chunks {
+        output.extend_from_slice(chunk);
+    }
+    if let Some(exif) = exif {
+        write_chunk(&mut output, b"EXIF", exif);
+    }
+    if let Some(xmp) = xmp {
+        write_chunk(&mut output, b"XMP ", xmp);
+    }
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(output)
+}
+
+/// Append chunk to WebP file in the extended format, which is always used by `compress`.
+pub fn add_chunk(buffer: &[u8], name: &[u8; 4], data: &[u8]) -> Option<Vec<u8>> {
+    if buffer.get(12..16)? != b"VP8X" {
+        return None;
+    }
+    let mut output = buffer.to_vec();
+    write_chunk(&mut output, name, data);
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(output)
+}
+
+/// Read data of chunks with the name.
+pub fn read_chunks<'a>(buffer: &'a [u8], name: &[u8; 4]) -> Vec<&'a [u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = buffer.get(12..).unwrap_or_default();
+    while let (Some(chunk_name), Some(size)) = (rest.get(0..4), rest.get(4..).and_then(read_u32)) {
+        let size = size as usize;
+        let data = match rest.get(8..8 + size) {
+            Some(data) => data,
+            None => break,
+        };
+        if chunk_name == name {
+            chunks.push(data);
+        }
+        rest = &rest[(8 + size + size % 2).min(rest.len())..];
+    }
+    chunks
+}
+
+/// Read width and height from the header.
+pub fn dimensions(buffer: &[u8]) -> Option<(usize, usize)> {
+    let mut width = 0;
+    let mut height = 0;
+    match unsafe { WebPGetInfo(buffer.as_ptr(), buffer.len(), &mut width, &mut height) } {
+        0 => None,
+        _ => Some((width as usize, height as usize)),
     }
 }